@@ -1,111 +1,920 @@
 #[macro_use]
 extern crate failure;
+extern crate base64;
 extern crate pcap;
 extern crate pnet_packet;
+extern crate quicklz;
+#[macro_use]
+extern crate serde_json;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate tsproto;
+extern crate tsproto_commands;
 
 use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
 
+use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::udp::UdpPacket;
 use pnet_packet::Packet;
 use structopt::StructOpt;
 use structopt::clap::AppSettings;
 use tsproto::packets;
 use tsproto::packets::*;
 use tsproto::algorithms as algs;
+use tsproto::connection::{CachedKey, SharedIv};
+use tsproto::crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubP256};
+use tsproto::identity::Identity;
+use tsproto::license::Licenses;
+use tsproto_commands::messages::{Message, ParseError};
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+/// The maximum size of a decompressed command.
+const MAX_DECOMPRESSED_SIZE: u32 = 40960;
+
+// Link types, see http://www.tcpdump.org/linktypes.html
+const LINKTYPE_ETHERNET: i32 = 1;
+/// Raw ip packets, some platforms use 12 instead of 101.
+const LINKTYPE_RAW: i32 = 101;
+const LINKTYPE_RAW_OLD: i32 = 12;
+/// Linux cooked captures, e.g. when capturing on the `any` device.
+const LINKTYPE_LINUX_SLL: i32 = 113;
+const LINKTYPE_IPV4: i32 = 228;
+const LINKTYPE_IPV6: i32 = 229;
+/// The length of the header of linux cooked captures.
+const SLL_HEADER_LEN: usize = 16;
+
+/// All packet types, used to parse the `--type` argument.
+const PACKET_TYPES: [PacketType; 9] = [
+    PacketType::Voice,
+    PacketType::VoiceWhisper,
+    PacketType::Command,
+    PacketType::CommandLow,
+    PacketType::Ping,
+    PacketType::Pong,
+    PacketType::Ack,
+    PacketType::AckLow,
+    PacketType::Init,
+];
+
 #[derive(StructOpt, Debug)]
 #[structopt(raw(global_settings =
     "&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands]"))]
 struct Args {
     #[structopt(long = "file", short = "f", help = "The capture file")]
     file: String,
+    #[structopt(long = "identity", short = "i",
+        help = "The identity of the client, either as private key or in the \
+        format of the TeamSpeak client (<offset>V<key>)")]
+    identity: Option<String>,
+    #[structopt(long = "ephemeral-key", short = "e",
+        help = "The ephemeral private key of the client (base64), it is \
+        needed to decrypt connections to servers since version 3.1")]
+    ephemeral_key: Option<String>,
+    #[structopt(long = "commands", short = "c",
+        help = "Only print a timeline of the decoded commands")]
+    commands: bool,
+    #[structopt(long = "server-address", short = "a",
+        help = "The ip address of the server, used to find out the direction \
+        of packets")]
+    server_address: Option<IpAddr>,
+    #[structopt(long = "server-port", short = "p", default_value = "9987",
+        help = "The udp port of the server, used to find out the direction of \
+        packets")]
+    server_port: u16,
+    #[structopt(long = "type", short = "t",
+        help = "Only show packets of this type (e.g. command, voice, ack), \
+        can be given multiple times")]
+    types: Vec<String>,
+    #[structopt(long = "json", short = "j",
+        help = "Print one json object per line")]
+    json: bool,
+}
+
+/// A udp packet which was extracted from a captured frame.
+struct UdpData {
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>,
+}
+
+/// The direction in which a packet was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// From the client to the server.
+    C2S,
+    /// From the server to the client.
+    S2C,
+}
+
+impl Direction {
+    fn index(&self) -> usize {
+        match *self {
+            Direction::C2S => 0,
+            Direction::S2C => 1,
+        }
+    }
+}
+
+/// The state of the captured connection, which is needed to decrypt it.
+#[derive(Default)]
+struct Session {
+    private_key: Option<EccKeyPrivP256>,
+    ephemeral_key: Option<EccKeyPrivEd25519>,
+    /// The `alpha` of the `clientinitiv` command.
+    alpha: Option<[u8; 10]>,
+    /// Set when the key exchange was found.
+    shared_iv: Option<SharedIv>,
+    /// The key cache for each direction.
+    key_cache: [[CachedKey; 8]; 2],
+    /// The generation and the last seen packet id for each direction and
+    /// packet type.
+    generations: [[(u32, Option<u16>); 8]; 2],
+    /// The commands for each direction and for `Command` and `CommandLow`.
+    commands: [[CommandQueue; 2]; 2],
+}
+
+/// Reassembles the commands of one direction and packet type.
+///
+/// This works like the receiving side of a connection: Packets are put into
+/// the right order, resent packets are ignored and fragmented commands are
+/// combined and decompressed.
+#[derive(Default)]
+struct CommandQueue {
+    /// The next expected packet id, `None` until the first packet was seen.
+    next_id: Option<u16>,
+    /// Packets which arrived before the expected packet.
+    receive_queue: Vec<(Header, Vec<u8>)>,
+    /// The fragments of the current command.
+    fragments: Option<(Header, Vec<u8>)>,
 }
 
 fn main() {
     real_main().unwrap();
 }
 
-fn get_udp_payload(data: &[u8]) -> Result<Vec<u8>> {
-    // Try to parse as ethernet packet
-    if let Some(eth_pack) = pnet_packet::ethernet::EthernetPacket::new(data) {
-        if let Some(ip_pack) = pnet_packet::ipv4::Ipv4Packet::new(eth_pack.payload()) {
-            if ip_pack.get_next_level_protocol() == IpNextHeaderProtocols::Udp {
-                if let Some(udp_pack) = pnet_packet::udp::UdpPacket::new(ip_pack.payload()) {
-                    Ok(udp_pack.payload().to_vec())
-                } else {
-                    bail!("Not a real udp packet")
-                }
+fn get_udp_packet(linktype: i32, data: &[u8]) -> Result<UdpData> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let eth_pack = EthernetPacket::new(data).ok_or_else(||
+                format_err!("Not an ethernet packet"))?;
+            match eth_pack.get_ethertype() {
+                EtherTypes::Ipv4 => get_ipv4_udp_packet(eth_pack.payload()),
+                EtherTypes::Ipv6 => get_ipv6_udp_packet(eth_pack.payload()),
+                _ => bail!("Not an ip packet"),
+            }
+        }
+        LINKTYPE_LINUX_SLL => {
+            if data.len() < SLL_HEADER_LEN {
+                bail!("Not a linux cooked packet");
+            }
+            // The protocol is stored in the last two bytes of the header
+            let protocol = (u16::from(data[14]) << 8) | u16::from(data[15]);
+            if protocol == EtherTypes::Ipv4.0 {
+                get_ipv4_udp_packet(&data[SLL_HEADER_LEN..])
+            } else if protocol == EtherTypes::Ipv6.0 {
+                get_ipv6_udp_packet(&data[SLL_HEADER_LEN..])
             } else {
-                bail!("Not an udp packet")
+                bail!("Not an ip packet")
+            }
+        }
+        LINKTYPE_RAW | LINKTYPE_RAW_OLD => {
+            // Look at the ip version
+            match data.first().map(|b| b >> 4) {
+                Some(4) => get_ipv4_udp_packet(data),
+                Some(6) => get_ipv6_udp_packet(data),
+                _ => bail!("Not an ip packet"),
             }
-        } else {
-            bail!("Not an ip packet")
         }
+        LINKTYPE_IPV4 => get_ipv4_udp_packet(data),
+        LINKTYPE_IPV6 => get_ipv6_udp_packet(data),
+        _ => bail!("Unsupported link type {}", linktype),
+    }
+}
+
+fn get_ipv4_udp_packet(data: &[u8]) -> Result<UdpData> {
+    let ip_pack = Ipv4Packet::new(data).ok_or_else(||
+        format_err!("Not an ipv4 packet"))?;
+    if ip_pack.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        bail!("Not an udp packet");
+    }
+    get_udp_data(IpAddr::V4(ip_pack.get_source()),
+        IpAddr::V4(ip_pack.get_destination()), ip_pack.payload())
+}
+
+fn get_ipv6_udp_packet(data: &[u8]) -> Result<UdpData> {
+    let ip_pack = Ipv6Packet::new(data).ok_or_else(||
+        format_err!("Not an ipv6 packet"))?;
+    if ip_pack.get_next_header() != IpNextHeaderProtocols::Udp {
+        bail!("Not an udp packet");
+    }
+    get_udp_data(IpAddr::V6(ip_pack.get_source()),
+        IpAddr::V6(ip_pack.get_destination()), ip_pack.payload())
+}
+
+fn get_udp_data(source: IpAddr, destination: IpAddr, data: &[u8])
+    -> Result<UdpData> {
+    let udp_pack = UdpPacket::new(data).ok_or_else(||
+        format_err!("Not a real udp packet"))?;
+    Ok(UdpData {
+        source: SocketAddr::new(source, udp_pack.get_source()),
+        destination: SocketAddr::new(destination, udp_pack.get_destination()),
+        payload: udp_pack.payload().to_vec(),
+    })
+}
+
+fn parse_packet_type(name: &str) -> Result<PacketType> {
+    PACKET_TYPES.iter().cloned()
+        .find(|t| format!("{:?}", t).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format_err!("Unknown packet type {}", name))
+}
+
+/// Find out the direction of a packet with the configured server address.
+///
+/// Returns `None` if the packet was neither sent to nor by the server.
+fn get_direction(args: &Args, udp: &UdpData) -> Option<Direction> {
+    let is_server = |addr: &SocketAddr| addr.port() == args.server_port
+        && args.server_address.map(|a| a == addr.ip()).unwrap_or(true);
+    if is_server(&udp.destination) {
+        Some(Direction::C2S)
+    } else if is_server(&udp.source) {
+        Some(Direction::S2C)
     } else {
-        bail!("Not an ethernet packet")
+        None
+    }
+}
+
+fn parse_private_key(identity: &str) -> Result<EccKeyPrivP256> {
+    if identity.contains('V') {
+        if let Ok(identity) = Identity::from_ts_obfuscated(identity) {
+            return Ok(identity.get_key().clone());
+        }
     }
+    Ok(EccKeyPrivP256::from_ts(identity)?)
 }
 
-fn get_packet(packet: &mut Vec<u8>, is_client: bool) -> Result<packets::Packet> {
-    let (header, pos) = {
-        let mut r = Cursor::new(packet.as_slice());
-        (
-            Header::read(&is_client, &mut r)?,
-            r.position() as usize,
-        )
+fn decode_array(data: &str, out: &mut [u8]) -> Result<()> {
+    let data = base64::decode(data)?;
+    if data.len() != out.len() {
+        bail!("Expected {} bytes but got {}", out.len(), data.len());
+    }
+    out.copy_from_slice(&data);
+    Ok(())
+}
+
+impl CommandQueue {
+    /// Add a decrypted packet.
+    ///
+    /// Returns the commands which are complete now.
+    fn handle_packet(&mut self, mut header: Header, mut data: Vec<u8>)
+        -> Result<Vec<(Header, Vec<u8>)>> {
+        let next_id = *self.next_id.get_or_insert(header.p_id);
+        if header.p_id != next_id {
+            // Packets in front of the expected packet are queued, older
+            // packets were resent and are ignored.
+            let in_front = header.p_id.wrapping_sub(next_id) < 0x8000;
+            if in_front && !self.receive_queue.iter()
+                .any(|&(ref h, _)| h.p_id == header.p_id) {
+                self.receive_queue.push((header, data));
+            }
+            return Ok(Vec::new());
+        }
+
+        let mut commands = Vec::new();
+        loop {
+            let id = header.p_id.wrapping_add(1);
+            self.next_id = Some(id);
+            if let Some(command) = self.add_packet(header, data)? {
+                commands.push(command);
+            }
+
+            // Check if the next packet is in the receive queue
+            if let Some(pos) = self.receive_queue.iter()
+                .position(|&(ref h, _)| h.p_id == id) {
+                let (h, d) = self.receive_queue.remove(pos);
+                header = h;
+                data = d;
+            } else {
+                break;
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Add the next packet in order.
+    fn add_packet(&mut self, header: Header, mut data: Vec<u8>)
+        -> Result<Option<(Header, Vec<u8>)>> {
+        let (header, data) = if header.get_fragmented() {
+            if let Some((first, mut frags)) = self.fragments.take() {
+                // Last fragment
+                frags.append(&mut data);
+                (first, frags)
+            } else {
+                // First fragment
+                self.fragments = Some((header, data));
+                return Ok(None);
+            }
+        } else if let Some((_, ref mut frags)) = self.fragments {
+            frags.append(&mut data);
+            return Ok(None);
+        } else {
+            (header, data)
+        };
+
+        if header.get_compressed() {
+            let data = quicklz::decompress(&mut Cursor::new(data),
+                MAX_DECOMPRESSED_SIZE)?;
+            Ok(Some((header, data)))
+        } else {
+            Ok(Some((header, data)))
+        }
+    }
+}
+
+impl Session {
+    /// The generation of a packet id.
+    fn get_generation(&self, dir: Direction, header: &Header) -> u32 {
+        let (gen, last) = self.generations[dir.index()]
+            [(header.p_type & 0xf) as usize];
+        match last {
+            // The packet id wrapped around
+            Some(last) if header.p_id < last && last - header.p_id > 0x8000 =>
+                gen.wrapping_add(1),
+            // A late packet from the last generation
+            Some(last) if header.p_id > last && header.p_id - last > 0x8000 =>
+                gen.wrapping_sub(1),
+            _ => gen,
+        }
+    }
+
+    fn update_generation(&mut self, dir: Direction, header: &Header) {
+        let gen = self.get_generation(dir, header);
+        let entry = &mut self.generations[dir.index()]
+            [(header.p_type & 0xf) as usize];
+        if entry.1.is_none() || gen != entry.0
+            || entry.1.map(|l| header.p_id > l).unwrap_or(false) {
+            *entry = (gen, Some(header.p_id));
+        }
+    }
+
+    /// Try to decrypt a packet.
+    ///
+    /// Returns `None` if the packet cannot be decrypted.
+    fn decrypt(&mut self, dir: Direction, header: &Header, data: &[u8])
+        -> Option<Vec<u8>> {
+        if header.get_type() == PacketType::Init || header.get_unencrypted() {
+            return Some(data.to_vec());
+        }
+
+        let gen = self.get_generation(dir, header);
+        let mut dec = data.to_vec();
+        let decrypted = if let Some(ref iv) = self.shared_iv {
+            algs::decrypt(header, &mut dec, gen, iv,
+                &mut self.key_cache[dir.index()]).is_ok()
+        } else {
+            false
+        };
+        if decrypted {
+            self.update_generation(dir, header);
+            return Some(dec);
+        }
+
+        // Packets of the handshake are encrypted with a fake key
+        let mut dec = data.to_vec();
+        if algs::decrypt_fake(header, &mut dec).is_ok() {
+            self.update_generation(dir, header);
+            Some(dec)
+        } else {
+            None
+        }
+    }
+
+    /// Parse and decrypt a packet which was sent in the given direction.
+    ///
+    /// Returns an empty list if the packet is only a part of a command which
+    /// is not yet complete.
+    fn get_packets(&mut self, dir: Direction, data: &[u8])
+        -> Result<Vec<packets::Packet>> {
+        let (header, pos) = {
+            let mut r = Cursor::new(data);
+            (
+                Header::read(&(dir == Direction::C2S), &mut r)?,
+                r.position() as usize,
+            )
+        };
+        let data = if let Some(d) = self.decrypt(dir, &header, &data[pos..]) {
+            d
+        } else {
+            bail!("Cannot decrypt packet");
+        };
+
+        let parts = match header.get_type() {
+            PacketType::Command | PacketType::CommandLow => {
+                let cmd_i = if header.get_type() == PacketType::Command {
+                    0
+                } else {
+                    1
+                };
+                self.commands[dir.index()][cmd_i].handle_packet(header, data)?
+            }
+            _ => vec![(header, data)],
+        };
+
+        let mut packets = Vec::with_capacity(parts.len());
+        for (header, data) in parts {
+            let p_data = Data::read(&header, &mut Cursor::new(
+                data.as_slice()))?;
+            let packet = packets::Packet::new(header, p_data);
+            self.handle_handshake(dir, &packet)?;
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    /// Find the key exchange and compute the shared iv.
+    fn handle_handshake(&mut self, dir: Direction, packet: &packets::Packet)
+        -> Result<()> {
+        let command = match packet.data {
+            packets::Data::C2SInit(C2SInit::Init4 { ref command, .. }) =>
+                command,
+            packets::Data::Command(ref command) => command,
+            _ => return Ok(()),
+        };
+
+        match (dir, command.command.as_str()) {
+            (Direction::C2S, "clientinitiv") => {
+                if let Some(alpha) = command.get_static_arg("alpha") {
+                    let mut a = [0; 10];
+                    decode_array(alpha, &mut a)?;
+                    self.alpha = Some(a);
+                }
+            }
+            (Direction::S2C, "initivexpand") => {
+                let private_key = if let Some(ref k) = self.private_key {
+                    k.clone()
+                } else {
+                    eprintln!("Found initivexpand, the identity of the client \
+                        is needed to decrypt the connection");
+                    return Ok(());
+                };
+                let mut alpha = [0; 10];
+                if let Some(a) = self.alpha {
+                    alpha = a;
+                } else if let Some(a) = command.get_static_arg("alpha") {
+                    decode_array(a, &mut alpha)?;
+                } else {
+                    bail!("Found no alpha");
+                }
+                let mut beta = [0; 10];
+                decode_array(command.get_static_arg("beta").ok_or_else(||
+                    format_err!("initivexpand contains no beta"))?,
+                    &mut beta)?;
+                let server_key = EccKeyPubP256::from_ts(
+                    command.get_static_arg("omega").ok_or_else(||
+                    format_err!("initivexpand contains no omega"))?)?;
+
+                let (iv, _) = algs::compute_iv_mac(&alpha, &beta,
+                    private_key, server_key)?;
+                self.shared_iv = Some(SharedIv::ProtocolOrig(iv));
+                eprintln!("Found initivexpand, decrypting the connection");
+            }
+            (Direction::S2C, "initivexpand2") => {
+                let iv = {
+                    let ek = if let Some(ref k) = self.ephemeral_key {
+                        k
+                    } else {
+                        eprintln!("Found initivexpand2, the ephemeral key of \
+                            the client is needed to decrypt the connection");
+                        return Ok(());
+                    };
+                    let alpha = self.alpha.ok_or_else(|| format_err!(
+                        "Found no clientinitiv"))?;
+                    let mut beta = [0; 54];
+                    decode_array(command.get_static_arg("beta").ok_or_else(||
+                        format_err!("initivexpand2 contains no beta"))?,
+                        &mut beta)?;
+                    let l = base64::decode(command.get_static_arg("l")
+                        .ok_or_else(|| format_err!(
+                            "initivexpand2 contains no license"))?)?;
+                    let server_ek = Licenses::parse(&l)?.derive_public_key()?;
+
+                    algs::compute_iv_mac31(&alpha, &beta, ek, &server_ek)?.0
+                };
+                self.shared_iv = Some(SharedIv::Protocol31(iv));
+                eprintln!("Found initivexpand2, decrypting the connection");
+            }
+            (Direction::C2S, "clientek") => {
+                if let (Some(ek), Some(ek_r)) = (self.ephemeral_key.as_ref(),
+                    command.get_static_arg("ek")) {
+                    if ek.to_pub().to_base64() != ek_r {
+                        eprintln!("The ephemeral key does not belong to this \
+                            connection");
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Parse a udp packet.
+    ///
+    /// If the direction is not known, it is guessed.
+    fn handle_udp_packet(&mut self, dir: Option<Direction>, data: &[u8])
+        -> Result<(Direction, Vec<packets::Packet>)> {
+        if let Some(dir) = dir {
+            return Ok((dir, self.get_packets(dir, data)?));
+        }
+        // A packet can be parsed in both directions, but only the right one
+        // can be decrypted.
+        match self.get_packets(Direction::C2S, data) {
+            Ok(p) => Ok((Direction::C2S, p)),
+            Err(_) => Ok((Direction::S2C,
+                self.get_packets(Direction::S2C, data)?)),
+        }
+    }
+}
+
+/// Print the commands of a packet as messages.
+///
+/// Commands which are not known are marked with a `!`.
+fn print_commands(args: &Args, time: f64, dir: Direction, udp: &UdpData,
+    packet: &packets::Packet) {
+    let command = if let packets::Data::Command(ref c) = packet.data {
+        c
+    } else {
+        return;
+    };
+    let mut raw = Vec::new();
+    let raw = if command.write(&mut raw).is_ok() {
+        String::from_utf8_lossy(&raw).into_owned()
+    } else {
+        String::new()
     };
-    let mut udp_packet = packet.split_off(pos);
 
-    // Try to fake decrypt the packet
-    println!("Header: {:?}", header);
-    if header.get_type() == PacketType::Command {
-        let udp_packet_bak = udp_packet.clone();
-        if algs::decrypt_fake(&header, &mut udp_packet).is_err() {
-            udp_packet.copy_from_slice(&udp_packet_bak);
+    for cmd in command.get_commands() {
+        let name = cmd.command.to_string();
+        let res = Message::parse(cmd);
+        if args.json {
+            let (message, error) = match res {
+                Ok(msg) => (Some(format!("{:?}", msg)), None),
+                Err(error) => (None, Some(error.to_string())),
+            };
+            println!("{}", json!({
+                "time": time,
+                "direction": format!("{:?}", dir),
+                "source": udp.source.to_string(),
+                "destination": udp.destination.to_string(),
+                "command": name,
+                "message": message,
+                "error": error,
+                "raw": raw,
+            }));
+            continue;
+        }
+        match res {
+            Ok(msg) => println!("{:10.3} {:?} {:?}", time, dir, msg),
+            Err(ParseError::UnknownCommand(name)) =>
+                println!("{:10.3} {:?} ! Unknown command {}: {}", time, dir,
+                    name, raw),
+            Err(error) => println!("{:10.3} {:?} ! {}: {}", time, dir, error,
+                raw),
         }
     }
-    let p_data = Data::read(
-        &header,
-        &mut Cursor::new(udp_packet.as_slice()),
-    )?;
-    let packet = tsproto::packets::Packet::new(header, p_data);
-    Ok(packet)
+}
+
+fn print_packet(args: &Args, time: f64, dir: Direction, udp: &UdpData,
+    packet: &packets::Packet) {
+    if args.commands {
+        print_commands(args, time, dir, udp, packet);
+    } else if args.json {
+        println!("{}", json!({
+            "time": time,
+            "direction": format!("{:?}", dir),
+            "source": udp.source.to_string(),
+            "destination": udp.destination.to_string(),
+            "type": format!("{:?}", packet.header.get_type()),
+            "id": packet.header.p_id,
+            "data": format!("{:?}", packet.data),
+        }));
+    } else {
+        println!("{:10.3} {:?}: {:?}", time, dir, packet);
+    }
 }
 
 fn real_main() -> Result<()> {
     // Parse command line options
     let args = Args::from_args();
+    tsproto::init()?;
 
-    let mut capture = pcap::Capture::from_file(args.file)?;
+    let types = args.types.iter().map(|t| parse_packet_type(t))
+        .collect::<Result<Vec<_>>>()?;
+    let mut session = Session::default();
+    if let Some(ref identity) = args.identity {
+        session.private_key = Some(parse_private_key(identity)?);
+    }
+    if let Some(ref ek) = args.ephemeral_key {
+        session.ephemeral_key = Some(EccKeyPrivEd25519::from_base64(ek)?);
+    }
 
-    while let Ok(packet) = capture.next() {
-        match get_udp_payload(&*packet) {
-            Ok(packet) => {
-                println!("Packet: {:?}", tsproto::utils::HexSlice(&packet));
-                // Try to parse as ts packet
-                let packet = if let Ok(p) = get_packet(&mut packet.clone(), true) {
-                    p
-                } else if let Ok(p) = get_packet(&mut packet.clone(), false) {
-                    p
-                } else {
-                    println!("Error, no ts packet");
-                    continue;
-                };
+    let mut capture = pcap::Capture::from_file(&args.file)?;
+    let linktype = capture.get_datalink().0;
+    // The time of the first packet
+    let mut start = None;
 
-                // Check if it is an init packet
-                println!("Packet: {:?}", packet);
-            }
+    while let Ok(packet) = capture.next() {
+        let time = packet.header.ts.tv_sec as f64
+            + packet.header.ts.tv_usec as f64 / 1_000_000.0;
+        let time = time - *start.get_or_insert(time);
+        let udp = match get_udp_packet(linktype, &*packet) {
+            Ok(udp) => udp,
             Err(_error) => {
                 //println!("Error: {:?}", _error);
+                continue;
             }
+        };
+
+        // Try to parse as ts packet
+        let dir = get_direction(&args, &udp);
+        match session.handle_udp_packet(dir, &udp.payload) {
+            Ok((dir, packets)) => for p in packets {
+                if types.is_empty() || types.contains(&p.header.get_type()) {
+                    print_packet(&args, time, dir, &udp, &p);
+                }
+            },
+            // Only commands are shown
+            Err(_) if args.commands => {}
+            Err(error) => if args.json {
+                println!("{}", json!({
+                    "time": time,
+                    "source": udp.source.to_string(),
+                    "destination": udp.destination.to_string(),
+                    "error": error.to_string(),
+                    "data": format!("{:?}",
+                        tsproto::utils::HexSlice(&udp.payload)),
+                }));
+            } else {
+                println!("Error, no ts packet ({}): {:?}", error,
+                    tsproto::utils::HexSlice(&udp.payload));
+            },
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tsproto::commands::Command;
+
+    use super::*;
+
+    /// Create the packets of a `sendtextmessage` command with the given
+    /// text, like a client sends them.
+    ///
+    /// Returns the uncompressed command and the packets.
+    fn command_packets(first_id: u16, text: &str)
+        -> (Vec<u8>, Vec<(Header, Vec<u8>)>) {
+        let mut command = Command::new("sendtextmessage");
+        command.push("msg", text);
+        let packet = packets::Packet::new(Header::new(PacketType::Command),
+            Data::Command(command));
+        let mut data = Vec::new();
+        packet.data.write(&mut data).unwrap();
+
+        let mut parts = algs::compress_and_split(true, &packet);
+        for (i, part) in parts.iter_mut().enumerate() {
+            part.0.p_id = first_id.wrapping_add(i as u16);
+        }
+        (data, parts)
+    }
+
+    /// Text which cannot be compressed well.
+    fn random_text(len: usize) -> String {
+        let mut state = 0x1234_5678u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            std::char::from_digit(state % 36, 36).unwrap()
+        }).collect()
+    }
+
+    /// Add packets to the queue and return the data of the completed
+    /// commands.
+    fn add(queue: &mut CommandQueue, packets: &[(Header, Vec<u8>)])
+        -> Vec<Vec<u8>> {
+        let mut res = Vec::new();
+        for &(ref header, ref data) in packets {
+            res.extend(queue.handle_packet(header.clone(), data.clone())
+                .unwrap().into_iter().map(|(_, d)| d));
+        }
+        res
+    }
+
+    /// A udp packet from port 9000 to 9979.
+    fn udp_frame(payload: &[u8]) -> Vec<u8> {
+        let len = 8 + payload.len() as u16;
+        let mut res = vec![0x23, 0x28, 0x26, 0xfb, (len >> 8) as u8,
+            len as u8, 0, 0];
+        res.extend_from_slice(payload);
+        res
+    }
+
+    /// An ipv4 packet from `1.2.3.4:9000` to `5.6.7.8:9979`.
+    fn ipv4_frame(payload: &[u8]) -> Vec<u8> {
+        let udp = udp_frame(payload);
+        let len = 20 + udp.len() as u16;
+        let mut res = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0, 0,
+            64, 17, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        res.extend_from_slice(&udp);
+        res
+    }
+
+    /// An ipv6 packet from `[::1]:9000` to `[::2]:9979`.
+    fn ipv6_frame(payload: &[u8]) -> Vec<u8> {
+        let udp = udp_frame(payload);
+        let len = udp.len() as u16;
+        let mut res = vec![0x60, 0, 0, 0, (len >> 8) as u8, len as u8, 17, 64];
+        res.extend_from_slice(&[0; 15]);
+        res.push(1);
+        res.extend_from_slice(&[0; 15]);
+        res.push(2);
+        res.extend_from_slice(&udp);
+        res
+    }
+
+    fn with_header(header: &[u8], frame: &[u8]) -> Vec<u8> {
+        let mut res = header.to_vec();
+        res.extend_from_slice(frame);
+        res
+    }
+
+    fn check_udp(linktype: i32, data: &[u8], ipv4: bool) {
+        let udp = get_udp_packet(linktype, data).unwrap();
+        let (source, destination) = if ipv4 {
+            ("1.2.3.4:9000", "5.6.7.8:9979")
+        } else {
+            ("[::1]:9000", "[::2]:9979")
+        };
+        assert_eq!(udp.source, source.parse().unwrap());
+        assert_eq!(udp.destination, destination.parse().unwrap());
+        assert_eq!(udp.payload, b"payload");
+    }
+
+    #[test]
+    fn udp_packet_link_types() {
+        let v4 = ipv4_frame(b"payload");
+        let v6 = ipv6_frame(b"payload");
+        let mut eth = [0; 14];
+        eth[12] = 0x08;
+        check_udp(LINKTYPE_ETHERNET, &with_header(&eth, &v4), true);
+        eth[12] = 0x86;
+        eth[13] = 0xdd;
+        check_udp(LINKTYPE_ETHERNET, &with_header(&eth, &v6), false);
+
+        let mut sll = [0; SLL_HEADER_LEN];
+        sll[14] = 0x08;
+        check_udp(LINKTYPE_LINUX_SLL, &with_header(&sll, &v4), true);
+        sll[14] = 0x86;
+        sll[15] = 0xdd;
+        check_udp(LINKTYPE_LINUX_SLL, &with_header(&sll, &v6), false);
+
+        for &linktype in &[LINKTYPE_RAW, LINKTYPE_RAW_OLD] {
+            check_udp(linktype, &v4, true);
+            check_udp(linktype, &v6, false);
+        }
+        check_udp(LINKTYPE_IPV4, &v4, true);
+        check_udp(LINKTYPE_IPV6, &v6, false);
+    }
+
+    #[test]
+    fn udp_packet_invalid() {
+        let v4 = ipv4_frame(b"payload");
+        assert!(get_udp_packet(LINKTYPE_LINUX_SLL, &v4[..10]).is_err());
+        assert!(get_udp_packet(LINKTYPE_RAW, &[]).is_err());
+        assert!(get_udp_packet(LINKTYPE_IPV6, &v4).is_err());
+        assert!(get_udp_packet(0, &v4).is_err());
+        // A tcp packet
+        let mut tcp = v4;
+        tcp[9] = 6;
+        assert!(get_udp_packet(LINKTYPE_IPV4, &tcp).is_err());
+    }
+
+    fn args(server_address: Option<&str>) -> Args {
+        Args {
+            file: String::new(),
+            identity: None,
+            ephemeral_key: None,
+            commands: false,
+            server_address: server_address.map(|a| a.parse().unwrap()),
+            server_port: 9987,
+            types: Vec::new(),
+            json: false,
+        }
+    }
+
+    fn udp_data(source: &str, destination: &str) -> UdpData {
+        UdpData {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            payload: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn direction_by_port() {
+        let args = args(None);
+        assert_eq!(get_direction(&args, &udp_data("1.2.3.4:9000",
+            "5.6.7.8:9987")), Some(Direction::C2S));
+        assert_eq!(get_direction(&args, &udp_data("5.6.7.8:9987",
+            "1.2.3.4:9000")), Some(Direction::S2C));
+        assert_eq!(get_direction(&args, &udp_data("1.2.3.4:9000",
+            "5.6.7.8:9000")), None);
+    }
+
+    #[test]
+    fn direction_by_server_address() {
+        let args = args(Some("5.6.7.8"));
+        assert_eq!(get_direction(&args, &udp_data("1.2.3.4:9000",
+            "5.6.7.8:9987")), Some(Direction::C2S));
+        assert_eq!(get_direction(&args, &udp_data("5.6.7.8:9987",
+            "1.2.3.4:9000")), Some(Direction::S2C));
+        // Only the port matches
+        assert_eq!(get_direction(&args, &udp_data("1.2.3.4:9000",
+            "9.9.9.9:9987")), None);
+        // Two clients on the same port, only one is the server
+        assert_eq!(get_direction(&args, &udp_data("1.2.3.4:9987",
+            "5.6.7.8:9987")), Some(Direction::C2S));
+    }
+
+    #[test]
+    fn command_queue_reorder() {
+        let (data0, packets0) = command_packets(0, "a");
+        let (data1, packets1) = command_packets(1, "b");
+        let (data2, packets2) = command_packets(2, "c");
+        let mut queue = CommandQueue::default();
+
+        assert_eq!(add(&mut queue, &packets0), vec![data0]);
+        // Wait for the missing packet
+        assert!(add(&mut queue, &packets2).is_empty());
+        assert_eq!(add(&mut queue, &packets1), vec![data1, data2]);
+        assert!(queue.receive_queue.is_empty());
+    }
+
+    #[test]
+    fn command_queue_resent() {
+        // Start right before the packet id wraps around
+        let (data0, packets0) = command_packets(0xffff, "a");
+        let (data1, packets1) = command_packets(0, "b");
+        let (data2, packets2) = command_packets(1, "c");
+        let mut queue = CommandQueue::default();
+
+        assert_eq!(add(&mut queue, &packets0), vec![data0]);
+        // Packets which were already received are ignored
+        assert!(add(&mut queue, &packets0).is_empty());
+        // Queued packets are stored only once
+        assert!(add(&mut queue, &packets2).is_empty());
+        assert!(add(&mut queue, &packets2).is_empty());
+        assert_eq!(queue.receive_queue.len(), 1);
+        assert_eq!(add(&mut queue, &packets1), vec![data1, data2]);
+        assert!(add(&mut queue, &packets1).is_empty());
+    }
+
+    #[test]
+    fn command_queue_fragments() {
+        let (data, packets) = command_packets(10, &random_text(2000));
+        assert!(packets.len() > 2);
+        let mut queue = CommandQueue::default();
+
+        // Only the last fragment completes the command
+        assert!(add(&mut queue, &packets[..packets.len() - 1]).is_empty());
+        assert_eq!(add(&mut queue, &packets[packets.len() - 1..]), vec![data]);
+
+        // Fragments which arrive in the wrong order
+        let (data, mut packets) = command_packets(0, &random_text(2000));
+        let mut queue = CommandQueue::default();
+        assert!(add(&mut queue, &packets[..1]).is_empty());
+        let second = packets.remove(1);
+        packets.reverse();
+        assert!(add(&mut queue, &packets[..packets.len() - 1]).is_empty());
+        assert_eq!(add(&mut queue, &[second]), vec![data]);
+    }
+
+    #[test]
+    fn command_queue_compressed() {
+        let text: String = std::iter::repeat("compress ").take(100).collect();
+        let (data, packets) = command_packets(0, &text);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].0.get_compressed());
+        assert!(packets[0].1.len() < data.len());
+
+        let mut queue = CommandQueue::default();
+        assert_eq!(add(&mut queue, &packets), vec![data]);
+    }
+}
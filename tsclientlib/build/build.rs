@@ -31,7 +31,7 @@ fn main() {
 
     // Write facades
     let mut structs = File::create(&path.join("facades.rs")).unwrap();
-    write!(&mut structs, "{}", FacadeDeclarations(decls)).unwrap();
+    write!(&mut structs, "{}", FacadeDeclarations(&decls, &messages)).unwrap();
 
     // Write getters
     let mut structs = File::create(&path.join("getters.rs")).unwrap();
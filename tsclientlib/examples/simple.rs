@@ -63,9 +63,10 @@ fn real_main() -> Result<(), failure::Error> {
     core.run(action.select2(cm.run())).unwrap();
 
     // Disconnect
-    core.run(cm.remove_connection(con_id, DisconnectOptions::new()
+    let disconnect = cm.remove_connection(con_id, DisconnectOptions::new()
         .reason(Reason::Clientdisconnect)
-        .message("Is this the real world?")))?;
+        .message("Is this the real world?"));
+    core.run(disconnect.select2(cm.run())).map_err(|e| e.split().0)?;
 
     Ok(())
 }
@@ -0,0 +1,92 @@
+//! Benchmarks of polling the connection manager with many connections.
+//!
+//! Only the connection which received a message should be polled, so the time
+//! to get a message should not depend on the number of connections.
+//!
+//! Run with `cargo +nightly bench --features unstable`.
+use futures::{future, Async};
+use futures::executor;
+use test::Bencher;
+use tokio_core::reactor::Core;
+
+use super::*;
+use test_utils::*;
+
+/// Add `count` connections to the manager, which receive their packets from
+/// the returned channels.
+fn add_connections(cm: &ConnectionManager, count: usize)
+    -> Vec<mpsc::UnboundedSender<(SocketAddr, Packet)>> {
+    let handle = cm.inner.borrow().handle.clone();
+    let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+    let mut senders = Vec::new();
+    for i in 0..count {
+        let client = client_data(&handle);
+        let con = Data::create_connection(&client, addr);
+        Data::add_connection(&client, con.clone());
+
+        // Replace the packets from the network
+        let (send, recv) = mpsc::unbounded();
+        client.borrow_mut().packet_stream = Some(Box::new(recv.map_err(|()|
+            -> tsproto::Error {
+            format_err!("The packet channel was closed").into()
+        })));
+        senders.push(send);
+
+        let id = ConnectionId(i);
+        let wrapper = structs::NetworkWrapper::new(id, client,
+            Rc::downgrade(&con), &init_server(),
+            ConnectOptions::from_address(addr));
+        cm.inner.borrow_mut().connections.insert(id, wrapper);
+        cm.inner.borrow().ready.push(i);
+    }
+    senders
+}
+
+fn text_message() -> Packet {
+    let cmd = commands::Command::read((), &mut "notifytextmessage \
+        targetmode=2 msg=Hello invokerid=5 invokername=Test invokeruid=abc="
+        .as_bytes()).unwrap();
+    Packet::new(Header::new(PacketType::Command), packets::Data::Command(cmd))
+}
+
+/// Send a message to one connection after another and poll it.
+fn poll_stream(b: &mut Bencher, count: usize) {
+    let core = Core::new().unwrap();
+    let mut cm = ConnectionManager::new(core.handle());
+    let senders = add_connections(&cm, count);
+    let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+    let packet = text_message();
+    let mut next = 0;
+
+    executor::spawn(future::lazy(|| -> Result<(), ()> {
+        // Poll every connection once, so they register themselves
+        match cm.poll_stream() {
+            Ok(Async::NotReady) => {}
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        b.iter(|| {
+            // One connection received a packet
+            senders[next].unbounded_send((addr, packet.clone())).unwrap();
+            next = (next + 1) % count;
+
+            match cm.poll_stream() {
+                Ok(Async::Ready(Some(_))) => {}
+                res => panic!("Expected a message, got {:?}", res),
+            }
+            // The connection is queued again because it may have more messages
+            match cm.poll_stream() {
+                Ok(Async::NotReady) => {}
+                res => panic!("Unexpected result {:?}", res),
+            }
+        });
+        Ok(())
+    })).wait_future().unwrap();
+}
+
+#[bench]
+fn poll_stream_10(b: &mut Bencher) { poll_stream(b, 10); }
+#[bench]
+fn poll_stream_100(b: &mut Bencher) { poll_stream(b, 100); }
+#[bench]
+fn poll_stream_1000(b: &mut Bencher) { poll_stream(b, 1000); }
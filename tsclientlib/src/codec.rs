@@ -2,12 +2,14 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use futures::{future, Sink, stream, Stream};
+use num::{FromPrimitive, ToPrimitive};
 use tsproto::Error;
 use tsproto::commands::Command;
 use tsproto::connectionmanager::ConnectionManager;
 use tsproto::handler_data;
-use tsproto::packets::{Data, Header, Packet, PacketType};
+use tsproto::packets::{CodecType, Data, Header, Packet, PacketType};
 
+use tsproto_commands::{ChannelId, ClientId};
 use tsproto_commands::messages;
 
 /// A "high-level" packet, which contains either a notification or audio.
@@ -17,11 +19,93 @@ use tsproto_commands::messages;
 #[derive(Debug, Clone)]
 pub enum Message {
 	Message(Box<messages::Message>),
-	/// Not yet implemented
-	/// Lazily decoded/transcoded? (for sending and receiving)
-	/// So we do not decode + encode if the source format is the destination
-	/// format.
-	Audio(),
+	/// The audio data is not decoded, so it can be forwarded without
+	/// transcoding if the source format is the destination format.
+	Audio(AudioPacket),
+}
+
+/// A voice packet, which is received from or sent to the server.
+#[derive(Debug, Clone)]
+pub struct AudioPacket {
+	/// The client who sent this packet.
+	///
+	/// This is ignored when sending.
+	pub from: ClientId,
+	pub codec: CodecType,
+	/// The counter of the voice packets of the sender.
+	///
+	/// This is ignored when sending, the connection counts the sent packets.
+	pub id: u16,
+	pub target: AudioTarget,
+	/// The encoded audio data.
+	pub data: Vec<u8>,
+}
+
+/// The receivers of a voice packet.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AudioTarget {
+	/// Everyone in the current channel.
+	Channel,
+	/// Whisper to a list of channels and clients.
+	///
+	/// Received whisper packets do not contain their targets, so both lists
+	/// are empty in this case.
+	Whisper {
+		channels: Vec<ChannelId>,
+		clients: Vec<ClientId>,
+	},
+}
+
+impl AudioPacket {
+	/// Create a new packet which can be sent to the current channel.
+	pub fn new(codec: CodecType, data: Vec<u8>) -> Self {
+		Self {
+			from: ClientId(0),
+			codec,
+			id: 0,
+			target: AudioTarget::Channel,
+			data,
+		}
+	}
+
+	/// Whisper this packet to the given channels and clients instead of
+	/// sending it to the current channel.
+	pub fn whisper(mut self, channels: Vec<ChannelId>, clients: Vec<ClientId>)
+		-> Self {
+		self.target = AudioTarget::Whisper { channels, clients };
+		self
+	}
+}
+
+/// Create a voice packet which can be sent to the server.
+fn audio_to_packet(packet: AudioPacket) -> Result<Packet, Error> {
+	let codec_type = packet.codec.to_u8().unwrap();
+	let (p_type, data) = match packet.target {
+		AudioTarget::Channel => (PacketType::Voice,
+			Data::VoiceC2S {
+				id: packet.id,
+				codec_type,
+				voice_data: packet.data,
+			}),
+		AudioTarget::Whisper { channels, clients } => {
+			// The number of targets is sent as a single byte
+			let max = usize::from(u8::max_value());
+			if channels.len() > max || clients.len() > max {
+				return Err(format_err!("Cannot whisper to more than {} channels \
+					or clients", max).into());
+			}
+			(PacketType::VoiceWhisper, Data::VoiceWhisperC2S {
+				id: packet.id,
+				codec_type,
+				channel_count: channels.len() as u8,
+				client_count: clients.len() as u8,
+				channel_ids: channels.iter().map(|c| c.0).collect(),
+				client_ids: clients.iter().map(|c| c.0).collect(),
+				voice_data: packet.data,
+			})
+		}
+	};
+	Ok(Packet::new(Header::new(p_type), data))
 }
 
 /// Convert a stream/sink of `Packet`s to a stream of `Message`s.
@@ -52,8 +136,30 @@ impl CommandCodec {
 						}).collect();
 					Box::new(stream::iter_ok(cmds))
 				}
-				Data::VoiceS2C { .. } | Data::VoiceWhisperS2C { .. } => {
-					Box::new(stream::once(Ok((con_key, Message::Audio()))))
+				Data::VoiceS2C { id, from_id, codec_type, voice_data } |
+				Data::VoiceWhisperS2C { id, from_id, codec_type, voice_data } => {
+					let target = if p.header.get_type() == PacketType::Voice {
+						AudioTarget::Channel
+					} else {
+						AudioTarget::Whisper {
+							channels: Vec::new(),
+							clients: Vec::new(),
+						}
+					};
+					if let Some(codec) = CodecType::from_u8(codec_type) {
+						Box::new(stream::once(Ok((con_key,
+							Message::Audio(AudioPacket {
+								from: ClientId(from_id),
+								codec,
+								id,
+								target,
+								data: voice_data,
+							})))))
+					} else {
+						warn!(logger, "Unknown codec in voice packet";
+							"codec" => codec_type);
+						Box::new(stream::empty())
+					}
 				}
 				// Ignore other packets
 				_ => Box::new(stream::empty()),
@@ -62,16 +168,72 @@ impl CommandCodec {
 		}).flatten())
 	}
 
+	/// Convert a sink of `Packet`s to a sink of `Message`s.
+	///
+	/// Messages are sent as `Command` or `CommandLow` packets and get the
+	/// newprotocol flag, as it is declared for their message group.
 	pub fn new_sink<CM: ConnectionManager + 'static>(
 		data: &Rc<RefCell<handler_data::Data<CM>>>) -> Box<Sink<
 		SinkItem = (CM::ConnectionsKey, Message), SinkError = Error>> {
 		let packets = handler_data::Data::get_packets(Rc::downgrade(data));
 
-		Box::new(packets.with(|(_con_key, _msg)| {
-			// TODO Use Command or CommandLow, set newprotocol flag
-			let _cmd: Command = panic!(); //n.into();
-			let _header = Header::new(PacketType::Command);
-			future::ok((_con_key, Packet::new(_header, Data::Command(_cmd))))
+		Box::new(packets.with(|(con_key, msg)| -> Result<_, Error> {
+			match msg {
+				Message::Message(msg) => {
+					let is_low = msg.is_low();
+					let mut header = Header::new(if is_low {
+						PacketType::CommandLow
+					} else {
+						PacketType::Command
+					});
+					header.set_newprotocol(msg.is_newprotocol());
+
+					let cmd: Command = (*msg).into();
+					let data = if is_low {
+						Data::CommandLow(cmd)
+					} else {
+						Data::Command(cmd)
+					};
+					Ok((con_key, Packet::new(header, data)))
+				}
+				Message::Audio(packet) =>
+					Ok((con_key, audio_to_packet(packet)?)),
+			}
 		}))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn whisper_packet() {
+		let packet = AudioPacket::new(CodecType::OpusVoice, vec![1, 2, 3])
+			.whisper(vec![ChannelId(5)], vec![ClientId(1), ClientId(2)]);
+		let packet = audio_to_packet(packet).unwrap();
+		assert_eq!(packet.header.get_type(), PacketType::VoiceWhisper);
+		if let Data::VoiceWhisperC2S { channel_count, client_count,
+			ref channel_ids, ref client_ids, .. } = packet.data {
+			assert_eq!(channel_count, 1);
+			assert_eq!(client_count, 2);
+			assert_eq!(*channel_ids, vec![5]);
+			assert_eq!(*client_ids, vec![1, 2]);
+		} else {
+			panic!("Expected a whisper packet");
+		}
+	}
+
+	#[test]
+	fn too_many_whisper_targets() {
+		let clients = (0..256).map(ClientId).collect();
+		let packet = AudioPacket::new(CodecType::OpusVoice, vec![1])
+			.whisper(Vec::new(), clients);
+		assert!(audio_to_packet(packet).is_err());
+
+		let clients = (0..255).map(ClientId).collect();
+		let packet = AudioPacket::new(CodecType::OpusVoice, vec![1])
+			.whisper(Vec::new(), clients);
+		assert!(audio_to_packet(packet).is_ok());
+	}
+}
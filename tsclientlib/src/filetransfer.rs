@@ -0,0 +1,619 @@
+//! The data channel of file transfers.
+//!
+//! A file transfer is started with a command over the normal connection
+//! (`ftinitupload` or `ftinitdownload`). The server answers with a port and a
+//! transfer key. The client then opens a tcp connection to this port, sends
+//! the key and afterwards sends or receives the content of the file.
+//!
+//! This module contains the client side of the data channel ([`Upload`] and
+//! [`Download`]) and a [`FileTransferServer`], which implements the server
+//! side in-process, so transfers can be tested without a TeamSpeak server.
+//!
+//! [`Upload`]: struct.Upload.html
+//! [`Download`]: struct.Download.html
+//! [`FileTransferServer`]: struct.FileTransferServer.html
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+
+use chrono::{DateTime, Utc};
+use futures::{Async, Future, Poll, Stream};
+use slog::Logger;
+use tokio_core::net::{TcpListener, TcpStream, TcpStreamNew};
+use tokio_core::reactor::Handle;
+use tsproto_commands::ChannelId;
+
+use {Error, Map, Result};
+use utils::{poll_io, poll_write_all};
+
+/// The length of the transfer keys which are created by the
+/// [`FileTransferServer`].
+///
+/// [`FileTransferServer`]: struct.FileTransferServer.html
+const KEY_LENGTH: usize = 16;
+/// How many bytes are read from a socket at once.
+const CHUNK_SIZE: usize = 4096;
+
+/// A file or directory on the server, as returned by a file listing.
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// The channel which contains this file.
+    pub channel: ChannelId,
+    /// The directory which contains this file.
+    pub path: String,
+    pub name: String,
+    /// The size in bytes.
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// `false` if this is a directory.
+    pub is_file: bool,
+}
+
+enum State {
+    Connecting(TcpStreamNew),
+    /// `key_pos` is the number of bytes of the transfer key which are sent.
+    Connected { stream: TcpStream, key_pos: usize },
+    Done,
+}
+
+impl State {
+    /// Connect to the server and send the key.
+    ///
+    /// Returns the stream when the key is sent.
+    fn poll(&mut self, key: &[u8]) -> Poll<&mut TcpStream, Error> {
+        let connected = if let State::Connecting(ref mut connect) = *self {
+            Some(try_ready!(connect.poll()))
+        } else {
+            None
+        };
+        if let Some(stream) = connected {
+            *self = State::Connected { stream, key_pos: 0 };
+        }
+
+        match *self {
+            State::Connected { ref mut stream, ref mut key_pos } => {
+                try_ready!(poll_write_all(stream, key, key_pos));
+                Ok(Async::Ready(stream))
+            }
+            State::Done => Err(format_err!(
+                "The file transfer is already finished").into()),
+            State::Connecting(_) =>
+                unreachable!("The file transfer should be connected"),
+        }
+    }
+}
+
+/// Uploads a file over the data channel.
+///
+/// This is a stream which yields the number of bytes of the file that are
+/// already at the server, so it can be used to display the progress. The
+/// stream ends when the whole file is sent.
+///
+/// The transfer is cancelled when this object is dropped.
+pub struct Upload {
+    server_transfer_id: u16,
+    key: Vec<u8>,
+    data: Vec<u8>,
+    /// The position in `data` up to which it was sent.
+    pos: usize,
+    state: State,
+}
+
+impl Upload {
+    /// Start uploading `data` to the server.
+    ///
+    /// # Arguments
+    /// - `addr`: The address of the file transfer server.
+    /// - `server_transfer_id`: The id of the transfer as returned by the
+    ///   server.
+    /// - `key`: The transfer key as returned by the server.
+    /// - `data`: The whole content of the file.
+    /// - `seek_position`: Where the upload should start, this is used to
+    ///   resume aborted uploads.
+    pub fn new(handle: &Handle, addr: SocketAddr, server_transfer_id: u16,
+        key: &str, data: Vec<u8>, seek_position: u64) -> Self {
+        let pos = ::std::cmp::min(seek_position as usize, data.len());
+        Self {
+            server_transfer_id,
+            key: key.as_bytes().to_vec(),
+            data,
+            pos,
+            state: State::Connecting(TcpStream::connect(&addr, handle)),
+        }
+    }
+
+    /// The id of this transfer on the server.
+    ///
+    /// It can be used to stop the transfer with
+    /// [`ConnectionMut::stop_file_transfer`].
+    ///
+    /// [`ConnectionMut::stop_file_transfer`]: ../struct.ConnectionMut.html#method.stop_file_transfer
+    pub fn get_server_transfer_id(&self) -> u16 {
+        self.server_transfer_id
+    }
+
+    /// The size of the uploaded file.
+    pub fn get_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+impl Stream for Upload {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let State::Done = self.state {
+            return Ok(Async::Ready(None));
+        }
+        let done = {
+            let stream = try_ready!(self.state.poll(&self.key));
+            if self.pos < self.data.len() {
+                let written = try_ready!(poll_io(
+                    stream.write(&self.data[self.pos..])));
+                if written == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                        "Cannot write to file transfer connection").into());
+                }
+                self.pos += written;
+                false
+            } else {
+                stream.shutdown(Shutdown::Write)?;
+                true
+            }
+        };
+        if done {
+            self.state = State::Done;
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(self.pos as u64)))
+        }
+    }
+}
+
+/// Downloads a file over the data channel.
+///
+/// This is a stream which yields the received parts of the file. The stream
+/// ends when the whole file is received.
+///
+/// The transfer is cancelled when this object is dropped.
+pub struct Download {
+    server_transfer_id: u16,
+    key: Vec<u8>,
+    /// The remaining bytes which should be received.
+    remaining: u64,
+    state: State,
+}
+
+impl Download {
+    /// Start downloading a file from the server.
+    ///
+    /// # Arguments
+    /// - `addr`: The address of the file transfer server.
+    /// - `server_transfer_id`: The id of the transfer as returned by the
+    ///   server.
+    /// - `key`: The transfer key as returned by the server.
+    /// - `size`: The size of the file as returned by the server.
+    /// - `seek_position`: Where the download should start, this is used to
+    ///   resume aborted downloads.
+    pub fn new(handle: &Handle, addr: SocketAddr, server_transfer_id: u16,
+        key: &str, size: u64, seek_position: u64) -> Self {
+        Self {
+            server_transfer_id,
+            key: key.as_bytes().to_vec(),
+            remaining: size.saturating_sub(seek_position),
+            state: State::Connecting(TcpStream::connect(&addr, handle)),
+        }
+    }
+
+    /// The id of this transfer on the server.
+    ///
+    /// It can be used to stop the transfer with
+    /// [`ConnectionMut::stop_file_transfer`].
+    ///
+    /// [`ConnectionMut::stop_file_transfer`]: ../struct.ConnectionMut.html#method.stop_file_transfer
+    pub fn get_server_transfer_id(&self) -> u16 {
+        self.server_transfer_id
+    }
+
+    /// The number of bytes which are not yet received.
+    pub fn get_remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl Stream for Download {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let State::Done = self.state {
+            return Ok(Async::Ready(None));
+        }
+        if self.remaining == 0 {
+            self.state = State::Done;
+            return Ok(Async::Ready(None));
+        }
+        let len = ::std::cmp::min(self.remaining, CHUNK_SIZE as u64) as usize;
+        let mut buf = vec![0; len];
+        let read = {
+            let stream = try_ready!(self.state.poll(&self.key));
+            try_ready!(poll_io(stream.read(&mut buf)))
+        };
+        if read == 0 {
+            return Err(format_err!("The file transfer connection was closed \
+                with {} bytes remaining", self.remaining).into());
+        }
+        buf.truncate(read);
+        self.remaining -= read as u64;
+        Ok(Async::Ready(Some(buf)))
+    }
+}
+
+/// A transfer which was announced to the [`FileTransferServer`] but did not
+/// connect yet.
+///
+/// [`FileTransferServer`]: struct.FileTransferServer.html
+#[derive(Debug)]
+enum PendingTransfer {
+    Upload { name: String, seek_position: u64 },
+    Download { name: String, seek_position: u64 },
+}
+
+#[derive(Debug, Default)]
+struct ServerState {
+    files: Map<String, Vec<u8>>,
+    transfers: Map<String, PendingTransfer>,
+    next_key: u64,
+}
+
+impl ServerState {
+    fn add_transfer(&mut self, transfer: PendingTransfer) -> String {
+        let key = format!("{:01$}", self.next_key, KEY_LENGTH);
+        self.next_key += 1;
+        self.transfers.insert(key.clone(), transfer);
+        key
+    }
+}
+
+/// A file transfer server which keeps all files in memory.
+///
+/// It implements only the data channel, the transfers have to be announced
+/// with [`add_upload`] and [`add_download`], which return the key and seek
+/// position that would normally be sent by the server in the answer to
+/// `ftinitupload` and `ftinitdownload`.
+///
+/// The server runs as long as the event loop of the given handle runs.
+///
+/// [`add_upload`]: #method.add_upload
+/// [`add_download`]: #method.add_download
+pub struct FileTransferServer {
+    addr: SocketAddr,
+    state: Rc<RefCell<ServerState>>,
+}
+
+impl FileTransferServer {
+    /// Start a server which listens on the given address.
+    pub fn new(handle: &Handle, addr: SocketAddr, logger: Logger)
+        -> Result<Self> {
+        let listener = TcpListener::bind(&addr, handle)?;
+        let addr = listener.local_addr()?;
+        let state = Rc::new(RefCell::new(ServerState::default()));
+
+        let handle2 = handle.clone();
+        let state2 = state.clone();
+        let logger2 = logger.clone();
+        handle.spawn(listener.incoming().map_err(Error::from)
+            .for_each(move |(stream, _)| {
+                let logger = logger2.clone();
+                handle2.spawn(ServerConnection::new(stream, state2.clone())
+                    .map_err(move |error| {
+                        warn!(logger, "File transfer failed";
+                            "error" => ?error);
+                    }));
+                Ok(())
+            }).map_err(move |error: Error| {
+                error!(logger, "File transfer server exited with error";
+                    "error" => ?error);
+            }));
+
+        Ok(Self { addr, state })
+    }
+
+    /// The address where the server listens.
+    pub fn get_address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Announce an upload of a file.
+    ///
+    /// If `resume` is `true` and the file exists already, the upload
+    /// continues at the end of the existing file.
+    ///
+    /// Returns the transfer key and the seek position.
+    pub fn add_upload(&self, name: &str, resume: bool) -> (String, u64) {
+        let mut state = self.state.borrow_mut();
+        let seek_position = if resume {
+            state.files.get(name).map(|f| f.len() as u64).unwrap_or(0)
+        } else {
+            0
+        };
+        let key = state.add_transfer(PendingTransfer::Upload {
+            name: name.to_string(),
+            seek_position,
+        });
+        (key, seek_position)
+    }
+
+    /// Announce a download of a file.
+    ///
+    /// Returns the transfer key and the size of the file or `None` if the
+    /// file does not exist.
+    pub fn add_download(&self, name: &str, seek_position: u64)
+        -> Option<(String, u64)> {
+        let mut state = self.state.borrow_mut();
+        let size = if let Some(file) = state.files.get(name) {
+            file.len() as u64
+        } else {
+            return None;
+        };
+        let key = state.add_transfer(PendingTransfer::Download {
+            name: name.to_string(),
+            seek_position,
+        });
+        Some((key, size))
+    }
+
+    /// The content of a stored file.
+    pub fn get_file(&self, name: &str) -> Option<Vec<u8>> {
+        self.state.borrow().files.get(name).cloned()
+    }
+
+    /// Store a file, an existing file with the same name is replaced.
+    pub fn set_file(&self, name: &str, content: Vec<u8>) {
+        self.state.borrow_mut().files.insert(name.to_string(), content);
+    }
+}
+
+enum ServerConnectionState {
+    ReadKey,
+    Upload { name: String },
+    Download { data: Vec<u8>, pos: usize },
+}
+
+/// The server side of one data channel.
+struct ServerConnection {
+    stream: TcpStream,
+    server: Rc<RefCell<ServerState>>,
+    key: Vec<u8>,
+    state: ServerConnectionState,
+}
+
+impl ServerConnection {
+    fn new(stream: TcpStream, server: Rc<RefCell<ServerState>>) -> Self {
+        Self {
+            stream,
+            server,
+            key: Vec::new(),
+            state: ServerConnectionState::ReadKey,
+        }
+    }
+}
+
+impl Future for ServerConnection {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                ServerConnectionState::ReadKey => {
+                    let mut buf = [0; KEY_LENGTH];
+                    let len = KEY_LENGTH - self.key.len();
+                    let read = try_ready!(poll_io(
+                        self.stream.read(&mut buf[..len])));
+                    if read == 0 {
+                        return Err(format_err!("The connection was closed \
+                            before the transfer key was received").into());
+                    }
+                    self.key.extend_from_slice(&buf[..read]);
+                    if self.key.len() < KEY_LENGTH {
+                        continue;
+                    }
+
+                    let key = String::from_utf8_lossy(&self.key).into_owned();
+                    let mut server = self.server.borrow_mut();
+                    match server.transfers.remove(&key) {
+                        Some(PendingTransfer::Upload { name,
+                            seek_position }) => {
+                            let file = server.files.entry(name.clone())
+                                .or_insert_with(Vec::new);
+                            file.truncate(seek_position as usize);
+                            ServerConnectionState::Upload { name }
+                        }
+                        Some(PendingTransfer::Download { name,
+                            seek_position }) => {
+                            let data = server.files.get(&name).cloned()
+                                .unwrap_or_default();
+                            let pos = ::std::cmp::min(seek_position as usize,
+                                data.len());
+                            ServerConnectionState::Download { data, pos }
+                        }
+                        None => return Err(format_err!(
+                            "Unknown file transfer key {:?}", key).into()),
+                    }
+                }
+                ServerConnectionState::Upload { ref name } => {
+                    let mut buf = [0; CHUNK_SIZE];
+                    let read = try_ready!(poll_io(self.stream.read(&mut buf)));
+                    if read == 0 {
+                        return Ok(Async::Ready(()));
+                    }
+                    let mut server = self.server.borrow_mut();
+                    server.files.entry(name.clone()).or_insert_with(Vec::new)
+                        .extend_from_slice(&buf[..read]);
+                    continue;
+                }
+                ServerConnectionState::Download { ref data, ref mut pos } => {
+                    try_ready!(poll_write_all(&mut self.stream, data, pos));
+                    self.stream.shutdown(Shutdown::Write)?;
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils::*;
+
+    fn create_server(core: &Core) -> FileTransferServer {
+        FileTransferServer::new(&core.handle(),
+            "127.0.0.1:0".parse().unwrap(), logger()).unwrap()
+    }
+
+    /// Create a file with a recognizable content.
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    /// Upload a file and return the progress reports.
+    fn upload(core: &mut Core, server: &FileTransferServer, name: &str,
+        data: &[u8], resume: bool) -> Vec<u64> {
+        let (key, seek_position) = server.add_upload(name, resume);
+        let upload = Upload::new(&core.handle(), server.get_address(), 1,
+            &key, data.to_vec(), seek_position);
+        assert_eq!(upload.get_size(), data.len() as u64);
+        let progress = run(core, upload.collect());
+        // Wait until the server received everything
+        run_until(core, || server.get_file(name).map(|f| f.len())
+            == Some(data.len()));
+        progress
+    }
+
+    /// Download a file and return the received parts.
+    fn download(core: &mut Core, server: &FileTransferServer, name: &str,
+        seek_position: u64) -> Vec<Vec<u8>> {
+        let (key, size) = server.add_download(name, seek_position).unwrap();
+        let download = Download::new(&core.handle(), server.get_address(), 1,
+            &key, size, seek_position);
+        assert_eq!(download.get_remaining(),
+            size.saturating_sub(seek_position));
+        run(core, download.collect())
+    }
+
+    /// Check that the progress grows and ends at the end of the file.
+    fn check_progress(progress: &[u64], start: u64, len: u64) {
+        assert!(!progress.is_empty());
+        assert!(progress[0] > start);
+        assert!(progress.windows(2).all(|w| w[0] < w[1]), "{:?}", progress);
+        assert_eq!(*progress.last().unwrap(), len);
+    }
+
+    #[test]
+    fn upload_file() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(100_000);
+        let progress = upload(&mut core, &server, "/file", &data, false);
+        check_progress(&progress, 0, data.len() as u64);
+        assert_eq!(server.get_file("/file").unwrap(), data);
+
+        // Overwrite the file
+        let data = content(10);
+        upload(&mut core, &server, "/file", &data, false);
+        assert_eq!(server.get_file("/file").unwrap(), data);
+    }
+
+    #[test]
+    fn download_file() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(100_000);
+        server.set_file("/file", data.clone());
+        let parts = download(&mut core, &server, "/file", 0);
+        assert!(parts.iter().all(|p| !p.is_empty()));
+        assert_eq!(parts.concat(), data);
+
+        assert!(server.add_download("/missing", 0).is_none());
+    }
+
+    #[test]
+    fn resume_upload() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(100_000);
+        server.set_file("/file", data[..30_000].to_vec());
+        let progress = upload(&mut core, &server, "/file", &data, true);
+        check_progress(&progress, 30_000, data.len() as u64);
+        assert_eq!(server.get_file("/file").unwrap(), data);
+    }
+
+    #[test]
+    fn resume_download() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(100_000);
+        server.set_file("/file", data.clone());
+        let parts = download(&mut core, &server, "/file", 30_000);
+        assert_eq!(parts.concat(), &data[30_000..]);
+
+        // Nothing is left to download
+        let parts = download(&mut core, &server, "/file", 100_000);
+        assert!(parts.is_empty());
+    }
+
+    #[test]
+    fn cancel_upload() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(8 * 1024 * 1024);
+
+        // Stop the upload after the first progress report
+        let (key, seek_position) = server.add_upload("/file", false);
+        let transfer = Upload::new(&core.handle(), server.get_address(), 1,
+            &key, data.clone(), seek_position);
+        let (sent, transfer) = match core.run(transfer.into_future()) {
+            Ok((Some(sent), transfer)) => (sent, transfer),
+            Ok((None, _)) => panic!("The upload sent nothing"),
+            Err((error, _)) => panic!("Upload failed: {:?}", error),
+        };
+        drop(transfer);
+        run_until(&mut core, || server.get_file("/file").map(|f| f.len())
+            == Some(sent as usize));
+
+        // Resume the cancelled upload
+        let progress = upload(&mut core, &server, "/file", &data, true);
+        assert!(progress.iter().all(|p| *p > sent));
+        assert_eq!(server.get_file("/file").unwrap(), data);
+    }
+
+    #[test]
+    fn cancel_download() {
+        let mut core = Core::new().unwrap();
+        let server = create_server(&core);
+        let data = content(8 * 1024 * 1024);
+        server.set_file("/file", data.clone());
+
+        let (key, size) = server.add_download("/file", 0).unwrap();
+        let transfer = Download::new(&core.handle(), server.get_address(), 1,
+            &key, size, 0);
+        let (part, transfer) = match core.run(transfer.into_future()) {
+            Ok((Some(part), transfer)) => (part, transfer),
+            Ok((None, _)) => panic!("The download received nothing"),
+            Err((error, _)) => panic!("Download failed: {:?}", error),
+        };
+        assert!(transfer.get_remaining() < size);
+        drop(transfer);
+
+        // Resume the cancelled download
+        let parts = download(&mut core, &server, "/file", part.len() as u64);
+        assert_eq!([part, parts.concat()].concat(), data);
+    }
+}
@@ -29,25 +29,35 @@
 // The NetworkWrapper wraps the stream of Messages and updates the
 // bookkeeping, raises events, etc. on new packets.
 // The ConnectionManager wraps all those streams into one stream (like a
-// select). To progress, the user of the library has to poll the
-// ConnectionManager for new notifications and sound.
+// select). Every stream is polled with its own NotifyHandle, which puts it
+// into the ReadyQueue when it is woken up, so only those streams are polled.
+// To progress, the user of the library has to poll the ConnectionManager for
+// new notifications and sound.
 //
 // The items of the stream are either Messages or audio data.
 
 // TODO
 #![allow(dead_code)]
+// The benchmarks need a nightly compiler
+#![cfg_attr(all(test, feature = "unstable"), feature(test))]
 
 extern crate base64;
 extern crate chrono;
 #[macro_use]
 extern crate failure;
+#[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
+extern crate num;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_perf;
 extern crate slog_term;
+#[cfg(all(test, feature = "unstable"))]
+extern crate test;
 extern crate tokio_core;
+extern crate trust_dns_resolver;
 extern crate tsproto;
 extern crate tsproto_commands;
 
@@ -55,16 +65,20 @@ use std::cell::{Ref, RefCell, RefMut};
 use std::fmt;
 use std::mem;
 use std::net::SocketAddr;
-use std::sync::{Once, ONCE_INIT};
-use std::rc::Rc;
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::sync::atomic::AtomicBool;
+use std::rc::{Rc, Weak};
+use std::time::Duration as StdDuration;
 
 use chrono::{DateTime, Duration, Utc};
 use failure::ResultExt;
 use futures::{future, Future, Sink, Stream};
-use futures::task::{self, Task};
+use futures::unsync::mpsc;
+use num::ToPrimitive;
 use futures::future::Either;
+use futures_cpupool::CpuPool;
 use slog::{Drain, Logger};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use tsproto::algorithms as algs;
 use tsproto::{client, crypto, packets, commands};
 use tsproto::connectionmanager::ConnectionManager as TsprotoCM;
@@ -92,44 +106,166 @@ macro_rules! tryf {
 }
 
 pub mod codec;
+pub mod filetransfer;
+mod ready_queue;
+pub mod resolver;
 mod structs;
+pub mod sync;
+mod utils;
+
+#[cfg(test)]
+mod test_utils;
+#[cfg(all(test, feature = "unstable"))]
+mod benches;
+
+/// Copies of the bookkeeping, which are returned in a [`Snapshot`].
+///
+/// [`Snapshot`]: ../sync/struct.Snapshot.html
+pub mod data {
+    pub use structs::{Channel, ChatEntry, Client, Connection, Server,
+        ServerGroup};
+}
 
 // Reexports
 pub use tsproto_commands::ConnectionId;
 pub use tsproto_commands::Reason;
 pub use tsproto_commands::versions::Version;
+pub use tsproto::identity::Identity;
+pub use resolver::{Resolver, ServerAddress};
+pub use structs::{PropertyId, PropertyValue};
 use tsproto_commands::messages;
 
 
-use codec::Message;
+use codec::{AudioPacket, Message};
+use ready_queue::ReadyQueue;
 
 type Result<T> = std::result::Result<T, Error>;
 type BoxFuture<T> = Box<Future<Item = T, Error = Error>>;
 type Map<K, V> = std::collections::HashMap<K, V>;
 
+/// The number of threads which search for a better identity offset.
+const HASH_CASH_THREADS: usize = 4;
+
 include!(concat!(env!("OUT_DIR"), "/facades.rs"));
 include!(concat!(env!("OUT_DIR"), "/getters.rs"));
 
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "Connection failed ({})", _0)]
-    ConnectionFailed(String),
+    ConnectionFailed(#[cause] ConnectionFailure),
     #[fail(display = "{}", _0)]
     Base64(#[cause] base64::DecodeError),
     #[fail(display = "{}", _0)]
+    Io(#[cause] std::io::Error),
+    #[fail(display = "{}", _0)]
     Tsproto(#[cause] tsproto::Error),
     #[fail(display = "{}", _0)]
     ParseMessage(#[cause] tsproto_commands::messages::ParseError),
+    /// The server answered a command with an error.
+    #[fail(display = "The server returned an error ({})", _0)]
+    Command(#[cause] tsproto_commands::errors::Error),
     #[fail(display = "{}", _0)]
     Other(#[cause] failure::Compat<failure::Error>),
 }
 
+/// The reason why a connection to a server could not be established.
+#[derive(Fail, Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionFailure {
+    /// The server did not answer or the handshake failed.
+    #[fail(display = "The server is not reachable")]
+    ServerUnreachable,
+    /// The address of the server could not be resolved.
+    #[fail(display = "The server address could not be resolved")]
+    UnknownHost,
+    /// The connection was not established within the connect timeout.
+    #[fail(display = "Connecting timed out")]
+    Timeout,
+    /// We are banned from the server.
+    ///
+    /// The duration of the ban is contained if the server sent it.
+    #[fail(display = "Banned from the server ({})", message)]
+    Banned {
+        duration: Option<Duration>,
+        message: String,
+    },
+    #[fail(display = "Wrong server password")]
+    WrongServerPassword,
+    #[fail(display = "The server is full")]
+    ServerFull,
+    /// The server does not accept our client version.
+    #[fail(display = "The client version is outdated")]
+    VersionOutdated,
+    /// The security level of our identity is lower than the level that the
+    /// server requires.
+    ///
+    /// The required level is contained if the server sent it.
+    #[fail(display = "The security level of the identity is too low")]
+    SecurityLevelTooLow {
+        required: Option<u8>,
+    },
+    /// The server rejected us with another error.
+    #[fail(display = "{} ({})", _0, _1)]
+    Rejected(#[cause] tsproto_commands::errors::Error, String),
+}
+
+impl ConnectionFailure {
+    /// Decode the error which the server sent as answer to our `clientinit`.
+    fn from_command_error(error: &messages::CommandError) -> Self {
+        match error.id {
+            errors::Error::ConnectFailedBanned => ConnectionFailure::Banned {
+                duration: error.extra_msg.as_ref()
+                    .and_then(|m| parse_ban_duration(m)),
+                message: error.msg.clone(),
+            },
+            errors::Error::ServerInvalidPassword =>
+                ConnectionFailure::WrongServerPassword,
+            errors::Error::ServerMaxclientsReached =>
+                ConnectionFailure::ServerFull,
+            errors::Error::ClientVersionOutdated =>
+                ConnectionFailure::VersionOutdated,
+            errors::Error::ClientCouldNotValidateIdentity =>
+                ConnectionFailure::SecurityLevelTooLow {
+                    // The server sends the required level as extra message
+                    required: error.extra_msg.as_ref()
+                        .and_then(|m| m.trim().parse().ok()),
+                },
+            id => ConnectionFailure::Rejected(id, error.msg.clone()),
+        }
+    }
+}
+
+/// Find the remaining time of a ban in the extra message of the server.
+///
+/// The message looks like `you may retry in 60 seconds`.
+fn parse_ban_duration(msg: &str) -> Option<Duration> {
+    const PREFIX: &str = "you may retry in ";
+    let msg = msg.trim();
+    if !msg.starts_with(PREFIX) {
+        return None;
+    }
+    let msg = &msg[PREFIX.len()..];
+    let secs = if msg.ends_with(" seconds") {
+        &msg[..msg.len() - 8]
+    } else if msg.ends_with(" second") {
+        &msg[..msg.len() - 7]
+    } else {
+        return None;
+    };
+    secs.parse::<u32>().ok().map(|s| Duration::seconds(s.into()))
+}
+
 impl From<base64::DecodeError> for Error {
     fn from(e: base64::DecodeError) -> Self {
         Error::Base64(e)
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
 impl From<tsproto::Error> for Error {
     fn from(e: tsproto::Error) -> Self {
         Error::Tsproto(e)
@@ -169,14 +305,149 @@ pub struct TalkPowerRequest {
     pub message: String,
 }
 
+/// The receiver of a text message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum TextMessageTarget {
+    /// A private message to a client.
+    Client(ClientId),
+    /// A message to the channel we are currently in.
+    Channel,
+    /// A message to everyone on the server.
+    Server,
+}
+
+/// The state of a connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ConnectionState {
+    /// The connection is established.
+    Connected,
+    /// The connection was lost and we are trying to connect again.
+    ///
+    /// `attempt` starts at 1 for the first try.
+    Reconnecting { attempt: u32 },
+    /// The connection is closed and will be removed.
+    Disconnected,
+}
+
+/// The reason why a connection was closed.
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    /// We closed the connection with [`ConnectionManager::remove_connection`].
+    ///
+    /// [`ConnectionManager::remove_connection`]: struct.ConnectionManager.html#method.remove_connection
+    Requested,
+    /// We were kicked from the server.
+    Kicked {
+        invoker: Option<ClientId>,
+        invoker_name: Option<String>,
+        message: Option<String>,
+    },
+    /// We were banned from the server.
+    ///
+    /// A `duration` of `None` means that the ban is permanent.
+    Banned {
+        invoker: Option<ClientId>,
+        invoker_name: Option<String>,
+        duration: Option<Duration>,
+        message: Option<String>,
+    },
+    /// The server was shut down.
+    ServerShutdown { message: Option<String> },
+    /// The server did not answer anymore, e.g. because of a timeout.
+    ConnectionLost,
+    /// The server removed us from the server for another reason.
+    Other {
+        reason: Reason,
+        message: Option<String>,
+    },
+}
+
+/// Events which are emitted by the [`ConnectionManager`] for a connection.
+///
+/// A stream of events can be obtained with
+/// [`ConnectionManager::get_events`].
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+/// [`ConnectionManager::get_events`]: struct.ConnectionManager.html#method.get_events
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The state of the connection changed.
+    ConnectionStateChanged {
+        old: ConnectionState,
+        new: ConnectionState,
+    },
+    /// A client became visible, e.g. it connected to the server.
+    ClientEntered(ClientId),
+    /// A client is not visible anymore, e.g. it left the server.
+    ClientLeft(ClientId),
+    /// A client switched to another channel.
+    ClientMoved {
+        client: ClientId,
+        from: ChannelId,
+        to: ChannelId,
+    },
+    /// A channel was created.
+    ///
+    /// This is also raised for the channels which exist when we connect.
+    ChannelCreated(ChannelId),
+    /// Properties of a channel were changed.
+    ///
+    /// The changed properties are reported as [`PropertyChanged`] events.
+    ///
+    /// [`PropertyChanged`]: #variant.PropertyChanged
+    ChannelEdited(ChannelId),
+    /// A channel was deleted.
+    ChannelDeleted(ChannelId),
+    /// A property in the bookkeeping was changed by the server.
+    PropertyChanged {
+        id: PropertyId,
+        old: PropertyValue,
+        new: PropertyValue,
+    },
+    /// A text message was received.
+    TextMessage {
+        target: TextMessageTargetMode,
+        from: ClientId,
+        message: String,
+    },
+    /// The connection was closed and will not be reconnected.
+    ///
+    /// This is the last event of a connection, it is sent right before the
+    /// state changes to [`ConnectionState::Disconnected`].
+    ///
+    /// [`ConnectionState::Disconnected`]: enum.ConnectionState.html#variant.Disconnected
+    Disconnected(DisconnectReason),
+}
+
 /// The connection manager which can be shared and cloned.
 struct InnerCM {
     handle: Handle,
     logger: Logger,
     connections: Map<ConnectionId, structs::NetworkWrapper>,
+    /// Everyone who wants to receive events.
+    event_listeners: Vec<mpsc::UnboundedSender<(ConnectionId, Event)>>,
+    /// Requests from [`SyncConnectionManager`] handles.
+    ///
+    /// [`SyncConnectionManager`]: sync/struct.SyncConnectionManager.html
+    sync_requests: Vec<futures::sync::mpsc::UnboundedReceiver<sync::Request>>,
+    /// The connections which were woken up and have to be polled.
+    ///
+    /// The task of the current `Run` is registered in the queue, it is
+    /// notified when a connection is woken up or added.
+    ready: Arc<ReadyQueue>,
+    /// The thread pool for cpu intensive computations of connections, which
+    /// do not have their own pool.
+    cpu_pool: CpuPool,
 }
 
 impl InnerCM {
+    /// Send an event to all listeners and remove listeners which do not exist
+    /// anymore.
+    fn send_event(&mut self, id: ConnectionId, event: Event) {
+        self.event_listeners.retain(|l|
+            l.unbounded_send((id, event.clone())).is_ok());
+    }
+
     /// Returns the first free connection id.
     fn find_connection_id(&self) -> ConnectionId {
         for i in 0..self.connections.len() + 1 {
@@ -210,16 +481,6 @@ impl InnerCM {
 /// [`ConnectionManager::new`]: #method.new
 pub struct ConnectionManager {
     inner: Rc<RefCell<InnerCM>>,
-    /// The index of the connection which should be polled next.
-    ///
-    /// This is used to ensure that connections don't starve if one connection
-    /// always returns something. It is used in the `Stream` implementation of
-    /// `ConnectionManager`.
-    poll_index: usize,
-    /// The task of the current `Run`, which polls all connections.
-    ///
-    /// It will be notified when a new connection is added.
-    task: Option<Task>,
 }
 
 impl ConnectionManager {
@@ -267,159 +528,81 @@ impl ConnectionManager {
                 handle,
                 logger,
                 connections: Map::new(),
+                event_listeners: Vec::new(),
+                sync_requests: Vec::new(),
+                ready: ReadyQueue::new(),
+                cpu_pool: CpuPool::new_num_cpus(),
             })),
-            poll_index: 0,
-            task: None,
         }
     }
 
-    /// Connect to a server.
-    pub fn add_connection(&mut self, mut config: ConnectOptions) -> Connect {
-        let res: BoxFuture<_>;
-        {
-            let inner = self.inner.borrow();
-            let addr = config.address.expect(
-                "Invalid ConnectOptions, this should not happen");
-            let private_key = match config.private_key.take().map(Ok)
-                .unwrap_or_else(|| {
-                    // Create new ECDH key
-                    crypto::EccKeyPrivP256::create()
-                }) {
-                Ok(key) => key,
-                Err(error) => return Connect::new_from_error(error.into()),
-            };
-
-            let client = match client::ClientData::new(
-                config.local_address,
-                private_key,
-                inner.handle.clone(),
-                true,
-                tsproto::connectionmanager::SocketConnectionManager::new(),
-                None,
-            ) {
-                Ok(client) => client,
-                Err(error) => return Connect::new_from_error(error.into()),
-            };
+    /// Set the thread pool which is used for cpu intensive computations.
+    ///
+    /// The security level of identities is raised and the RSA puzzle of the
+    /// handshake is solved on this pool, so other connections are not
+    /// blocked. This is only used for connections which are added afterwards
+    /// and have no pool set in their [`ConnectOptions`].
+    ///
+    /// # Default
+    ///
+    /// A pool with one thread per cpu.
+    ///
+    /// [`ConnectOptions`]: struct.ConnectOptions.html
+    pub fn set_cpu_pool(&mut self, cpu_pool: CpuPool) {
+        self.inner.borrow_mut().cpu_pool = cpu_pool;
+    }
 
-            // Set the data reference
-            {
-                let c2 = client.clone();
-                let mut client = client.borrow_mut();
-                client.connection_manager.set_data_ref(Rc::downgrade(&c2));
-            }
-            client::default_setup(&client, config.log_packets);
-
-            // Create a connection
-            let connect_fut = client::connect(&client, addr);
-
-            let logger = inner.logger.clone();
-            let inner = Rc::downgrade(&self.inner);
-            let client2 = client.clone();
-
-            // Poll the connection for packets
-            let initserver_poll = client::ClientData::get_packets(
-                Rc::downgrade(&client))
-                .filter_map(|(_, p)| {
-                    // Filter commands
-                    if let Packet { data: packets::Data::Command(cmd), .. } = p {
-                        Some(cmd)
-                    } else {
-                        None
-                    }
-                })
-                .into_future().map_err(|(e, _)| e.into())
-                .and_then(move |(cmd, _)| -> BoxFuture<_> {
-                    let cmd = if let Some(cmd) = cmd {
-                        cmd
-                    } else {
-                        return Box::new(future::err(Error::ConnectionFailed(
-                            String::from("Connection ended"))));
-                    };
-
-                    let cmd = cmd.get_commands().remove(0);
-                    let notif = tryf!(messages::Message::parse(cmd));
-                    if let messages::Message::InitServer(p) = notif {
-                        // Create a connection id
-                        let inner = inner.upgrade().expect(
-                            "Connection manager does not exist anymore");
-                        let mut inner = inner.borrow_mut();
-                        let id = inner.find_connection_id();
-
-                        let con;
-                        {
-                            let mut client = client2.borrow_mut();
-                            con = client.connection_manager
-                                .get_connection(addr).unwrap();
-                        }
-
-                        // Create the connection
-                        let con = structs::NetworkWrapper::new(id, client2,
-                            Rc::downgrade(&con), &p);
-
-                        // Add the connection
-                        inner.connections.insert(id, con);
-
-                        Box::new(future::ok(id))
-                    } else {
-                        Box::new(future::err(Error::ConnectionFailed(
-                            String::from("Got no initserver"))))
-                    }
-                });
-
-            res = Box::new(connect_fut.and_then(move |()| {
-                // TODO Add possibility to specify offset and level in ConnectOptions
-                // Compute hash cash
-                let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-                    "Compute public key hash cash level", logger.clone(),
-                    slog::Level::Info);
-                time_reporter.start("Compute public key hash cash level");
-                let (offset, omega) = {
-                    let mut c = client.borrow_mut();
-                    let pub_k = c.private_key.to_pub();
-                    (algs::hash_cash(&pub_k, 8).unwrap(),
-                    pub_k.to_ts().unwrap())
-                };
-                time_reporter.finish();
-                info!(logger, "Computed hash cash level";
-                    "level" => algs::get_hash_cash_level(&omega, offset),
-                    "offset" => offset);
-
-                // Create clientinit packet
-                let header = Header::new(PacketType::Command);
-                let mut command = commands::Command::new("clientinit");
-                command.push("client_nickname", config.name);
-                command.push("client_version", config.version.get_version_string());
-                command.push("client_platform", config.version.get_platform());
-                command.push("client_input_hardware", "1");
-                command.push("client_output_hardware", "1");
-                command.push("client_default_channel", "");
-                command.push("client_default_channel_password", "");
-                command.push("client_server_password", "");
-                command.push("client_meta_data", "");
-                command.push("client_version_sign", base64::encode(
-                    config.version.get_signature()));
-                command.push("client_key_offset", offset.to_string());
-                command.push("client_nickname_phonetic", "");
-                command.push("client_default_token", "");
-                command.push("hwid", "123,456");
-                let p_data = packets::Data::Command(command);
-                let clientinit_packet = Packet::new(header, p_data);
-
-                let sink = Data::get_packets(Rc::downgrade(&client));
-
-                sink.send((addr, clientinit_packet))
-            })
-            .map_err(|e| e.into())
-            // Wait until we sent the clientinit packet and afterwards received
-            // the initserver packet.
-            .join(initserver_poll)
-            .map(|(_, id)| id));
+    /// Connect to a server.
+    ///
+    /// If the security level of the identity is lower than the level in the
+    /// `config`, it is improved first. If the server requires a higher level,
+    /// the level is raised and the connection is retried.
+    ///
+    /// The returned future does not borrow the `ConnectionManager`, so
+    /// multiple connections can be established at the same time. Existing
+    /// connections are only handled while the future returned by
+    /// [`ConnectionManager::run`] is polled.
+    ///
+    /// # Example
+    ///
+    /// Connect to multiple servers at the same time:
+    ///
+    /// ```rust,no_run
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// # extern crate tsclientlib;
+    /// #
+    /// # use futures::{future, Future};
+    /// # use tsclientlib::{ConnectionManager, ConnectOptions};
+    /// # fn main() {
+    /// #
+    /// let mut core = tokio_core::reactor::Core::new().unwrap();
+    /// let mut cm = ConnectionManager::new(core.handle());
+    ///
+    /// let connects = future::join_all(vec![
+    ///     cm.add_connection(ConnectOptions::from_address("localhost")),
+    ///     cm.add_connection(ConnectOptions::from_address("127.0.0.2")),
+    /// ]);
+    /// let con_ids = core.run(connects.select2(cm.run()))
+    ///     .map_err(|e| e.split().0).unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ConnectionManager::run`]: #method.run
+    pub fn add_connection(&self, config: ConnectOptions) -> Connect {
+        match self.connect_future(config) {
+            Ok(res) => Connect::new_from_future(res),
+            Err(error) => Connect::new_from_error(error),
         }
-        Connect::new_from_future(self.run().select2(res))
     }
 
     /// Disconnect from a server.
     ///
+    /// The returned future does not borrow the `ConnectionManager`. It has to
+    /// be polled together with the future returned by
+    /// [`ConnectionManager::run`], otherwise the answer of the server is not
+    /// handled.
+    ///
     /// # Arguments
     /// - `id`: The connection which should be removed.
     /// - `options`: Either `None` or `DisconnectOptions`.
@@ -429,10 +612,12 @@ impl ConnectionManager {
     /// Use default options:
     ///
     /// ```rust,no_run
+    /// # extern crate futures;
     /// # extern crate tokio_core;
     /// # extern crate tsclientlib;
     /// # use std::boxed::Box;
     /// #
+    /// # use futures::Future;
     /// # use tsclientlib::{ConnectionId, ConnectionManager};
     /// # fn main() {
     /// #
@@ -443,7 +628,8 @@ impl ConnectionManager {
     ///
     /// # let con_id = ConnectionId(0);
     /// let disconnect_future = cm.remove_connection(con_id, None);
-    /// core.run(disconnect_future).unwrap();
+    /// core.run(disconnect_future.select2(cm.run()))
+    ///     .map_err(|e| e.split().0).unwrap();
     /// # }
     /// ```
     ///
@@ -466,17 +652,189 @@ impl ConnectionManager {
     ///     .message("Away for a while"));
     /// # }
     /// ```
-    pub fn remove_connection<O: Into<Option<DisconnectOptions>>>(&mut self,
+    ///
+    /// [`ConnectionManager::run`]: #method.run
+    pub fn remove_connection<O: Into<Option<DisconnectOptions>>>(&self,
         id: ConnectionId, options: O) -> Disconnect {
+        match self.disconnect_future(id, options.into()) {
+            Some(fut) => Disconnect::new_from_future(fut),
+            None => Disconnect::new_from_ok(),
+        }
+    }
+
+    #[inline]
+    pub fn get_connection(&self, id: ConnectionId) -> Option<Connection> {
+        if self.inner.borrow().connections.contains_key(&id) {
+            Some(Connection { cm: self, id })
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn get_mut_connection(&mut self, id: ConnectionId) -> Option<ConnectionMut> {
+        if self.inner.borrow().connections.contains_key(&id) {
+            Some(ConnectionMut { cm: self, id })
+        } else {
+            None
+        }
+    }
+
+    /// Get a stream of events for all connections.
+    ///
+    /// Every stream which is created by this function receives all events
+    /// that happen after its creation.
+    pub fn get_events(&mut self) -> mpsc::UnboundedReceiver<(ConnectionId,
+        Event)> {
+        let (send, recv) = mpsc::unbounded();
+        self.inner.borrow_mut().event_listeners.push(send);
+        recv
+    }
+
+    /// Get a handle which can be used from other threads.
+    ///
+    /// The requests of the handle are handled while the future returned by
+    /// [`ConnectionManager::run`] is polled.
+    ///
+    /// [`ConnectionManager::run`]: #method.run
+    pub fn get_sync_handle(&mut self) -> sync::SyncConnectionManager {
+        let (send, recv) = futures::sync::mpsc::unbounded();
+        let mut inner = self.inner.borrow_mut();
+        inner.sync_requests.push(recv);
+        // Poll the new channel
+        inner.ready.notify_task();
+        sync::SyncConnectionManager::new(send)
+    }
+
+    /// Send a message to the server of a connection.
+    fn send_message(&self, id: ConnectionId, msg: Message) -> BoxFuture<()> {
+        let inner = self.inner.borrow();
+        let con = if let Some(con) = inner.connections.get(&id) {
+            con
+        } else {
+            return Box::new(future::err(
+                format_err!("Connection {:?} does not exist", id).into()));
+        };
+        let addr = if let Some(c) = con.client_connection.upgrade() {
+            c.borrow().address
+        } else {
+            return Box::new(future::err(
+                format_err!("Connection {:?} is not connected", id).into()));
+        };
+
+        Box::new(codec::CommandCodec::new_sink(&con.client_data)
+            .send((addr, msg))
+            .map(|_| ())
+            .map_err(|e| e.into()))
+    }
+
+    /// If `client` is our own client on the connection `id`.
+    ///
+    /// The generated setters change our own client with `clientupdate`.
+    fn is_own_client(&self, id: ConnectionId, client: ClientId) -> bool {
+        self.inner.borrow().connections.get(&id)
+            .map(|con| con.own_client == client).unwrap_or(false)
+    }
+
+    /// Send a raw command, which has no declared message, to the server.
+    ///
+    /// A return code is attached to the command and the returned future
+    /// resolves to the messages which the server sent as response to this
+    /// command. If the server answers with an error, the future fails with
+    /// [`Error::Command`].
+    ///
+    /// [`Error::Command`]: enum.Error.html#variant.Command
+    fn send_command(&self, id: ConnectionId, mut cmd: commands::Command)
+        -> BoxFuture<Vec<Box<messages::Message>>> {
+        let mut inner = self.inner.borrow_mut();
+        let con = if let Some(con) = inner.connections.get_mut(&id) {
+            con
+        } else {
+            return Box::new(future::err(
+                format_err!("Connection {:?} does not exist", id).into()));
+        };
+        let addr = if let Some(c) = con.client_connection.upgrade() {
+            c.borrow().address
+        } else {
+            return Box::new(future::err(
+                format_err!("Connection {:?} is not connected", id).into()));
+        };
+
+        let (return_code, receiver) = con.add_pending_command();
+        cmd.push("return_code", return_code);
+
+        // Commands which are not declared get the flag, like most commands
+        let mut header = Header::new(PacketType::Command);
+        header.set_newprotocol(messages::is_newprotocol_command(&cmd.command)
+            .unwrap_or(true));
+        let packet = Packet::new(header, packets::Data::Command(cmd));
+        Box::new(Data::get_packets(Rc::downgrade(&con.client_data))
+            .send((addr, packet))
+            .map_err(|e| e.into())
+            .and_then(move |_| receiver.then(|res| match res {
+                Ok(res) => res,
+                Err(_) => Err(format_err!("Connection {:?} was closed before \
+                    the command was answered", id).into()),
+            })))
+    }
+
+    #[inline]
+    /// Creates a future to handle all packets.
+    pub fn run(&mut self) -> Run {
+        Run { cm: self }
+    }
+}
+
+// Private methods
+impl ConnectionManager {
+    /// Create a future which connects to a server and adds the connection.
+    fn connect_future(&self, mut config: ConnectOptions)
+        -> Result<BoxFuture<ConnectionId>> {
+        // Create a new identity if none was given, it is reused when
+        // reconnecting.
+        if config.identity.is_none() {
+            config.identity = Some(if let Some(key) = config.private_key.take() {
+                Identity::new(key, 0)?
+            } else {
+                Identity::create()?
+            });
+        }
+
+        let inner = self.inner.borrow();
+        if config.cpu_pool.is_none() {
+            config.cpu_pool = Some(inner.cpu_pool.clone());
+        }
+        let level = config.security_level;
+        let handle = inner.handle.clone();
+        let logger = inner.logger.clone();
+        let connect_fut = improve_identity(logger.clone(), config, level)
+            .and_then(move |config|
+                connect_with_timeout(handle, logger, config));
+        let inner = Rc::downgrade(&self.inner);
+
+        Ok(Box::new(connect_fut.and_then(move |(client, con, initserver,
+            config)| insert_connection(&inner, client, con, &initserver,
+            config))))
+    }
+
+    /// Create a future which sends the disconnect command and waits until
+    /// the connection is closed.
+    ///
+    /// Returns `None` if the connection does not exist or is already
+    /// disconnected.
+    fn disconnect_future(&self, id: ConnectionId,
+        options: Option<DisconnectOptions>) -> Option<BoxFuture<()>> {
         let client_con;
         let client_data;
         {
-            let inner_b = self.inner.borrow();
-            if let Some(con) = inner_b.connections.get(&id) {
+            let mut inner_b = self.inner.borrow_mut();
+            if let Some(con) = inner_b.connections.get_mut(&id) {
+                // Do not reconnect after the connection is closed
+                con.disconnecting = true;
                 client_con = con.client_connection.clone();
                 client_data = con.client_data.clone();
             } else {
-                return Disconnect::new_from_ok();
+                return None;
             }
         }
 
@@ -484,13 +842,13 @@ impl ConnectionManager {
             c
         } else {
             // Already disconnected
-            return Disconnect::new_from_ok();
+            return None;
         };
 
         let header = Header::new(PacketType::Command);
         let mut command = commands::Command::new("clientdisconnect");
 
-        let options = options.into().unwrap_or_default();
+        let options = options.unwrap_or_default();
         if let Some(reason) = options.reason {
             command.push("reasonid", (reason as u8).to_string());
         }
@@ -519,90 +877,82 @@ impl ConnectionManager {
         let fut: BoxFuture<_> = Box::new(sink.send((addr, packet))
             .and_then(move |_| wait_for_state)
             .map_err(|e| e.into()));
-        Disconnect::new_from_future(self.run().select(fut))
-    }
-
-    #[inline]
-    pub fn get_connection(&self, id: ConnectionId) -> Option<Connection> {
-        if self.inner.borrow().connections.contains_key(&id) {
-            Some(Connection { cm: self, id })
-        } else {
-            None
-        }
-    }
-
-    #[inline]
-    pub fn get_mut_connection(&mut self, id: ConnectionId) -> Option<ConnectionMut> {
-        if self.inner.borrow().connections.contains_key(&id) {
-            Some(ConnectionMut { cm: self, id })
-        } else {
-            None
-        }
-    }
-
-    #[inline]
-    /// Creates a future to handle all packets.
-    pub fn run(&mut self) -> Run {
-        Run { cm: self }
+        Some(fut)
     }
-}
 
-// Private methods
-impl ConnectionManager {
-    fn get_file(&self, _con: ConnectionId, _chan: ChannelId, _path: &str, _file: &str) -> Ref<structs::File> {
-        unimplemented!("File transfer is not yet implemented")
+    /// Get a file which was returned by the last listing of its directory.
+    ///
+    /// Returns `None` if the connection does not exist or if the file was not
+    /// listed.
+    fn get_file(&self, con: ConnectionId, chan: ChannelId, path: &str,
+        file: &str) -> Option<Ref<structs::File>> {
+        let r = self.inner.borrow();
+        let i = r.connections.get(&con)?.files.iter().position(|f|
+            f.channel_id == chan && f.path == path && f.name == file)?;
+        Some(Ref::map(r, |r| &r.connections[&con].files[i]))
     }
 
-    fn get_chat_entry(&self, _con: ConnectionId, _sender: ClientId) -> Ref<structs::ChatEntry> {
-        unimplemented!("Chatting is not yet implemented")
+    /// Get the last message which was received from a client.
+    ///
+    /// Returns `None` if the connection does not exist or if no message was
+    /// received from this client.
+    fn get_chat_entry(&self, con: ConnectionId, sender: ClientId)
+        -> Option<Ref<structs::ChatEntry>> {
+        let r = self.inner.borrow();
+        let i = r.connections.get(&con)?.chat_history.iter()
+            .rposition(|e| e.sender == sender)?;
+        Some(Ref::map(r, |r| &r.connections[&con].chat_history[i]))
     }
 
     /// Poll like a stream to get the next message.
+    ///
+    /// Only connections which were woken up are polled. A connection which
+    /// returned a message is added to the end of the queue again, so
+    /// connections don't starve if one connection always returns something.
     fn poll_stream(&mut self) -> futures::Poll<Option<(ConnectionId, Message)>,
         Error> {
-        if !self.task.as_ref().map(|t| t.will_notify_current()).unwrap_or(false) {
-            self.task = Some(task::current());
-        }
+        self.inner.borrow().ready.register();
+        self.poll_sync_requests();
 
-        // Poll all connections
         let inner = &mut *self.inner.borrow_mut();
-        let keys: Vec<_> = inner.connections.keys().cloned().collect();
-        if keys.is_empty() {
-            // Wait until our task gets notified
-            return Ok(futures::Async::NotReady);
-        }
-
-        if self.poll_index >= keys.len() {
-            self.poll_index = 0;
-        }
-
-        let mut remove_connection = false;
+        let mut events = Vec::new();
         let mut result = Ok(futures::Async::NotReady);
-        for con_id in 0..keys.len() {
-            let i = (self.poll_index + con_id) % keys.len();
-            let con = inner.connections.get_mut(&keys[i]).unwrap();
-            match con.poll() {
-                Ok(futures::Async::Ready(None)) =>
-                    warn!(inner.logger, "Got None from a connection";
-                        "connection" => %keys[i].0),
+        while let Some(i) = inner.ready.pop() {
+            let id = ConnectionId(i);
+            let res = {
+                let con = if let Some(con) = inner.connections.get_mut(&id) {
+                    con
+                } else {
+                    // The connection was already removed
+                    continue;
+                };
+                let res = ReadyQueue::poll_with(&inner.ready, i, || con.poll());
+                events.extend(con.events.drain(..).map(|e| (id, e)));
+                res
+            };
+            match res {
+                Ok(futures::Async::Ready(None)) => {
+                    // The connection is closed and will not be reconnected
+                    info!(inner.logger, "Removing connection";
+                        "connection" => %id.0);
+                    inner.connections.remove(&id);
+                }
                 Ok(futures::Async::Ready(Some((_, res)))) => {
-                    // Check if the connection is still alive
-                    if con.client_connection.upgrade().is_none() {
-                        remove_connection = true;
-                    }
-                    self.poll_index = i + 1;
-                    result = Ok(futures::Async::Ready(Some((keys[i], res))));
+                    // The connection may have more messages
+                    inner.ready.push(i);
+                    result = Ok(futures::Async::Ready(Some((id, res))));
                     break;
                 }
                 Ok(futures::Async::NotReady) => {}
-                Err(error) =>
+                Err(error) => {
+                    // The connection is polled again when it is woken up
                     warn!(inner.logger, "Got an error from a connection";
-                        "error" => ?error, "connection" => %keys[i].0),
+                        "error" => ?error, "connection" => %id.0);
+                }
             }
         }
-        if remove_connection {
-            // Remove the connection
-            inner.connections.remove(&keys[self.poll_index - 1]);
+        for (id, event) in events {
+            inner.send_event(id, event);
         }
         result
     }
@@ -621,6 +971,278 @@ impl ConnectionManager {
     }
 }
 
+/// Raise the security level of the identity in the `config`.
+///
+/// The level is computed on the thread pool of the `config`.
+fn improve_identity(logger: Logger, mut config: ConnectOptions, level: u8)
+    -> BoxFuture<ConnectOptions> {
+    let mut identity = config.identity.take().expect(
+        "Connecting without identity, this should not happen");
+    if identity.get_level() >= level {
+        config.identity = Some(identity);
+        return Box::new(future::ok(config));
+    }
+
+    let cpu_pool = config.cpu_pool.clone().expect(
+        "Connecting without thread pool, this should not happen");
+    Box::new(cpu_pool.spawn_fn(move || -> Result<_> {
+        let mut time_reporter = slog_perf::TimeReporter::new_with_level(
+            "Compute public key hash cash level", logger.clone(),
+            slog::Level::Info);
+        time_reporter.start("Compute public key hash cash level");
+        let cancel = AtomicBool::new(false);
+        identity.improve(level, HASH_CASH_THREADS, &cancel, |_, _| {})?;
+        time_reporter.finish();
+        info!(logger, "Computed hash cash level";
+            "level" => identity.get_level(),
+            "offset" => identity.get_offset());
+        Ok(identity)
+    }).map(move |identity| {
+        config.identity = Some(identity);
+        config
+    }))
+}
+
+/// Add an established connection to the connection manager.
+///
+/// If the manager does not exist anymore, the connection is closed and an
+/// error is returned.
+fn insert_connection(inner: &Weak<RefCell<InnerCM>>,
+    client: Rc<RefCell<client::ClientData>>,
+    con: Weak<RefCell<client::ClientConnection>>,
+    initserver: &messages::InitServer, config: ConnectOptions)
+    -> Result<ConnectionId> {
+    let inner = if let Some(inner) = inner.upgrade() {
+        inner
+    } else {
+        // Nobody can use the connection
+        if let Some(con) = con.upgrade() {
+            let addr = con.borrow().address;
+            Data::remove_connection(&client, addr);
+        }
+        return Err(format_err!("The connection manager does not exist \
+            anymore").into());
+    };
+    let mut inner = inner.borrow_mut();
+    // Create a connection id
+    let id = inner.find_connection_id();
+
+    // Create the connection
+    let con = structs::NetworkWrapper::new(id, client, con, initserver,
+        config);
+
+    // Add the connection
+    inner.connections.insert(id, con);
+    // Poll the new connection
+    inner.ready.push(id.0);
+    Ok(id)
+}
+
+/// Connect to a server like `connect_with_identity` and fail with
+/// `ConnectionFailure::Timeout` if it takes longer than the connect timeout of
+/// the `config`.
+fn connect_with_timeout(handle: Handle, logger: Logger, config: ConnectOptions)
+    -> BoxFuture<(Rc<RefCell<client::ClientData>>,
+        Weak<RefCell<client::ClientConnection>>, messages::InitServer,
+        ConnectOptions)> {
+    let timeout = tryf!(Timeout::new(config.connect_timeout, &handle));
+    let fut = connect_with_identity(handle, logger, config);
+    Box::new(fut.select2(timeout).then(|res| match res {
+        Ok(Either::A((res, _))) => Ok(res),
+        Ok(Either::B(((), _))) =>
+            Err(Error::ConnectionFailed(ConnectionFailure::Timeout)),
+        Err(Either::A((error, _))) => Err(error),
+        Err(Either::B((error, _))) => Err(error.into()),
+    }))
+}
+
+/// Connect to a server and raise the security level of the identity if the
+/// server rejects it.
+///
+/// Returns the connection and the options with the improved identity.
+fn connect_with_identity(handle: Handle, logger: Logger,
+    mut config: ConnectOptions)
+    -> BoxFuture<(Rc<RefCell<client::ClientData>>,
+        Weak<RefCell<client::ClientConnection>>, messages::InitServer,
+        ConnectOptions)> {
+    Box::new(connect(&handle, &logger, &config).then(move |res|
+        -> BoxFuture<_> {
+        match res {
+            Ok((client, con, initserver)) =>
+                Box::new(future::ok((client, con, initserver, config))),
+            Err(Error::ConnectionFailed(
+                ConnectionFailure::SecurityLevelTooLow { required })) => {
+                let current = config.identity.as_ref().unwrap().get_level();
+                // Raise the level by one if the server did not send the
+                // required level.
+                let level = required.unwrap_or(current + 1);
+                if level <= current || level > config.max_security_level {
+                    return Box::new(future::err(Error::ConnectionFailed(
+                        ConnectionFailure::SecurityLevelTooLow { required })));
+                }
+                info!(logger, "The server requires a higher security level";
+                    "level" => level);
+                Box::new(improve_identity(logger.clone(), config, level)
+                    .and_then(move |config|
+                        connect_with_identity(handle, logger, config)))
+            }
+            Err(error) => Box::new(future::err(error)),
+        }
+    }))
+}
+
+/// Resolve the server address, connect to the server and wait until the
+/// `initserver` packet is received.
+///
+/// The identity in the `config` has to be set.
+fn connect(handle: &Handle, logger: &Logger, config: &ConnectOptions)
+    -> BoxFuture<(Rc<RefCell<client::ClientData>>,
+        Weak<RefCell<client::ClientConnection>>, messages::InitServer)> {
+    let address = config.address.as_ref().expect(
+        "Invalid ConnectOptions, this should not happen");
+    let resolver: Rc<Resolver> = match config.resolver {
+        Some(ref r) => r.clone(),
+        None => Rc::new(tryf!(resolver::DnsResolver::new(handle))),
+    };
+
+    let handle2 = handle.clone();
+    let logger2 = logger.clone();
+    let config2 = config.clone();
+    Box::new(resolver::resolve(handle, logger, resolver, address)
+        .and_then(move |addr| {
+            debug!(logger2, "Resolved server address"; "address" => %addr);
+            connect_to(&handle2, &logger2, &config2, addr)
+        }))
+}
+
+/// Connect to a resolved server address.
+fn connect_to(handle: &Handle, logger: &Logger, config: &ConnectOptions,
+    addr: SocketAddr) -> BoxFuture<(Rc<RefCell<client::ClientData>>,
+        Weak<RefCell<client::ClientConnection>>, messages::InitServer)> {
+    let identity = config.identity.clone().expect(
+        "Connecting without identity, this should not happen");
+
+    let client = tryf!(client::ClientData::new(
+        config.local_address,
+        identity.get_key().clone(),
+        handle.clone(),
+        true,
+        tsproto::connectionmanager::SocketConnectionManager::new(),
+        None,
+    ));
+
+    // Set the data reference
+    {
+        let c2 = client.clone();
+        let mut client = client.borrow_mut();
+        client.connection_manager.set_data_ref(Rc::downgrade(&c2));
+        client.cpu_pool = config.cpu_pool.clone();
+    }
+    client::default_setup(&client, config.log_packets);
+
+    // Create a connection
+    let logger = logger.clone();
+    let logger2 = logger.clone();
+    let connect_fut = client::connect(&client, addr).map_err(move |error| {
+        debug!(logger2, "Handshake failed"; "error" => ?error);
+        Error::ConnectionFailed(ConnectionFailure::ServerUnreachable)
+    });
+
+    let client2 = client.clone();
+    let options = config.clone();
+
+    // Poll the connection for packets
+    let initserver_poll = client::ClientData::get_packets(
+        Rc::downgrade(&client))
+        .filter_map(|(_, p)| {
+            // Filter commands
+            if let Packet { data: packets::Data::Command(cmd), .. } = p {
+                Some(cmd)
+            } else {
+                None
+            }
+        })
+        .into_future().map_err(|(e, _)| e.into())
+        .and_then(move |(cmd, _)| -> BoxFuture<_> {
+            let cmd = if let Some(cmd) = cmd {
+                cmd
+            } else {
+                return Box::new(future::err(Error::ConnectionFailed(
+                    ConnectionFailure::ServerUnreachable)));
+            };
+
+            let cmd = cmd.get_commands().remove(0);
+            let notif = tryf!(messages::Message::parse(cmd));
+            if let messages::Message::CommandError(ref e) = notif {
+                // The server rejected our clientinit
+                return Box::new(future::err(Error::ConnectionFailed(
+                    ConnectionFailure::from_command_error(e))));
+            }
+            if let messages::Message::InitServer(p) = notif {
+                let con;
+                {
+                    let client = client2.borrow();
+                    con = client.connection_manager
+                        .get_connection(addr).unwrap();
+                }
+
+                Box::new(future::ok((client2, Rc::downgrade(&con), p)))
+            } else {
+                Box::new(future::err(format_err!("Got no initserver")
+                    .into()))
+            }
+        });
+
+    let fut = connect_fut.and_then(move |()| {
+        debug!(logger, "Sending clientinit";
+            "level" => identity.get_level(),
+            "offset" => identity.get_offset());
+
+        // Create clientinit packet
+        let header = Header::new(PacketType::Command);
+        let command = create_clientinit(&options, &identity);
+        let p_data = packets::Data::Command(command);
+        let clientinit_packet = Packet::new(header, p_data);
+
+        let sink = Data::get_packets(Rc::downgrade(&client));
+
+        sink.send((addr, clientinit_packet)).map_err(|e| e.into())
+    })
+    // Wait until we sent the clientinit packet and afterwards received
+    // the initserver packet.
+    .join(initserver_poll)
+    .map(|(_, res)| res);
+    Box::new(fut)
+}
+
+/// Create the `clientinit` command, which is sent after the handshake.
+fn create_clientinit(options: &ConnectOptions, identity: &Identity)
+    -> commands::Command {
+    let version = options.version;
+    let mut command = commands::Command::new("clientinit");
+    command.push("client_nickname", options.name.as_str());
+    command.push("client_version", version.get_version_string());
+    command.push("client_platform", version.get_platform());
+    command.push("client_input_hardware",
+        if options.input_hardware { "1" } else { "0" });
+    command.push("client_output_hardware",
+        if options.output_hardware { "1" } else { "0" });
+    command.push("client_default_channel", options.default_channel.as_str());
+    command.push("client_default_channel_password",
+        algs::hash_password(&options.default_channel_password));
+    command.push("client_server_password",
+        algs::hash_password(&options.server_password));
+    command.push("client_meta_data", options.metadata.as_str());
+    command.push("client_version_sign", base64::encode(
+        version.get_signature()));
+    command.push("client_key_offset", identity.get_offset().to_string());
+    command.push("client_nickname_phonetic", options.phonetic_name.as_str());
+    command.push("client_default_token", options.privilege_key.as_str());
+    command.push("hwid", options.hardware_id.clone().unwrap_or_else(||
+        identity.get_hardware_id()));
+    command
+}
+
 impl fmt::Debug for ConnectionManager {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "ConnectionManager(...)")
@@ -643,25 +1265,29 @@ impl<'a> Future for Run<'a> {
     }
 }
 
-pub struct Connect<'a> {
+/// A future which connects to a server and resolves to the id of the new
+/// connection.
+///
+/// It is returned by [`ConnectionManager::add_connection`].
+///
+/// [`ConnectionManager::add_connection`]: struct.ConnectionManager.html#method.add_connection
+pub struct Connect {
     /// Contains an error if the `add_connection` functions should return an
     /// error.
-    inner: Either<Option<Error>,
-        futures::future::Select2<Run<'a>, BoxFuture<ConnectionId>>>,
+    inner: Either<Option<Error>, BoxFuture<ConnectionId>>,
 }
 
-impl<'a> Connect<'a> {
+impl Connect {
     fn new_from_error(error: Error) -> Self {
         Self { inner: Either::A(Some(error)) }
     }
 
-    fn new_from_future(future: futures::future::Select2<Run<'a>,
-        BoxFuture<ConnectionId>>) -> Self {
+    fn new_from_future(future: BoxFuture<ConnectionId>) -> Self {
         Self { inner: Either::B(future) }
     }
 }
 
-impl<'a> Future for Connect<'a> {
+impl Future for Connect {
     type Item = ConnectionId;
     type Error = Error;
 
@@ -669,35 +1295,31 @@ impl<'a> Future for Connect<'a> {
         match self.inner {
             // Take the error, this will panic if called twice
             Either::A(ref mut error) => Err(error.take().unwrap()),
-            Either::B(ref mut inner) => match inner.poll() {
-                Ok(futures::Async::Ready(Either::A(((), _)))) =>
-                    Err(format_err!("Could not connect").into()),
-                Ok(futures::Async::Ready(Either::B((id, _)))) =>
-                    Ok(futures::Async::Ready(id)),
-                Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
-                Err(Either::A((error, _))) |
-                Err(Either::B((error, _))) => Err(error),
-            }
+            Either::B(ref mut inner) => inner.poll(),
         }
     }
 }
 
-pub struct Disconnect<'a> {
-    inner: Option<futures::future::Select<Run<'a>, BoxFuture<()>>>,
+/// A future which closes a connection.
+///
+/// It is returned by [`ConnectionManager::remove_connection`].
+///
+/// [`ConnectionManager::remove_connection`]: struct.ConnectionManager.html#method.remove_connection
+pub struct Disconnect {
+    inner: Option<BoxFuture<()>>,
 }
 
-impl<'a> Disconnect<'a> {
+impl Disconnect {
     fn new_from_ok() -> Self {
         Self { inner: None }
     }
 
-    fn new_from_future(future: futures::future::Select<Run<'a>, BoxFuture<()>>)
-        -> Self {
+    fn new_from_future(future: BoxFuture<()>) -> Self {
         Self { inner: Some(future) }
     }
 }
 
-impl<'a> Future for Disconnect<'a> {
+impl Future for Disconnect {
     type Item = ();
     type Error = Error;
 
@@ -705,15 +1327,32 @@ impl<'a> Future for Disconnect<'a> {
     fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
         match self.inner {
             None => Ok(futures::Async::Ready(())),
-            Some(ref mut f) => f.poll().map(|r| match r {
-                futures::Async::Ready(_) => futures::Async::Ready(()),
-                futures::Async::NotReady => futures::Async::NotReady,
-            }).map_err(|(e, _)| e),
+            Some(ref mut f) => f.poll(),
         }
     }
 }
 
 impl<'a> Connection<'a> {
+    /// The current state of this connection.
+    ///
+    /// Returns `Disconnected` if the connection was removed.
+    #[inline]
+    pub fn get_state(&self) -> ConnectionState {
+        self.cm.inner.borrow().connections.get(&self.id).map(|c| c.state)
+            .unwrap_or(ConnectionState::Disconnected)
+    }
+
+    /// The reason why this connection was closed.
+    ///
+    /// Returns `None` as long as the connection is established or if the
+    /// connection was removed. If the connection is reconnecting, this is the
+    /// reason why it was lost.
+    #[inline]
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.cm.inner.borrow().connections.get(&self.id)
+            .and_then(|c| c.disconnect_reason.clone())
+    }
+
     #[inline]
     pub fn get_server(&self) -> Server {
         Server {
@@ -724,9 +1363,29 @@ impl<'a> Connection<'a> {
 }
 
 impl<'a> ConnectionMut<'a> {
+    /// The current state of this connection.
+    ///
+    /// Returns `Disconnected` if the connection was removed.
     #[inline]
-    pub fn get_server(&self) -> Server {
-        Server {
+    pub fn get_state(&self) -> ConnectionState {
+        self.cm.inner.borrow().connections.get(&self.id).map(|c| c.state)
+            .unwrap_or(ConnectionState::Disconnected)
+    }
+
+    /// The reason why this connection was closed.
+    ///
+    /// Returns `None` as long as the connection is established or if the
+    /// connection was removed. If the connection is reconnecting, this is the
+    /// reason why it was lost.
+    #[inline]
+    pub fn get_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.cm.inner.borrow().connections.get(&self.id)
+            .and_then(|c| c.disconnect_reason.clone())
+    }
+
+    #[inline]
+    pub fn get_server(&self) -> Server {
+        Server {
             cm: self.cm,
             connection_id: self.id,
         }
@@ -739,6 +1398,260 @@ impl<'a> ConnectionMut<'a> {
             connection_id: self.id,
         }
     }
+
+    /// Send a voice packet to the server.
+    ///
+    /// The returned future has to be polled together with the future returned
+    /// by [`ConnectionManager::run`].
+    ///
+    /// [`ConnectionManager::run`]: struct.ConnectionManager.html#method.run
+    pub fn send_audio(&mut self, packet: AudioPacket) -> BoxFuture<()> {
+        self.cm.send_message(self.id, Message::Audio(packet))
+    }
+
+    /// Send a text message to a client, our channel or the server.
+    ///
+    /// The returned future resolves when the server accepted the message. It
+    /// has to be polled together with the future returned by
+    /// [`ConnectionManager::run`].
+    ///
+    /// [`ConnectionManager::run`]: struct.ConnectionManager.html#method.run
+    pub fn send_text_message(&mut self, target: TextMessageTarget,
+        message: &str) -> BoxFuture<()> {
+        let (mode, target) = match target {
+            TextMessageTarget::Client(client) =>
+                (TextMessageTargetMode::Client, client.0 as u64),
+            TextMessageTarget::Channel => {
+                let inner = self.cm.inner.borrow();
+                let channel = inner.connections.get(&self.id)
+                    .and_then(|con| con.server.clients.get(&con.own_client))
+                    .map(|c| c.channel);
+                if let Some(channel) = channel {
+                    (TextMessageTargetMode::Channel, channel.0)
+                } else {
+                    return Box::new(future::err(format_err!("The channel of \
+                        our client on connection {:?} is unknown", self.id)
+                        .into()));
+                }
+            }
+            TextMessageTarget::Server => (TextMessageTargetMode::Server, 0),
+        };
+        let mut command = commands::Command::new("sendtextmessage");
+        command.push("targetmode", mode.to_u32().unwrap().to_string());
+        command.push("target", target.to_string());
+        command.push("msg", message);
+        Box::new(self.cm.send_command(self.id, command).map(|_| ()))
+    }
+
+    /// The text messages which were received on this connection, the oldest
+    /// message comes first.
+    ///
+    /// Returns an empty list if the connection was removed.
+    pub fn get_chat_history(&self) -> Ref<[structs::ChatEntry]> {
+        Ref::map(self.cm.inner.borrow(), |r| r.connections.get(&self.id)
+            .map(|c| c.chat_history.as_slice()).unwrap_or(&[]))
+    }
+
+    /// List the files in a directory of a channel.
+    ///
+    /// The listed files are also stored, so they can be accessed later as
+    /// [`File`]s.
+    ///
+    /// # Arguments
+    /// - `channel`: The channel which contains the files.
+    /// - `password`: The password of the channel or an empty string.
+    /// - `path`: The directory, e.g. `/` for the root directory.
+    ///
+    /// [`File`]: data/struct.File.html
+    pub fn get_file_list(&mut self, channel: ChannelId, password: &str,
+        path: &str) -> BoxFuture<Vec<filetransfer::FileInfo>> {
+        let mut command = commands::Command::new("ftgetfilelist");
+        command.push("cid", channel.0.to_string());
+        command.push("cpw", password);
+        command.push("path", path);
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        let path = path.to_string();
+        Box::new(self.cm.send_command(self.id, command).then(move |res|
+            -> Result<Vec<filetransfer::FileInfo>> {
+            let files = match res {
+                Ok(r) => parse_file_list(&r),
+                // The server returns an error for empty directories
+                Err(Error::Command(errors::Error::DatabaseEmptyResultSet)) =>
+                    Vec::new(),
+                Err(error) => return Err(error),
+            };
+            if let Some(inner) = inner.upgrade() {
+                if let Some(con) = inner.borrow_mut().connections.get_mut(&id) {
+                    con.set_file_list(channel, &path, &files);
+                }
+            }
+            Ok(files)
+        }))
+    }
+
+    /// Upload a file into a channel.
+    ///
+    /// The returned future resolves to an [`Upload`] when the server accepted
+    /// the transfer. The upload stream has to be polled to send the file and
+    /// reports the progress.
+    ///
+    /// # Arguments
+    /// - `channel`: The channel which should contain the file.
+    /// - `password`: The password of the channel or an empty string.
+    /// - `name`: The path and name of the file, e.g. `/log.txt`.
+    /// - `data`: The content of the file.
+    /// - `resume`: Continue a previously aborted upload of this file.
+    ///
+    /// [`Upload`]: filetransfer/struct.Upload.html
+    pub fn upload_file(&mut self, channel: ChannelId, password: &str,
+        name: &str, data: Vec<u8>, resume: bool)
+        -> BoxFuture<filetransfer::Upload> {
+        let (handle, addr, transfer_id) = tryf!(self.start_file_transfer());
+        let mut command = commands::Command::new("ftinitupload");
+        command.push("clientftfid", transfer_id.to_string());
+        command.push("name", name);
+        command.push("cid", channel.0.to_string());
+        command.push("cpw", password);
+        command.push("size", data.len().to_string());
+        command.push("overwrite", if resume { "0" } else { "1" });
+        command.push("resume", if resume { "1" } else { "0" });
+        Box::new(self.cm.send_command(self.id, command)
+            .and_then(move |responses| {
+                let start = responses.iter().filter_map(|msg|
+                    if let messages::Message::FileUpload(ref f) = **msg {
+                        Some(f)
+                    } else {
+                        None
+                    }).next();
+                if let Some(f) = start {
+                    Ok(filetransfer::Upload::new(&handle,
+                        SocketAddr::new(addr.ip(), f.port),
+                        f.server_file_transfer_id, &f.file_transfer_key, data,
+                        f.seek_position))
+                } else {
+                    Err(format_err!("The server did not start the upload")
+                        .into())
+                }
+            }))
+    }
+
+    /// Download a file from a channel.
+    ///
+    /// The returned future resolves to a [`Download`] when the server accepted
+    /// the transfer. The download stream has to be polled to receive the
+    /// content of the file.
+    ///
+    /// # Arguments
+    /// - `channel`: The channel which contains the file.
+    /// - `password`: The password of the channel or an empty string.
+    /// - `name`: The path and name of the file, e.g. `/icon_123`.
+    /// - `seek_position`: The number of bytes which were already downloaded,
+    ///   this is used to resume aborted downloads.
+    ///
+    /// [`Download`]: filetransfer/struct.Download.html
+    pub fn download_file(&mut self, channel: ChannelId, password: &str,
+        name: &str, seek_position: u64) -> BoxFuture<filetransfer::Download> {
+        let (handle, addr, transfer_id) = tryf!(self.start_file_transfer());
+        let mut command = commands::Command::new("ftinitdownload");
+        command.push("clientftfid", transfer_id.to_string());
+        command.push("name", name);
+        command.push("cid", channel.0.to_string());
+        command.push("cpw", password);
+        command.push("seekpos", seek_position.to_string());
+        Box::new(self.cm.send_command(self.id, command)
+            .and_then(move |responses| {
+                let start = responses.iter().filter_map(|msg|
+                    if let messages::Message::FileDownload(ref f) = **msg {
+                        Some(f)
+                    } else {
+                        None
+                    }).next();
+                if let Some(f) = start {
+                    Ok(filetransfer::Download::new(&handle,
+                        SocketAddr::new(addr.ip(), f.port),
+                        f.server_file_transfer_id, &f.file_transfer_key,
+                        f.size, seek_position))
+                } else {
+                    Err(format_err!("The server did not start the download")
+                        .into())
+                }
+            }))
+    }
+
+    /// Cancel a running file transfer.
+    ///
+    /// # Arguments
+    /// - `server_transfer_id`: The id of the transfer, as returned by
+    ///   [`Upload::get_server_transfer_id`] or
+    ///   [`Download::get_server_transfer_id`].
+    /// - `delete`: If the partially transferred file should be deleted.
+    ///
+    /// [`Upload::get_server_transfer_id`]: filetransfer/struct.Upload.html#method.get_server_transfer_id
+    /// [`Download::get_server_transfer_id`]: filetransfer/struct.Download.html#method.get_server_transfer_id
+    pub fn stop_file_transfer(&mut self, server_transfer_id: u16,
+        delete: bool) -> BoxFuture<()> {
+        let mut command = commands::Command::new("ftstop");
+        command.push("serverftfid", server_transfer_id.to_string());
+        command.push("delete", if delete { "1" } else { "0" });
+        Box::new(self.cm.send_command(self.id, command).map(|_| ()))
+    }
+
+    /// Get everything which is needed to start a file transfer.
+    ///
+    /// Returns the handle of the event loop, the address of the server and a
+    /// new id for the transfer.
+    fn start_file_transfer(&mut self) -> Result<(Handle, SocketAddr, u16)> {
+        let mut inner = self.cm.inner.borrow_mut();
+        let handle = inner.handle.clone();
+        let con = if let Some(con) = inner.connections.get_mut(&self.id) {
+            con
+        } else {
+            return Err(format_err!("Connection {:?} does not exist", self.id)
+                .into());
+        };
+        let addr = if let Some(c) = con.client_connection.upgrade() {
+            c.borrow().address
+        } else {
+            return Err(format_err!("Connection {:?} is not connected",
+                self.id).into());
+        };
+        let transfer_id = con.next_file_transfer_id;
+        con.next_file_transfer_id = con.next_file_transfer_id.wrapping_add(1);
+        Ok((handle, addr, transfer_id))
+    }
+
+    /// Send a command to the server and wait for its answer.
+    ///
+    /// The returned future resolves to the messages which the server sent as
+    /// response to this command, or to [`Error::Command`] if the server
+    /// answered with an error. It has to be polled together with the future
+    /// returned by [`ConnectionManager::run`].
+    ///
+    /// [`Error::Command`]: enum.Error.html#variant.Command
+    /// [`ConnectionManager::run`]: struct.ConnectionManager.html#method.run
+    pub fn send_command(&mut self, command: commands::Command)
+        -> BoxFuture<Vec<Box<messages::Message>>> {
+        self.cm.send_command(self.id, command)
+    }
+}
+
+/// Collect the files from the answer to `ftgetfilelist`.
+fn parse_file_list(responses: &[Box<messages::Message>])
+    -> Vec<filetransfer::FileInfo> {
+    responses.iter().filter_map(|msg|
+        if let messages::Message::FileList(ref f) = **msg {
+            Some(filetransfer::FileInfo {
+                channel: f.channel_id,
+                path: f.path.clone(),
+                name: f.name.clone(),
+                size: f.size,
+                modified: f.date_time,
+                is_file: f.is_file,
+            })
+        } else {
+            None
+        }).collect()
 }
 
 /// The configuration used to create a new connection.
@@ -764,14 +1677,32 @@ impl<'a> ConnectionMut<'a> {
 /// let con = core.run(cm.add_connection(con_config)).unwrap();
 /// # }
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConnectOptions {
-    address: Option<SocketAddr>,
+    address: Option<ServerAddress>,
+    resolver: Option<Rc<Resolver>>,
     local_address: SocketAddr,
+    identity: Option<Identity>,
+    /// Used to create the identity if no identity is set.
     private_key: Option<crypto::EccKeyPrivP256>,
+    security_level: u8,
+    max_security_level: u8,
     name: String,
     version: Version,
     log_packets: bool,
+    reconnect: Option<ReconnectOptions>,
+    chat_history: usize,
+    connect_timeout: StdDuration,
+    server_password: String,
+    default_channel: String,
+    default_channel_password: String,
+    privilege_key: String,
+    phonetic_name: String,
+    metadata: String,
+    input_hardware: bool,
+    output_hardware: bool,
+    hardware_id: Option<String>,
+    cpu_pool: Option<CpuPool>,
 }
 
 impl ConnectOptions {
@@ -783,25 +1714,60 @@ impl ConnectOptions {
     fn default() -> Self {
         Self {
             address: None,
+            resolver: None,
             local_address: "0.0.0.0:0".parse().unwrap(),
+            identity: None,
             private_key: None,
+            security_level: 8,
+            max_security_level: 20,
             name: String::from("TeamSpeakUser"),
             version: Version::Linux_3_1_8,
             log_packets: false,
+            reconnect: None,
+            chat_history: 100,
+            connect_timeout: StdDuration::from_secs(10),
+            server_password: String::new(),
+            default_channel: String::new(),
+            default_channel_password: String::new(),
+            privilege_key: String::new(),
+            phonetic_name: String::new(),
+            metadata: String::new(),
+            input_hardware: true,
+            output_hardware: true,
+            hardware_id: None,
+            cpu_pool: None,
         }
     }
 
     /// Start creating the configuration of a new connection.
     ///
-    /// The address of the server has to be supplied.
+    /// The address of the server has to be supplied. It can be a
+    /// `SocketAddr` or a string like `ts.example.com` or
+    /// `ts.example.com:9987`, which is resolved when connecting. See the
+    /// [`resolver`] module for the details of the resolution.
+    ///
+    /// [`resolver`]: resolver/index.html
     #[inline]
-    pub fn from_address(address: SocketAddr) -> Self {
+    pub fn from_address<A: Into<ServerAddress>>(address: A) -> Self {
         Self {
-            address: Some(address),
+            address: Some(address.into()),
             .. Self::default()
         }
     }
 
+    /// The resolver which looks up the server address.
+    ///
+    /// # Default
+    ///
+    /// A [`DnsResolver`], which uses the dns configuration of the system.
+    ///
+    /// [`DnsResolver`]: resolver/struct.DnsResolver.html
+    #[inline]
+    pub fn resolver(mut self, resolver: Rc<Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
     /// The address for the socket of our client
     ///
     /// # Default
@@ -813,7 +1779,20 @@ impl ConnectOptions {
         self
     }
 
-    /// Set the private key of the user.
+    /// Set the identity of the user.
+    ///
+    /// # Default
+    ///
+    /// A new identity is generated when connecting.
+    #[inline]
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self.private_key = None;
+        self
+    }
+
+    /// Set the private key of the user, the offset of the identity starts at
+    /// 0.
     ///
     /// # Default
     ///
@@ -821,6 +1800,7 @@ impl ConnectOptions {
     #[inline]
     pub fn private_key(mut self, private_key: crypto::EccKeyPrivP256)
         -> Self {
+        self.identity = None;
         self.private_key = Some(private_key);
         self
     }
@@ -837,9 +1817,35 @@ impl ConnectOptions {
     /// An error is returned if either the string is not encoded in valid base64
     /// or libtomcrypt cannot import the key.
     #[inline]
-    pub fn private_key_ts(mut self, private_key: &str) -> Result<Self> {
-        self.private_key = Some(crypto::EccKeyPrivP256::from_ts(private_key)?);
-        Ok(self)
+    pub fn private_key_ts(self, private_key: &str) -> Result<Self> {
+        Ok(self.private_key(crypto::EccKeyPrivP256::from_ts(private_key)?))
+    }
+
+    /// The security level which the identity should have before connecting.
+    ///
+    /// If the level of the identity is lower, it is improved before
+    /// connecting, which can take a while for high levels.
+    ///
+    /// # Default
+    ///
+    /// 8
+    #[inline]
+    pub fn security_level(mut self, security_level: u8) -> Self {
+        self.security_level = security_level;
+        self
+    }
+
+    /// The highest security level, to which the identity is raised
+    /// automatically, if the server requires a higher level than the
+    /// identity has.
+    ///
+    /// # Default
+    ///
+    /// 20
+    #[inline]
+    pub fn max_security_level(mut self, max_security_level: u8) -> Self {
+        self.max_security_level = max_security_level;
+        self
     }
 
     /// The name of the user.
@@ -875,6 +1881,292 @@ impl ConnectOptions {
         self.log_packets = log_packets;
         self
     }
+
+    /// Reconnect automatically if the connection gets lost.
+    ///
+    /// The same identity and settings are used for the new connection and it
+    /// keeps its `ConnectionId`. Connections which are closed with
+    /// [`ConnectionManager::remove_connection`] are not reconnected.
+    ///
+    /// # Default
+    ///
+    /// Do not reconnect
+    ///
+    /// [`ConnectionManager::remove_connection`]: struct.ConnectionManager.html#method.remove_connection
+    #[inline]
+    pub fn reconnect(mut self, reconnect: ReconnectOptions) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// How many received text messages are kept in the chat history of this
+    /// connection.
+    ///
+    /// If the history is full, the oldest message is removed.
+    ///
+    /// # Default
+    ///
+    /// 100
+    #[inline]
+    pub fn chat_history(mut self, chat_history: usize) -> Self {
+        self.chat_history = chat_history;
+        self
+    }
+
+    /// How long connecting to the server may take, before it fails with
+    /// [`ConnectionFailure::Timeout`].
+    ///
+    /// The timeout is applied once to the whole connection attempt. It
+    /// includes resolving the address, the handshake, the answer of the server
+    /// to our `clientinit` and retries with a higher security level, if the
+    /// server requires it. It does not include improving the identity to the
+    /// configured [`security_level`] before connecting.
+    ///
+    /// A negative timeout is treated like zero, so connecting fails
+    /// immediately.
+    ///
+    /// # Default
+    ///
+    /// 10 seconds
+    ///
+    /// [`security_level`]: #method.security_level
+    /// [`ConnectionFailure::Timeout`]: enum.ConnectionFailure.html#variant.Timeout
+    #[inline]
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        // Only negative durations cannot be converted
+        self.connect_timeout = connect_timeout.to_std()
+            .unwrap_or_else(|_| StdDuration::new(0, 0));
+        self
+    }
+
+    /// The password which is needed to join the server.
+    ///
+    /// # Default
+    ///
+    /// No password
+    #[inline]
+    pub fn server_password(mut self, server_password: String) -> Self {
+        self.server_password = server_password;
+        self
+    }
+
+    /// The channel which should be joined after connecting.
+    ///
+    /// The path of a channel is the name of the channel and its parents,
+    /// separated by slashes, e.g. `Lobby/Bots`.
+    ///
+    /// # Default
+    ///
+    /// The default channel of the server
+    #[inline]
+    pub fn default_channel(mut self, path: String) -> Self {
+        self.default_channel = path;
+        self
+    }
+
+    /// The channel which should be joined after connecting, specified by its
+    /// id.
+    ///
+    /// # Default
+    ///
+    /// The default channel of the server
+    #[inline]
+    pub fn default_channel_id(mut self, channel: ChannelId) -> Self {
+        self.default_channel = format!("/{}", channel.0);
+        self
+    }
+
+    /// The password of the channel which is set by
+    /// [`default_channel`] or [`default_channel_id`].
+    ///
+    /// # Default
+    ///
+    /// No password
+    ///
+    /// [`default_channel`]: #method.default_channel
+    /// [`default_channel_id`]: #method.default_channel_id
+    #[inline]
+    pub fn default_channel_password(mut self, password: String) -> Self {
+        self.default_channel_password = password;
+        self
+    }
+
+    /// A privilege key (also called token), which is used when connecting to
+    /// get into a server or channel group.
+    ///
+    /// # Default
+    ///
+    /// No privilege key
+    #[inline]
+    pub fn privilege_key(mut self, privilege_key: String) -> Self {
+        self.privilege_key = privilege_key;
+        self
+    }
+
+    /// How the name of the user is pronounced.
+    ///
+    /// # Default
+    ///
+    /// Empty
+    #[inline]
+    pub fn phonetic_name(mut self, phonetic_name: String) -> Self {
+        self.phonetic_name = phonetic_name;
+        self
+    }
+
+    /// Custom metadata of the client, which is visible to other clients.
+    ///
+    /// # Default
+    ///
+    /// Empty
+    #[inline]
+    pub fn metadata(mut self, metadata: String) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// If the client has a working microphone.
+    ///
+    /// # Default
+    ///
+    /// true
+    #[inline]
+    pub fn input_hardware(mut self, input_hardware: bool) -> Self {
+        self.input_hardware = input_hardware;
+        self
+    }
+
+    /// If the client has working speakers.
+    ///
+    /// # Default
+    ///
+    /// true
+    #[inline]
+    pub fn output_hardware(mut self, output_hardware: bool) -> Self {
+        self.output_hardware = output_hardware;
+        self
+    }
+
+    /// The hardware id which is sent to the server.
+    ///
+    /// # Default
+    ///
+    /// An id which is derived from the identity, so it stays the same for
+    /// each identity.
+    #[inline]
+    pub fn hardware_id(mut self, hardware_id: String) -> Self {
+        self.hardware_id = Some(hardware_id);
+        self
+    }
+
+    /// The thread pool which is used for cpu intensive computations of this
+    /// connection, like raising the security level of the identity and
+    /// solving the RSA puzzle of the handshake.
+    ///
+    /// # Default
+    ///
+    /// The pool of the [`ConnectionManager`], see
+    /// [`ConnectionManager::set_cpu_pool`].
+    ///
+    /// [`ConnectionManager`]: struct.ConnectionManager.html
+    /// [`ConnectionManager::set_cpu_pool`]: struct.ConnectionManager.html#method.set_cpu_pool
+    #[inline]
+    pub fn cpu_pool(mut self, cpu_pool: CpuPool) -> Self {
+        self.cpu_pool = Some(cpu_pool);
+        self
+    }
+}
+
+/// Configures how a lost connection is reestablished.
+///
+/// The delay before each attempt grows exponentially, starting with the
+/// `initial_delay`.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    initial_delay: Duration,
+    max_delay: Duration,
+    factor: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectOptions {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::seconds(1),
+            max_delay: Duration::minutes(1),
+            factor: 2.0,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectOptions {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The time to wait before the first attempt.
+    ///
+    /// # Default
+    ///
+    /// 1 second
+    #[inline]
+    pub fn initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// The maximum time to wait between two attempts.
+    ///
+    /// # Default
+    ///
+    /// 1 minute
+    #[inline]
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// The delay gets multiplied with this factor after each failed attempt.
+    ///
+    /// # Default
+    ///
+    /// 2
+    #[inline]
+    pub fn factor(mut self, factor: f64) -> Self {
+        self.factor = factor;
+        self
+    }
+
+    /// Give up after this number of failed attempts.
+    ///
+    /// # Default
+    ///
+    /// Try forever
+    #[inline]
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// If another attempt should be made after `attempt - 1` attempts failed.
+    fn should_try(&self, attempt: u32) -> bool {
+        self.max_attempts.map(|m| attempt <= m).unwrap_or(true)
+    }
+
+    /// The time to wait before the `attempt`th try, starting with 1.
+    ///
+    /// The delay is never negative and never longer than the `max_delay`.
+    fn get_delay(&self, attempt: u32) -> Duration {
+        let initial = self.initial_delay.num_milliseconds().max(0) as f64;
+        let max = self.max_delay.num_milliseconds().max(0) as f64;
+        let exponent = attempt.saturating_sub(1).min(i32::max_value() as u32);
+        let delay = initial * self.factor.powi(exponent as i32);
+        // `min` and `max` return the other value if the delay is NaN
+        Duration::milliseconds(delay.min(max).max(0.0) as i64)
+    }
 }
 
 pub struct DisconnectOptions {
@@ -923,3 +2215,277 @@ impl DisconnectOptions {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils::*;
+
+    #[test]
+    fn reconnect_delay_grows() {
+        let options = ReconnectOptions::new()
+            .initial_delay(Duration::seconds(1))
+            .max_delay(Duration::seconds(10))
+            .factor(1.5);
+        assert_eq!(options.get_delay(1), Duration::seconds(1));
+        assert_eq!(options.get_delay(2), Duration::milliseconds(1500));
+        assert_eq!(options.get_delay(3), Duration::milliseconds(2250));
+        // Capped at the maximum delay
+        assert_eq!(options.get_delay(10), Duration::seconds(10));
+        assert_eq!(options.get_delay(u32::max_value()), Duration::seconds(10));
+    }
+
+    #[test]
+    fn reconnect_delay_not_negative() {
+        let options = ReconnectOptions::new()
+            .initial_delay(Duration::seconds(-1));
+        assert_eq!(options.get_delay(1), Duration::zero());
+        assert_eq!(options.get_delay(5), Duration::zero());
+
+        let options = ReconnectOptions::new().factor(-2.0);
+        assert_eq!(options.get_delay(2), Duration::zero());
+
+        let options = ReconnectOptions::new()
+            .max_delay(Duration::seconds(-1));
+        assert_eq!(options.get_delay(1), Duration::zero());
+    }
+
+    #[test]
+    fn reconnect_attempts() {
+        let options = ReconnectOptions::new();
+        assert!(options.should_try(1));
+        assert!(options.should_try(1000));
+
+        let options = ReconnectOptions::new().max_attempts(3);
+        assert!(options.should_try(1));
+        assert!(options.should_try(3));
+        assert!(!options.should_try(4));
+
+        let options = ReconnectOptions::new().max_attempts(0);
+        assert!(!options.should_try(1));
+    }
+
+    #[test]
+    fn file_list() {
+        let responses = vec![
+            parse_message("notifyfilelist cid=1 path=\\/ name=dir size=0 \
+                datetime=1500000000 type=0 return_code=0"),
+            parse_message("notifyfilelist cid=1 path=\\/ name=file size=5 \
+                datetime=1500000001 type=1 return_code=0"),
+            parse_message("error id=0 msg=ok return_code=0"),
+        ];
+        let files = parse_file_list(&responses);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "dir");
+        assert!(!files[0].is_file);
+        assert_eq!(files[1].channel, ChannelId(1));
+        assert_eq!(files[1].path, "/");
+        assert_eq!(files[1].name, "file");
+        assert_eq!(files[1].size, 5);
+        assert_eq!(files[1].modified.timestamp(), 1_500_000_001);
+        assert!(files[1].is_file);
+
+        let file = structs::File::from(&files[1]);
+        assert_eq!(file.channel_id, ChannelId(1));
+        assert_eq!(file.path, "/");
+        assert_eq!(file.name, "file");
+        assert!(file.is_file);
+    }
+
+    fn default_options() -> ConnectOptions {
+        let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+        ConnectOptions::from_address(addr)
+    }
+
+    #[test]
+    fn clientinit_passwords() {
+        let identity = Identity::create().unwrap();
+        let cmd = create_clientinit(&default_options(), &identity);
+        // Empty passwords are not hashed
+        assert_eq!(cmd.get_static_arg("client_server_password"), Some(""));
+        assert_eq!(cmd.get_static_arg("client_default_channel_password"),
+            Some(""));
+
+        let options = default_options()
+            .server_password(String::from("secret"))
+            .default_channel_password(String::from("password"));
+        let cmd = create_clientinit(&options, &identity);
+        assert_eq!(cmd.get_static_arg("client_server_password"),
+            Some("5en6G6MezRroT3XKqkdPOmY/BfQ="));
+        assert_eq!(cmd.get_static_arg("client_default_channel_password"),
+            Some("W6ph5Mm5Pz8GgiULbPgzG37mj9g="));
+    }
+
+    #[test]
+    fn clientinit_default_channel() {
+        let identity = Identity::create().unwrap();
+        let cmd = create_clientinit(&default_options(), &identity);
+        assert_eq!(cmd.get_static_arg("client_default_channel"), Some(""));
+
+        let options = default_options()
+            .default_channel(String::from("Lobby/Music"));
+        let cmd = create_clientinit(&options, &identity);
+        assert_eq!(cmd.get_static_arg("client_default_channel"),
+            Some("Lobby/Music"));
+
+        let options = default_options().default_channel_id(ChannelId(5));
+        let cmd = create_clientinit(&options, &identity);
+        assert_eq!(cmd.get_static_arg("client_default_channel"), Some("/5"));
+    }
+
+    #[test]
+    fn clientinit_identity() {
+        let identity = Identity::create().unwrap();
+        let options = default_options().name(String::from("Bot"));
+        let cmd = create_clientinit(&options, &identity);
+        assert_eq!(cmd.get_static_arg("client_nickname"), Some("Bot"));
+        assert_eq!(cmd.get_static_arg("client_key_offset"),
+            Some(identity.get_offset().to_string().as_str()));
+        assert_eq!(cmd.get_static_arg("hwid"),
+            Some(identity.get_hardware_id().as_str()));
+
+        let options = options.hardware_id(String::from("a,b"));
+        let cmd = create_clientinit(&options, &identity);
+        assert_eq!(cmd.get_static_arg("hwid"), Some("a,b"));
+    }
+
+    #[test]
+    fn ban_duration() {
+        assert_eq!(parse_ban_duration("you may retry in 600 seconds"),
+            Some(Duration::seconds(600)));
+        assert_eq!(parse_ban_duration("you may retry in 1 second"),
+            Some(Duration::seconds(1)));
+        assert_eq!(parse_ban_duration("banned for 3 reasons"), None);
+        assert_eq!(parse_ban_duration("you may retry in 5 minutes"), None);
+        assert_eq!(parse_ban_duration("you may retry in -5 seconds"), None);
+        assert_eq!(parse_ban_duration(""), None);
+    }
+
+    #[test]
+    fn connect_timeout_not_negative() {
+        let options = default_options().connect_timeout(Duration::seconds(-1));
+        assert_eq!(options.connect_timeout, StdDuration::new(0, 0));
+        let options = options.connect_timeout(Duration::seconds(3));
+        assert_eq!(options.connect_timeout, StdDuration::from_secs(3));
+    }
+
+    /// Create an error, which the server sends as answer to our `clientinit`.
+    fn command_error(id: errors::Error, extra: &str) -> ConnectionFailure {
+        let msg = format!("error id={} msg=error\\s2{}", id.to_u32().unwrap(),
+            extra);
+        if let messages::Message::CommandError(error) = *parse_message(&msg) {
+            ConnectionFailure::from_command_error(&error)
+        } else {
+            panic!("Expected an error");
+        }
+    }
+
+    #[test]
+    fn connection_failure() {
+        use ConnectionFailure::*;
+        let cases = [
+            (errors::Error::ConnectFailedBanned,
+                " extra_msg=you\\smay\\sretry\\sin\\s600\\sseconds",
+                Banned {
+                    duration: Some(Duration::seconds(600)),
+                    message: String::from("error 2"),
+                }),
+            (errors::Error::ConnectFailedBanned, "", Banned {
+                duration: None,
+                message: String::from("error 2"),
+            }),
+            (errors::Error::ServerInvalidPassword, "", WrongServerPassword),
+            (errors::Error::ServerMaxclientsReached, "", ServerFull),
+            (errors::Error::ClientVersionOutdated, "", VersionOutdated),
+            (errors::Error::ClientCouldNotValidateIdentity, " extra_msg=23",
+                SecurityLevelTooLow { required: Some(23) }),
+            (errors::Error::ClientCouldNotValidateIdentity, "",
+                SecurityLevelTooLow { required: None }),
+            (errors::Error::DatabaseEmptyResultSet, "",
+                Rejected(errors::Error::DatabaseEmptyResultSet,
+                    String::from("error 2"))),
+        ];
+        for &(id, extra, ref expected) in &cases {
+            assert_eq!(&command_error(id, extra), expected,
+                "Error {:?} with {:?}", id, extra);
+        }
+    }
+
+    #[test]
+    fn chat_entry() {
+        let core = Core::new().unwrap();
+        let cm = ConnectionManager::new(core.handle());
+        let id = ConnectionId(0);
+        assert!(cm.get_chat_entry(id, ClientId(5)).is_none());
+
+        let mut con = network_wrapper(&core.handle(), id, default_options());
+        for &(sender, text) in &[(5, "first"), (6, "other"), (5, "second")] {
+            con.chat_history.push(structs::ChatEntry {
+                sender: ClientId(sender),
+                text: String::from(text),
+                date: Utc::now(),
+                mode: TextMessageTargetMode::Client,
+            });
+        }
+        cm.inner.borrow_mut().connections.insert(id, con);
+
+        // The newest message of the sender
+        assert_eq!(cm.get_chat_entry(id, ClientId(5)).unwrap().text, "second");
+        assert_eq!(cm.get_chat_entry(id, ClientId(6)).unwrap().text, "other");
+        assert!(cm.get_chat_entry(id, ClientId(7)).is_none());
+        assert!(cm.get_chat_entry(ConnectionId(1), ClientId(5)).is_none());
+    }
+
+    /// Only accepts futures which do not borrow anything.
+    fn assert_static<F: Future + 'static>(_: &F) {}
+
+    #[test]
+    fn owned_futures() {
+        // A server which never answers
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = ConnectOptions::from_address(socket.local_addr()
+            .unwrap()).connect_timeout(Duration::milliseconds(100));
+
+        let mut core = Core::new().unwrap();
+        let mut cm = ConnectionManager::new(core.handle());
+        let connect = {
+            let cm_ref = &cm;
+            cm_ref.add_connection(options.clone())
+        };
+        assert_static(&connect);
+
+        // The manager can be borrowed mutably while the future exists
+        match core.run(connect.select2(cm.run())) {
+            Err(Either::A((Error::ConnectionFailed(_), _))) => {}
+            _ => panic!("Expected a connection failure"),
+        }
+
+        // And the future outlives the manager
+        let connect = cm.add_connection(options);
+        drop(cm);
+        if let Ok(id) = core.run(connect) {
+            panic!("Expected an error, got {:?}", id);
+        }
+    }
+
+    #[test]
+    fn connected_without_manager() {
+        let core = Core::new().unwrap();
+        let client = client_data(&core.handle());
+        let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+        let con = Data::create_connection(&client, addr);
+        Data::add_connection(&client, con.clone());
+
+        let inner = Rc::downgrade(&ConnectionManager::new(core.handle())
+            .inner);
+        assert!(insert_connection(&inner, client.clone(), Rc::downgrade(&con),
+            &init_server(), default_options()).is_err());
+        // The connection is closed
+        assert!(client.borrow().connection_manager.get_connection(addr)
+            .is_none());
+    }
+}
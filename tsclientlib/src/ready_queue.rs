@@ -0,0 +1,180 @@
+//! A queue of streams which were woken up and should be polled.
+//!
+//! Polling hundreds of connections on every wakeup is wasteful if only one of
+//! them received a packet. Instead, every stream is polled with its own
+//! [`NotifyHandle`], which adds the id of the stream to the [`ReadyQueue`]
+//! when it is woken up. Only streams in the queue are polled afterwards.
+//!
+//! [`NotifyHandle`]: https://docs.rs/futures/0.1/futures/executor/struct.NotifyHandle.html
+//! [`ReadyQueue`]: struct.ReadyQueue.html
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::executor::{self, Notify};
+use futures::task::AtomicTask;
+
+#[derive(Debug, Default)]
+struct ReadySet {
+    /// The ids in the order in which they were woken up.
+    queue: VecDeque<usize>,
+    /// The ids which are currently in the queue, so they are not added twice.
+    queued: HashSet<usize>,
+}
+
+/// The ids of streams which should be polled, in the order in which they were
+/// woken up.
+///
+/// The queue is shared with the notify handles of the streams, which can be
+/// called from other threads.
+#[derive(Default)]
+pub struct ReadyQueue {
+    ready: Mutex<ReadySet>,
+    /// The task which polls the queue, it is notified when an id is added.
+    task: AtomicTask,
+}
+
+impl ReadyQueue {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Notify the current task when an id is added to the queue.
+    pub fn register(&self) {
+        self.task.register();
+    }
+
+    /// Notify the registered task without adding an id.
+    pub fn notify_task(&self) {
+        self.task.notify();
+    }
+
+    /// Add an id to the end of the queue, if it is not already queued.
+    pub fn push(&self, id: usize) {
+        {
+            let mut ready = self.ready.lock().unwrap();
+            if ready.queued.insert(id) {
+                ready.queue.push_back(id);
+            }
+        }
+        self.task.notify();
+    }
+
+    /// Take the id which was woken up first.
+    pub fn pop(&self) -> Option<usize> {
+        let mut ready = self.ready.lock().unwrap();
+        let id = ready.queue.pop_front();
+        if let Some(id) = id {
+            ready.queued.remove(&id);
+        }
+        id
+    }
+
+    /// Run `f`, which polls the stream `id`, so that the stream is added to
+    /// the queue when it is woken up.
+    ///
+    /// This has to be called from within a task.
+    pub fn poll_with<F: FnOnce() -> R, R>(queue: &Arc<Self>, id: usize, f: F)
+        -> R {
+        executor::with_notify(queue, id, f)
+    }
+}
+
+impl fmt::Debug for ReadyQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadyQueue({:?})", self.ready.lock().unwrap().queue)
+    }
+}
+
+impl Notify for ReadyQueue {
+    fn notify(&self, id: usize) {
+        self.push(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::{future, Async, Stream};
+    use futures::sync::mpsc;
+
+    use super::*;
+
+    /// Run `f` inside a task, which is needed to poll streams.
+    fn in_task<F: FnOnce()>(f: F) {
+        executor::spawn(future::lazy(|| -> Result<(), ()> {
+            f();
+            Ok(())
+        })).wait_future().unwrap();
+    }
+
+    #[test]
+    fn push_deduplicates() {
+        let queue = ReadyQueue::new();
+        queue.push(1);
+        queue.push(2);
+        queue.push(1);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn pop_in_wakeup_order() {
+        let queue = ReadyQueue::new();
+        for &i in &[3, 1, 2] {
+            queue.push(i);
+        }
+        // A popped id can be queued again and comes after the others
+        assert_eq!(queue.pop(), Some(3));
+        queue.push(3);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn notify_pushes_id() {
+        let queue = ReadyQueue::new();
+        let queue2 = queue.clone();
+        thread::spawn(move || queue2.notify(7)).join().unwrap();
+        assert_eq!(queue.pop(), Some(7));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn requeued_after_item() {
+        let queue = ReadyQueue::new();
+        let (send, mut recv) = mpsc::unbounded::<u32>();
+        in_task(|| {
+            assert_eq!(ReadyQueue::poll_with(&queue, 4, || recv.poll()),
+                Ok(Async::NotReady));
+            assert_eq!(queue.pop(), None);
+
+            // Woken up by new items
+            send.unbounded_send(1).unwrap();
+            send.unbounded_send(2).unwrap();
+            assert_eq!(queue.pop(), Some(4));
+            assert_eq!(queue.pop(), None);
+
+            // The stream may have more items, so it is queued again
+            assert_eq!(ReadyQueue::poll_with(&queue, 4, || recv.poll()),
+                Ok(Async::Ready(Some(1))));
+            queue.push(4);
+            assert_eq!(queue.pop(), Some(4));
+            assert_eq!(ReadyQueue::poll_with(&queue, 4, || recv.poll()),
+                Ok(Async::Ready(Some(2))));
+            queue.push(4);
+            assert_eq!(queue.pop(), Some(4));
+            assert_eq!(ReadyQueue::poll_with(&queue, 4, || recv.poll()),
+                Ok(Async::NotReady));
+            assert_eq!(queue.pop(), None);
+
+            send.unbounded_send(3).unwrap();
+            assert_eq!(queue.pop(), Some(4));
+        });
+    }
+}
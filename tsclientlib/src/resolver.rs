@@ -0,0 +1,610 @@
+//! Resolve the address of a TeamSpeak server.
+//!
+//! A server address like `ts.example.com` is resolved in the same order as the
+//! TeamSpeak client does it:
+//!
+//! 1. The `_ts3._udp` SRV record of the host.
+//! 2. A TSDNS server (tcp port 41144) on the host or one of its parent
+//!    domains.
+//! 3. The A and AAAA records of the host with the default port 9987.
+//!
+//! If the address contains a port, only the last step is used.
+//!
+//! The dns lookups are done by a [`Resolver`], which can be replaced to use a
+//! custom dns implementation.
+//!
+//! [`Resolver`]: trait.Resolver.html
+use std::fmt;
+use std::mem;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration as StdDuration;
+
+use futures::{future, Async, Future, Poll};
+use futures::future::Either;
+use slog::Logger;
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::{Handle, Timeout};
+use trust_dns_resolver::ResolverFuture;
+
+use {BoxFuture, ConnectionFailure, Error, Result};
+use utils::{poll_io, poll_write_all};
+
+/// The port which is used if the address contains no port.
+pub const DEFAULT_PORT: u16 = 9987;
+/// The tcp port of TSDNS servers.
+pub const TSDNS_PORT: u16 = 41144;
+/// The maximum length of an answer of a TSDNS server.
+const MAX_TSDNS_ANSWER: usize = 256;
+/// How long we wait for the answer of a TSDNS server, before trying the next
+/// one.
+const TSDNS_TIMEOUT_SECS: u64 = 3;
+
+/// The address of a TeamSpeak server.
+///
+/// It can be created from a `SocketAddr` or from a string like
+/// `ts.example.com`, `ts.example.com:9987`, `127.0.0.1` or `[::1]:9987`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ServerAddress {
+    SocketAddr(SocketAddr),
+    /// A host name or ip address with an optional port.
+    Other(String),
+}
+
+impl From<SocketAddr> for ServerAddress {
+    fn from(addr: SocketAddr) -> Self {
+        ServerAddress::SocketAddr(addr)
+    }
+}
+
+impl From<String> for ServerAddress {
+    fn from(addr: String) -> Self {
+        ServerAddress::Other(addr)
+    }
+}
+
+impl<'a> From<&'a str> for ServerAddress {
+    fn from(addr: &'a str) -> Self {
+        ServerAddress::Other(addr.to_string())
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddress::SocketAddr(ref addr) => write!(f, "{}", addr),
+            ServerAddress::Other(ref addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Does the dns lookups which are needed to find a server.
+///
+/// The default implementation is [`DnsResolver`], which uses the system
+/// configuration. A custom resolver can be set with
+/// [`ConnectOptions::resolver`].
+///
+/// [`DnsResolver`]: struct.DnsResolver.html
+/// [`ConnectOptions::resolver`]: ../struct.ConnectOptions.html#method.resolver
+pub trait Resolver: fmt::Debug {
+    /// Look up the SRV records of `name`.
+    ///
+    /// Returns the target hosts and ports, sorted by their priority. An empty
+    /// list is returned if no record exists.
+    fn resolve_srv(&self, name: &str) -> BoxFuture<Vec<(String, u16)>>;
+
+    /// Look up the A and AAAA records of `host` and combine them with `port`.
+    fn resolve_host(&self, host: &str, port: u16) -> BoxFuture<Vec<SocketAddr>>;
+}
+
+/// A [`Resolver`] which uses the dns servers of the system.
+///
+/// [`Resolver`]: trait.Resolver.html
+pub struct DnsResolver {
+    resolver: ResolverFuture,
+}
+
+impl DnsResolver {
+    pub fn new(handle: &Handle) -> Result<Self> {
+        Ok(Self {
+            resolver: ResolverFuture::from_system_conf(handle)?,
+        })
+    }
+}
+
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DnsResolver(...)")
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn resolve_srv(&self, name: &str) -> BoxFuture<Vec<(String, u16)>> {
+        Box::new(self.resolver.lookup_srv(name).then(|res| {
+            let lookup = match res {
+                Ok(l) => l,
+                // Treat errors as if no record exists
+                Err(_) => return Ok(Vec::new()),
+            };
+            let mut records: Vec<_> = lookup.iter().map(|srv| (srv.priority(),
+                srv.target().to_string(), srv.port())).collect();
+            records.sort_by_key(|&(priority, _, _)| priority);
+            Ok(records.into_iter().map(|(_, target, port)| {
+                // Remove the trailing dot of fully qualified names
+                (target.trim_right_matches('.').to_string(), port)
+            }).collect())
+        }))
+    }
+
+    fn resolve_host(&self, host: &str, port: u16)
+        -> BoxFuture<Vec<SocketAddr>> {
+        Box::new(self.resolver.lookup_ip(host)
+            .map(move |lookup| lookup.iter()
+                .map(|ip| SocketAddr::new(ip, port)).collect())
+            .map_err(|e| format_err!("Failed to resolve host ({})", e).into()))
+    }
+}
+
+/// Split an address into the host and the port, if it contains one.
+fn split_port(address: &str) -> Result<(&str, Option<u16>)> {
+    if address.starts_with('[') {
+        // Ipv6 address
+        let end = address.find(']').ok_or_else(|| format_err!(
+            "Invalid ipv6 address"))?;
+        let host = &address[1..end];
+        let rest = &address[end + 1..];
+        if rest.is_empty() {
+            return Ok((host, None));
+        }
+        if !rest.starts_with(':') {
+            return Err(format_err!("Invalid ipv6 address").into());
+        }
+        return Ok((host, Some(parse_port(&rest[1..])?)));
+    }
+
+    // An ipv6 address without brackets contains more than one colon
+    if address.matches(':').count() == 1 {
+        let pos = address.find(':').unwrap();
+        Ok((&address[..pos], Some(parse_port(&address[pos + 1..])?)))
+    } else {
+        Ok((address, None))
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16> {
+    Ok(port.parse().map_err(|_| format_err!("Invalid port {}", port))?)
+}
+
+/// The domains where a TSDNS server for `host` is searched.
+///
+/// These are the host and all its parent domains, except for the top level
+/// domain.
+fn tsdns_domains(host: &str) -> Vec<String> {
+    let parts: Vec<_> = host.split('.').collect();
+    (0..parts.len().saturating_sub(1)).map(|i| parts[i..].join("."))
+        .collect()
+}
+
+/// Resolve the address of a server.
+///
+/// Returns the first address which was found.
+pub fn resolve(handle: &Handle, logger: &Logger, resolver: Rc<Resolver>,
+    address: &ServerAddress) -> BoxFuture<SocketAddr> {
+    let address = match *address {
+        ServerAddress::SocketAddr(addr) => return Box::new(future::ok(addr)),
+        ServerAddress::Other(ref addr) => addr.trim(),
+    };
+    let (host, port) = tryf!(split_port(address));
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Box::new(future::ok(SocketAddr::new(ip,
+            port.unwrap_or(DEFAULT_PORT))));
+    }
+    let host = host.to_string();
+
+    let res: BoxFuture<_> = if let Some(port) = port {
+        resolve_first(resolver, &host, port)
+    } else {
+        let handle = handle.clone();
+        let logger = logger.clone();
+        let logger2 = logger.clone();
+        let resolver2 = resolver.clone();
+        let host2 = host.clone();
+        // 1. SRV record
+        Box::new(resolve_srv(resolver.clone(), &host)
+            .or_else(move |error| {
+                debug!(logger, "SRV lookup failed"; "error" => ?error);
+                // 2. TSDNS
+                resolve_tsdns(handle, resolver, host,
+                    StdDuration::from_secs(TSDNS_TIMEOUT_SECS))
+            })
+            .or_else(move |error| {
+                debug!(logger2, "TSDNS lookup failed"; "error" => ?error);
+                // 3. A and AAAA records
+                resolve_first(resolver2, &host2, DEFAULT_PORT)
+            }))
+    };
+    Box::new(res.map_err(|_|
+        Error::ConnectionFailed(ConnectionFailure::UnknownHost)))
+}
+
+/// Resolve a host and return the first address.
+fn resolve_first(resolver: Rc<Resolver>, host: &str, port: u16)
+    -> BoxFuture<SocketAddr> {
+    let host = host.to_string();
+    Box::new(resolver.resolve_host(&host, port).and_then(move |addrs|
+        addrs.into_iter().next().ok_or_else(|| format_err!(
+            "Found no address for {}", host).into())))
+}
+
+/// Resolve the `_ts3._udp` SRV record of a host.
+fn resolve_srv(resolver: Rc<Resolver>, host: &str) -> BoxFuture<SocketAddr> {
+    let name = format!("_ts3._udp.{}", host);
+    Box::new(resolver.resolve_srv(&name).and_then(move |records|
+        -> BoxFuture<_> {
+        match records.into_iter().next() {
+            Some((target, port)) => resolve_first(resolver, &target, port),
+            None => Box::new(future::err(format_err!("No SRV record found")
+                .into())),
+        }
+    }))
+}
+
+/// Ask the TSDNS servers on the host and its parent domains for the address
+/// of the host.
+///
+/// A server which does not answer within `timeout` is skipped.
+fn resolve_tsdns(handle: Handle, resolver: Rc<Resolver>, host: String,
+    timeout: StdDuration) -> BoxFuture<SocketAddr> {
+    let domains = tsdns_domains(&host);
+    Box::new(future::loop_fn(domains.into_iter(), move |mut domains|
+        -> BoxFuture<future::Loop<SocketAddr, _>> {
+        let domain = if let Some(d) = domains.next() {
+            d
+        } else {
+            return Box::new(future::err(format_err!(
+                "Found no TSDNS server").into()));
+        };
+        let handle = handle.clone();
+        let resolver2 = resolver.clone();
+        let host = host.clone();
+        Box::new(resolve_first(resolver.clone(), &domain, TSDNS_PORT)
+            .and_then(move |server| -> BoxFuture<_> {
+                let query = TsDnsQuery::new(&handle, server, &host);
+                let timeout = tryf!(Timeout::new(timeout, &handle));
+                Box::new(query.select2(timeout).then(|res| match res {
+                    Ok(Either::A((answer, _))) => Ok(answer),
+                    Ok(Either::B(((), _))) => Err(format_err!(
+                        "The TSDNS server did not answer").into()),
+                    Err(Either::A((error, _))) => Err(error),
+                    Err(Either::B((error, _))) => Err(error.into()),
+                }))
+            })
+            .and_then(move |answer| parse_tsdns_answer(resolver2, &answer))
+            .then(move |res| Ok::<_, Error>(match res {
+                Ok(addr) => future::Loop::Break(addr),
+                Err(_) => future::Loop::Continue(domains),
+            })))
+    }))
+}
+
+/// Parse the answer of a TSDNS server, which is either `404` or an address
+/// with an optional port.
+fn parse_tsdns_answer(resolver: Rc<Resolver>, answer: &str)
+    -> BoxFuture<SocketAddr> {
+    let answer = answer.trim();
+    if answer.is_empty() || answer == "404" {
+        return Box::new(future::err(format_err!(
+            "The TSDNS server does not know the host").into()));
+    }
+    let (host, port) = tryf!(split_port(answer));
+    let port = port.unwrap_or(DEFAULT_PORT);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        Box::new(future::ok(SocketAddr::new(ip, port)))
+    } else {
+        resolve_first(resolver, host, port)
+    }
+}
+
+enum TsDnsState {
+    Connecting(TcpStreamNew),
+    /// `pos` is the number of bytes of the query which are sent.
+    Sending { stream: TcpStream, pos: usize },
+    Receiving { stream: TcpStream, answer: Vec<u8> },
+    Done,
+}
+
+/// Sends a host name to a TSDNS server and receives the answer.
+///
+/// The server closes the connection after it sent the answer.
+struct TsDnsQuery {
+    query: Vec<u8>,
+    state: TsDnsState,
+}
+
+impl TsDnsQuery {
+    fn new(handle: &Handle, server: SocketAddr, host: &str) -> Self {
+        Self {
+            query: host.as_bytes().to_vec(),
+            state: TsDnsState::Connecting(TcpStream::connect(&server, handle)),
+        }
+    }
+}
+
+impl Future for TsDnsQuery {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let connected = match self.state {
+                TsDnsState::Connecting(ref mut connect) =>
+                    Some(try_ready!(connect.poll())),
+                TsDnsState::Sending { ref mut stream, ref mut pos } => {
+                    try_ready!(poll_write_all(stream, &self.query, pos));
+                    None
+                }
+                TsDnsState::Receiving { ref mut stream, ref mut answer } => {
+                    let mut buf = [0; MAX_TSDNS_ANSWER];
+                    let read = try_ready!(poll_io(stream.read(&mut buf)));
+                    if read != 0 {
+                        answer.extend_from_slice(&buf[..read]);
+                        if answer.len() > MAX_TSDNS_ANSWER {
+                            return Err(format_err!(
+                                "The TSDNS answer is too long").into());
+                        }
+                        continue;
+                    }
+                    None
+                }
+                TsDnsState::Done => return Err(format_err!(
+                    "TsDnsQuery polled after it finished").into()),
+            }
+
+            // Go to the next state
+            if let Some(stream) = connected {
+                self.state = TsDnsState::Sending { stream, pos: 0 };
+                continue;
+            }
+            match mem::replace(&mut self.state, TsDnsState::Done) {
+                TsDnsState::Sending { stream, .. } =>
+                    self.state = TsDnsState::Receiving {
+                        stream,
+                        answer: Vec::new(),
+                    },
+                TsDnsState::Receiving { answer, .. } => {
+                    let answer = String::from_utf8(answer).map_err(|_|
+                        io::Error::new(io::ErrorKind::InvalidData,
+                            "The TSDNS answer is not valid utf-8"))?;
+                    return Ok(Async::Ready(answer));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use Map;
+    use test_utils::*;
+
+    /// Answers dns lookups from fixed records and remembers the queries.
+    #[derive(Debug, Default)]
+    struct FakeResolver {
+        srv: Map<String, Vec<(String, u16)>>,
+        hosts: Map<(String, u16), Vec<SocketAddr>>,
+        queries: RefCell<Vec<String>>,
+    }
+
+    impl FakeResolver {
+        fn srv(mut self, name: &str, target: &str, port: u16) -> Self {
+            self.srv.insert(name.to_string(), vec![(target.to_string(), port)]);
+            self
+        }
+
+        fn host(mut self, host: &str, port: u16, addr: &str) -> Self {
+            self.hosts.insert((host.to_string(), port),
+                vec![addr.parse().unwrap()]);
+            self
+        }
+    }
+
+    impl Resolver for FakeResolver {
+        fn resolve_srv(&self, name: &str) -> BoxFuture<Vec<(String, u16)>> {
+            self.queries.borrow_mut().push(format!("srv {}", name));
+            Box::new(future::ok(self.srv.get(name).cloned()
+                .unwrap_or_default()))
+        }
+
+        fn resolve_host(&self, host: &str, port: u16)
+            -> BoxFuture<Vec<SocketAddr>> {
+            self.queries.borrow_mut().push(format!("host {}:{}", host, port));
+            match self.hosts.get(&(host.to_string(), port)) {
+                Some(addrs) => Box::new(future::ok(addrs.clone())),
+                None => Box::new(future::err(format_err!("Unknown host {}",
+                    host).into())),
+            }
+        }
+    }
+
+    /// Resolve the address and return the result and the dns queries.
+    fn resolve_with(resolver: FakeResolver, address: &str)
+        -> (Result<SocketAddr>, Vec<String>) {
+        let mut core = Core::new().unwrap();
+        let resolver = Rc::new(resolver);
+        let fut = resolve(&core.handle(), &logger(), resolver.clone(),
+            &ServerAddress::from(address));
+        let res = core.run(fut);
+        let queries = resolver.queries.borrow().clone();
+        (res, queries)
+    }
+
+    /// Start a TSDNS server, which answers one query.
+    ///
+    /// Returns the address of the server and a receiver for the query.
+    fn tsdns_server(answer: &'static str)
+        -> (SocketAddr, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 256];
+            let read = stream.read(&mut buf).unwrap();
+            send.send(String::from_utf8(buf[..read].to_vec()).unwrap())
+                .unwrap();
+            stream.write_all(answer.as_bytes()).unwrap();
+        });
+        (addr, recv)
+    }
+
+    #[test]
+    fn split_host_and_port() {
+        assert_eq!(split_port("ts.example.com").unwrap(),
+            ("ts.example.com", None));
+        assert_eq!(split_port("ts.example.com:9000").unwrap(),
+            ("ts.example.com", Some(9000)));
+        assert_eq!(split_port("127.0.0.1:9000").unwrap(),
+            ("127.0.0.1", Some(9000)));
+        assert!(split_port("ts.example.com:port").is_err());
+        assert!(split_port("ts.example.com:70000").is_err());
+    }
+
+    #[test]
+    fn split_ipv6_port() {
+        assert_eq!(split_port("::1").unwrap(), ("::1", None));
+        assert_eq!(split_port("2001:db8::1").unwrap(), ("2001:db8::1", None));
+        assert_eq!(split_port("[::1]").unwrap(), ("::1", None));
+        assert_eq!(split_port("[::1]:9000").unwrap(), ("::1", Some(9000)));
+        assert!(split_port("[::1").is_err());
+        assert!(split_port("[::1]9000").is_err());
+        assert!(split_port("[::1]:").is_err());
+    }
+
+    #[test]
+    fn tsdns_domain_list() {
+        assert_eq!(tsdns_domains("a.b.example.com"), vec!["a.b.example.com",
+            "b.example.com", "example.com"]);
+        assert_eq!(tsdns_domains("example.com"), vec!["example.com"]);
+        assert!(tsdns_domains("localhost").is_empty());
+    }
+
+    #[test]
+    fn resolve_ip() {
+        let (res, queries) = resolve_with(FakeResolver::default(),
+            "127.0.0.1");
+        assert_eq!(res.unwrap(), "127.0.0.1:9987".parse().unwrap());
+        assert!(queries.is_empty());
+
+        let (res, queries) = resolve_with(FakeResolver::default(),
+            "[::1]:9000");
+        assert_eq!(res.unwrap(), "[::1]:9000".parse().unwrap());
+        assert!(queries.is_empty());
+    }
+
+    #[test]
+    fn resolve_with_port() {
+        let resolver = FakeResolver::default()
+            .srv("_ts3._udp.ts.example.com", "other.example.com", 9000)
+            .host("ts.example.com", 9001, "1.2.3.4:9001");
+        let (res, queries) = resolve_with(resolver, "ts.example.com:9001");
+        assert_eq!(res.unwrap(), "1.2.3.4:9001".parse().unwrap());
+        // The SRV record is not used
+        assert_eq!(queries, vec!["host ts.example.com:9001"]);
+    }
+
+    #[test]
+    fn resolve_srv_first() {
+        let resolver = FakeResolver::default()
+            .srv("_ts3._udp.ts.example.com", "other.example.com", 9000)
+            .host("other.example.com", 9000, "1.2.3.4:9000")
+            .host("ts.example.com", DEFAULT_PORT, "5.6.7.8:9987");
+        let (res, queries) = resolve_with(resolver, "ts.example.com");
+        assert_eq!(res.unwrap(), "1.2.3.4:9000".parse().unwrap());
+        assert_eq!(queries, vec!["srv _ts3._udp.ts.example.com",
+            "host other.example.com:9000"]);
+    }
+
+    #[test]
+    fn resolve_default_port() {
+        let resolver = FakeResolver::default()
+            .host("ts.example.com", DEFAULT_PORT, "5.6.7.8:9987");
+        let (res, queries) = resolve_with(resolver, "ts.example.com");
+        assert_eq!(res.unwrap(), "5.6.7.8:9987".parse().unwrap());
+        // SRV, then TSDNS on the host and its parent domain, then A/AAAA
+        assert_eq!(queries, vec!["srv _ts3._udp.ts.example.com",
+            "host ts.example.com:41144", "host example.com:41144",
+            "host ts.example.com:9987"]);
+    }
+
+    #[test]
+    fn resolve_unknown_host() {
+        let (res, _) = resolve_with(FakeResolver::default(), "ts.example.com");
+        match res {
+            Err(Error::ConnectionFailed(ConnectionFailure::UnknownHost)) => {}
+            res => panic!("Expected an unknown host, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn resolve_over_tsdns() {
+        let (server, query) = tsdns_server("127.0.0.1:9000");
+        // The TSDNS server runs on the parent domain
+        let resolver = FakeResolver::default()
+            .host("example.com", TSDNS_PORT, &server.to_string())
+            .host("ts.example.com", DEFAULT_PORT, "5.6.7.8:9987");
+        let (res, queries) = resolve_with(resolver, "ts.example.com");
+        assert_eq!(res.unwrap(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(query.recv().unwrap(), "ts.example.com");
+        assert_eq!(queries, vec!["srv _ts3._udp.ts.example.com",
+            "host ts.example.com:41144", "host example.com:41144"]);
+    }
+
+    #[test]
+    fn resolve_tsdns_not_found() {
+        let (server, _query) = tsdns_server("404");
+        let resolver = FakeResolver::default()
+            .host("ts.example.com", TSDNS_PORT, &server.to_string())
+            .host("ts.example.com", DEFAULT_PORT, "5.6.7.8:9987");
+        let (res, _) = resolve_with(resolver, "ts.example.com");
+        assert_eq!(res.unwrap(), "5.6.7.8:9987".parse().unwrap());
+    }
+
+    #[test]
+    fn tsdns_timeout() {
+        // A TSDNS server which accepts connections but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (server, _query) = tsdns_server("127.0.0.1:9000");
+        let resolver = Rc::new(FakeResolver::default()
+            .host("ts.example.com", TSDNS_PORT,
+                &silent.local_addr().unwrap().to_string())
+            .host("example.com", TSDNS_PORT, &server.to_string()));
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let fut = resolve_tsdns(handle, resolver,
+            String::from("ts.example.com"), StdDuration::from_millis(100));
+        // The next server is asked after the timeout
+        assert_eq!(core.run(fut).unwrap(), "127.0.0.1:9000".parse().unwrap());
+    }
+
+    #[test]
+    fn tsdns_query() {
+        let (server, query) = tsdns_server("1.2.3.4:9000");
+        let mut core = Core::new().unwrap();
+        let mut fut = TsDnsQuery::new(&core.handle(), server, "ts.example.com");
+        assert_eq!(run(&mut core, &mut fut), "1.2.3.4:9000");
+        assert_eq!(query.recv().unwrap(), "ts.example.com");
+        // Polling a finished query fails instead of panicking
+        assert!(fut.poll().is_err());
+    }
+}
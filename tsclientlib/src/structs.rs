@@ -5,14 +5,21 @@ use std::ops::{Deref, DerefMut};
 use std::rc::{Rc, Weak};
 
 use chrono::{DateTime, Duration, Utc};
-use futures::{self, Stream};
+use futures::{self, Future, Stream};
+use futures::task::{self, Task};
+use futures::unsync::oneshot;
+use tokio_core::reactor::Timeout;
 use tsproto::Error as tsproto_error;
 use tsproto::client;
+use tsproto::connectionmanager::SocketConnectionManager;
+use tsproto::handler_data::ConnectionListener;
 use tsproto_commands::*;
 use tsproto_commands::messages::*;
 
-use {ChannelType, Map, MaxFamilyClients, TalkPowerRequest, Result};
+use {BoxFuture, ChannelType, ConnectOptions, ConnectionState,
+    DisconnectReason, Error, Event, Map, MaxFamilyClients, TalkPowerRequest, Result};
 use codec::Message;
+use filetransfer::FileInfo;
 
 include!(concat!(env!("OUT_DIR"), "/structs.rs"));
 include!(concat!(env!("OUT_DIR"), "/m2bdecls.rs"));
@@ -66,14 +73,34 @@ impl Connection {
         }
     }
 
-    fn handle_message(&mut self, msg: &Message) -> Result<()> {
+    /// Update the bookkeeping and collect the events which are raised by
+    /// this message.
+    fn handle_message(&mut self, msg: &Message, events: &mut Vec<Event>)
+        -> Result<()> {
         if let Message::Message(ref notification) = *msg {
-            self.handle_message_generated(&*notification)?;
-        }
+            let start = events.len();
+            self.handle_message_generated(&*notification, events)?;
+
+            // A changed channel of a client is a move
+            let mut moves = Vec::new();
+            for e in &events[start..] {
+                if let Event::PropertyChanged {
+                    id: PropertyId::ClientChannel(client),
+                    old: PropertyValue::ClientChannel(from),
+                    new: PropertyValue::ClientChannel(to),
+                } = *e {
+                    moves.push(Event::ClientMoved { client, from, to });
+                }
+            }
+            events.append(&mut moves);
 
-        // Also raise events
-        match *msg {
-            _ => {} // TODO
+            if let messages::Message::TextMessage(ref cmd) = **notification {
+                events.push(Event::TextMessage {
+                    target: cmd.target_mode,
+                    from: cmd.invoker_id,
+                    message: cmd.message.clone(),
+                });
+            }
         }
         Ok(())
     }
@@ -177,12 +204,139 @@ impl Connection {
     }
 }
 
+impl<'a> From<&'a FileInfo> for File {
+    fn from(info: &'a FileInfo) -> Self {
+        Self {
+            channel_id: info.channel,
+            path: info.path.clone(),
+            name: info.name.clone(),
+            size: info.size as _,
+            last_changed: info.modified,
+            is_file: info.is_file,
+        }
+    }
+}
+
+/// Gets notified when the tsproto connection is removed, so the
+/// `NetworkWrapper` can notice that the connection died.
+struct RemoveListener {
+    task: Rc<RefCell<Option<Task>>>,
+}
+
+impl ConnectionListener<SocketConnectionManager<client::ServerConnectionData>>
+    for RemoveListener {
+    fn on_connection_removed(&mut self, _: Rc<RefCell<client::ClientData>>,
+        _: SocketAddr) -> bool {
+        if let Some(ref task) = *self.task.borrow() {
+            task.notify();
+        }
+        false
+    }
+}
+
+/// A connection attempt after the connection was lost.
+struct Reconnect {
+    /// Starts at 1.
+    attempt: u32,
+    /// Waits for the backoff delay and connects afterwards.
+    ///
+    /// Returns the options with the identity, which was possibly improved.
+    future: BoxFuture<(Rc<RefCell<client::ClientData>>,
+        Weak<RefCell<client::ClientConnection>>, InitServer, ConnectOptions)>,
+}
+
+/// A command which was sent to the server and waits for its answer.
+struct PendingCommand {
+    /// The messages which the server sent as response to this command.
+    responses: Vec<Box<messages::Message>>,
+    sender: oneshot::Sender<Result<Vec<Box<messages::Message>>>>,
+}
+
+/// Matches the answers of the server to the commands which were sent.
+#[derive(Default)]
+struct PendingCommands {
+    /// The return code which will be attached to the next command.
+    next_return_code: u32,
+    /// Commands which wait for an answer, indexed by their return code.
+    commands: Map<String, PendingCommand>,
+}
+
+impl PendingCommands {
+    /// Register a command which will be sent to the server.
+    ///
+    /// Returns the return code, which has to be attached to the command, and
+    /// a receiver for the answer of the server.
+    fn add(&mut self) -> (String,
+        oneshot::Receiver<Result<Vec<Box<messages::Message>>>>) {
+        let return_code = self.next_return_code.to_string();
+        self.next_return_code = self.next_return_code.wrapping_add(1);
+        let (sender, receiver) = oneshot::channel();
+        self.commands.insert(return_code.clone(), PendingCommand {
+            responses: Vec::new(),
+            sender,
+        });
+        (return_code, receiver)
+    }
+
+    /// Collect responses to our commands and finish a command when the server
+    /// sent its error message.
+    fn handle_response(&mut self, msg: &Message) {
+        let msg = if let Message::Message(ref msg) = *msg {
+            msg
+        } else {
+            return;
+        };
+        if let messages::Message::CommandError(ref error) = **msg {
+            let pending = error.return_code.as_ref()
+                .and_then(|code| self.commands.remove(code));
+            if let Some(pending) = pending {
+                let res = if error.id == errors::Error::Ok {
+                    Ok(pending.responses)
+                } else {
+                    Err(Error::Command(error.id))
+                };
+                // Ignore the error if nobody waits for the answer anymore
+                let _ = pending.sender.send(res);
+            }
+        } else if let Some(code) = msg.get_return_code() {
+            if let Some(pending) = self.commands.get_mut(code) {
+                pending.responses.push(msg.clone());
+            }
+        }
+    }
+
+    /// Forget all commands, their receivers will return an error.
+    fn clear(&mut self) {
+        self.commands.clear();
+    }
+}
+
 pub struct NetworkWrapper {
     connection: Connection,
     pub client_data: Rc<RefCell<client::ClientData>>,
     pub client_connection: Weak<RefCell<client::ClientConnection>>,
     pub inner_stream: Box<Stream<Item = (SocketAddr, Message),
         Error = tsproto_error>>,
+    /// The options which are used to reconnect.
+    options: ConnectOptions,
+    pub state: ConnectionState,
+    /// Set if the connection is closed by us, it will not be reconnected then.
+    pub disconnecting: bool,
+    /// Why the connection was closed, `None` while it is established.
+    pub disconnect_reason: Option<DisconnectReason>,
+    reconnect: Option<Reconnect>,
+    /// The task which polls this stream.
+    task: Rc<RefCell<Option<Task>>>,
+    /// Events which were raised by this connection and were not yet sent.
+    pub events: Vec<Event>,
+    /// Commands which wait for an answer.
+    pending_commands: PendingCommands,
+    /// The received text messages, the oldest message comes first.
+    pub chat_history: Vec<ChatEntry>,
+    /// The id which will be used for the next file transfer.
+    pub next_file_transfer_id: u16,
+    /// The files which were returned by the last listing of each directory.
+    pub files: Vec<File>,
 }
 
 impl NetworkWrapper {
@@ -191,17 +345,142 @@ impl NetworkWrapper {
         client_data: Rc<RefCell<client::ClientData>>,
         client_connection: Weak<RefCell<client::ClientConnection>>,
         initserver: &InitServer,
+        options: ConnectOptions,
     ) -> Self {
         let connection = Connection::new(id, Uid(String::from("TODO")),
             initserver);
-        let inner_stream = ::codec::CommandCodec::new_stream(&client_data);
+        let task = Rc::new(RefCell::new(None));
+        let inner_stream = Self::setup_client(&client_data, &task);
         Self {
             connection,
             client_data,
             client_connection,
             inner_stream,
+            options,
+            state: ConnectionState::Connected,
+            disconnecting: false,
+            disconnect_reason: None,
+            reconnect: None,
+            task,
+            events: Vec::new(),
+            pending_commands: PendingCommands::default(),
+            chat_history: Vec::new(),
+            next_file_transfer_id: 0,
+            files: Vec::new(),
+        }
+    }
+
+    /// Register a command which will be sent to the server.
+    ///
+    /// Returns the return code, which has to be attached to the command, and
+    /// a receiver for the answer of the server.
+    pub fn add_pending_command(&mut self) -> (String,
+        oneshot::Receiver<Result<Vec<Box<messages::Message>>>>) {
+        self.pending_commands.add()
+    }
+
+    /// Replace the stored files of a directory with a new listing.
+    pub fn set_file_list(&mut self, channel: ChannelId, path: &str,
+        list: &[FileInfo]) {
+        self.files.retain(|f| f.channel_id != channel || f.path != path);
+        self.files.extend(list.iter().map(File::from));
+    }
+
+    /// Add received text messages to the chat history.
+    fn handle_chat(&mut self, msg: &Message) {
+        if let Message::Message(ref msg) = *msg {
+            if let messages::Message::TextMessage(ref cmd) = **msg {
+                if self.options.chat_history == 0 {
+                    return;
+                }
+                if self.chat_history.len() >= self.options.chat_history {
+                    let remove = self.chat_history.len()
+                        - self.options.chat_history + 1;
+                    self.chat_history.drain(..remove);
+                }
+                self.chat_history.push(ChatEntry {
+                    sender: cmd.invoker_id,
+                    text: cmd.message.clone(),
+                    date: Utc::now(),
+                    mode: cmd.target_mode,
+                });
+            }
         }
     }
+
+    /// Remember the reason if the server removed our own client.
+    fn handle_disconnect(&mut self, msg: &Message) {
+        if let Message::Message(ref msg) = *msg {
+            if let messages::Message::ClientLeftView(ref cmd) = **msg {
+                if cmd.client_id != self.own_client {
+                    return;
+                }
+                self.disconnect_reason = Some(disconnect_reason(cmd));
+            }
+        }
+    }
+
+    /// Raise the last event of this connection and mark it as closed.
+    fn close(&mut self) {
+        let reason = self.disconnect_reason.get_or_insert_with(||
+            DisconnectReason::ConnectionLost).clone();
+        self.events.push(Event::Disconnected(reason));
+        self.set_state(ConnectionState::Disconnected);
+    }
+
+    /// Creates the stream of messages and registers for the removal of the
+    /// connection.
+    fn setup_client(client_data: &Rc<RefCell<client::ClientData>>,
+        task: &Rc<RefCell<Option<Task>>>) -> Box<Stream<
+        Item = (SocketAddr, Message), Error = tsproto_error>> {
+        client_data.borrow_mut().connection_listeners.push(Box::new(
+            RemoveListener { task: task.clone() }));
+        ::codec::CommandCodec::new_stream(client_data)
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            let old = mem::replace(&mut self.state, state);
+            self.events.push(Event::ConnectionStateChanged {
+                old,
+                new: state,
+            });
+        }
+    }
+
+    /// Start the next attempt to reconnect.
+    ///
+    /// Returns `false` if no further attempt should be made.
+    fn start_reconnect(&mut self, attempt: u32) -> bool {
+        let delay = match self.options.reconnect {
+            Some(ref r) if r.should_try(attempt) => r.get_delay(attempt),
+            _ => return false,
+        };
+        let (handle, logger) = {
+            let data = self.client_data.borrow();
+            (data.handle.clone(), data.logger.clone())
+        };
+        info!(logger, "Reconnecting"; "attempt" => attempt,
+            "delay" => %delay);
+        let timeout = match Timeout::new(delay.to_std().unwrap_or_default(),
+            &handle) {
+            Ok(t) => t,
+            Err(error) => {
+                warn!(logger, "Cannot create reconnect timer";
+                    "error" => ?error);
+                return false;
+            }
+        };
+        let options = self.options.clone();
+        self.reconnect = Some(Reconnect {
+            attempt,
+            // The server may require a higher security level by now
+            future: Box::new(timeout.map_err(|e| e.into()).and_then(move |()|
+                ::connect_with_timeout(handle, logger, options))),
+        });
+        self.set_state(ConnectionState::Reconnecting { attempt });
+        true
+    }
 }
 
 impl Deref for NetworkWrapper {
@@ -222,14 +501,411 @@ impl Stream for NetworkWrapper {
     type Item = (SocketAddr, Message);
     type Error = tsproto_error;
 
+    /// Returns `None` if the connection is closed and should be removed.
     fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
-        let res = self.inner_stream.poll()?;
-        if let futures::Async::Ready(Some((_, ref msg))) = res {
-            if let Err(error) = self.connection.handle_message(msg) {
-                warn!(self.client_data.borrow().logger,
-                    "Error when handling message"; "error" => ?error);
+        *self.task.borrow_mut() = Some(task::current());
+        loop {
+            if let Some(mut reconnect) = self.reconnect.take() {
+                match reconnect.future.poll() {
+                    Ok(futures::Async::Ready((client_data, client_connection,
+                        initserver, options))) => {
+                        // Keep the improved identity for the next reconnect
+                        self.options = options;
+                        // Replace the old connection
+                        let id = self.connection.id;
+                        self.connection = Connection::new(id,
+                            Uid(String::from("TODO")), &initserver);
+                        self.inner_stream = Self::setup_client(&client_data,
+                            &self.task);
+                        self.client_data = client_data;
+                        self.client_connection = client_connection;
+                        // The old connection will not answer our commands
+                        self.pending_commands.clear();
+                        self.disconnect_reason = None;
+                        self.set_state(ConnectionState::Connected);
+                    }
+                    Ok(futures::Async::NotReady) => {
+                        self.reconnect = Some(reconnect);
+                        return Ok(futures::Async::NotReady);
+                    }
+                    Err(error) => {
+                        warn!(self.client_data.borrow().logger,
+                            "Reconnecting failed"; "error" => ?error,
+                            "attempt" => reconnect.attempt);
+                        if !self.start_reconnect(reconnect.attempt + 1) {
+                            self.close();
+                            return Ok(futures::Async::Ready(None));
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            match self.inner_stream.poll()? {
+                futures::Async::Ready(Some((addr, msg))) => {
+                    if let Err(error) = self.connection.handle_message(&msg,
+                        &mut self.events) {
+                        warn!(self.client_data.borrow().logger,
+                            "Error when handling message"; "error" => ?error);
+                    }
+                    self.pending_commands.handle_response(&msg);
+                    self.handle_chat(&msg);
+                    self.handle_disconnect(&msg);
+                    return Ok(futures::Async::Ready(Some((addr, msg))));
+                }
+                futures::Async::NotReady => {
+                    // Check if the connection is still alive
+                    if self.client_connection.upgrade().is_some() {
+                        return Ok(futures::Async::NotReady);
+                    }
+                }
+                futures::Async::Ready(None) => {}
+            }
+
+            // The connection is closed
+            if self.disconnecting {
+                self.disconnect_reason = Some(DisconnectReason::Requested);
             }
+            // Only reconnect if the server did not remove us on purpose
+            let lost = match self.disconnect_reason {
+                None | Some(DisconnectReason::ConnectionLost) => true,
+                _ => false,
+            };
+            if lost {
+                self.disconnect_reason = Some(DisconnectReason::ConnectionLost);
+                if self.start_reconnect(1) {
+                    continue;
+                }
+            }
+            self.close();
+            return Ok(futures::Async::Ready(None));
+        }
+    }
+}
+
+/// Decode why the server removed our own client.
+fn disconnect_reason(cmd: &messages::ClientLeftView) -> DisconnectReason {
+    let message = cmd.reason_message.clone();
+    match cmd.reason {
+        Reason::KickServer => DisconnectReason::Kicked {
+            invoker: cmd.invoker_id,
+            invoker_name: cmd.invoker_name.clone(),
+            message,
+        },
+        Reason::KickServerBan => DisconnectReason::Banned {
+            invoker: cmd.invoker_id,
+            invoker_name: cmd.invoker_name.clone(),
+            // A ban time of 0 means a permanent ban
+            duration: cmd.ban_time.filter(|d| *d > Duration::zero()),
+            message,
+        },
+        Reason::Serverstop
+        | Reason::ClientdisconnectServerShutdown =>
+            DisconnectReason::ServerShutdown { message },
+        Reason::LostConnection => DisconnectReason::ConnectionLost,
+        reason => DisconnectReason::Other { reason, message },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::ToPrimitive;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils::*;
+
+    fn connection() -> Connection {
+        Connection::new(ConnectionId(0), Uid(String::from("server")),
+            &init_server())
+    }
+
+    fn handle(con: &mut Connection, msg: &str) -> Vec<Event> {
+        let mut events = Vec::new();
+        con.handle_message(&message(msg), &mut events).unwrap();
+        events
+    }
+
+    #[test]
+    fn client_events() {
+        let mut con = connection();
+        let events = handle(&mut con, CLIENT_ENTER_VIEW);
+        assert!(con.server.clients.contains_key(&ClientId(5)));
+        assert!(events.iter().any(|e| match *e {
+            Event::ClientEntered(ClientId(5)) => true,
+            _ => false,
+        }), "{:?}", events);
+
+        let events = handle(&mut con,
+            "notifyclientmoved ctid=2 reasonid=0 clid=5");
+        assert_eq!(con.server.clients[&ClientId(5)].channel, ChannelId(2));
+        assert!(events.iter().any(|e| match *e {
+            Event::PropertyChanged {
+                id: PropertyId::ClientChannel(ClientId(5)),
+                old: PropertyValue::ClientChannel(ChannelId(1)),
+                new: PropertyValue::ClientChannel(ChannelId(2)),
+            } => true,
+            _ => false,
+        }), "{:?}", events);
+        assert!(events.iter().any(|e| match *e {
+            Event::ClientMoved {
+                client: ClientId(5),
+                from: ChannelId(1),
+                to: ChannelId(2),
+            } => true,
+            _ => false,
+        }), "{:?}", events);
+
+        // Moving into the same channel changes nothing
+        let events = handle(&mut con,
+            "notifyclientmoved ctid=2 reasonid=0 clid=5");
+        assert!(!events.iter().any(|e| match *e {
+            Event::PropertyChanged { .. } | Event::ClientMoved { .. } => true,
+            _ => false,
+        }), "{:?}", events);
+
+        let events = handle(&mut con, "notifyclientleftview cfid=2 ctid=0 \
+            reasonid=8 reasonmsg=Bye clid=5");
+        assert!(!con.server.clients.contains_key(&ClientId(5)));
+        assert!(events.iter().any(|e| match *e {
+            Event::ClientLeft(ClientId(5)) => true,
+            _ => false,
+        }), "{:?}", events);
+    }
+
+    #[test]
+    fn channel_events() {
+        let mut con = connection();
+        let events = handle(&mut con, "notifychannelcreated cid=3 cpid=0 \
+            channel_name=Channel channel_topic channel_codec=4 \
+            channel_codec_quality=6 channel_maxclients=10 \
+            channel_maxfamilyclients=10 channel_order=1 \
+            channel_flag_permanent=1 channel_flag_semi_permanent=0 \
+            channel_flag_default=0 channel_flag_password=0 \
+            channel_codec_latency_factor=1 channel_codec_is_unencrypted=1 \
+            channel_delete_delay=0 channel_flag_maxclients_unlimited=0 \
+            channel_flag_maxfamilyclients_unlimited=0 \
+            channel_flag_maxfamilyclients_inherited=1 \
+            channel_needed_talk_power=0 channel_name_phonetic \
+            channel_icon_id=0 channel_flag_private=0 invokerid=5 \
+            invokername=Test invokeruid=abc=");
+        assert!(con.server.channels.contains_key(&ChannelId(3)));
+        assert!(events.iter().any(|e| match *e {
+            Event::ChannelCreated(ChannelId(3)) => true,
+            _ => false,
+        }), "{:?}", events);
+
+        let events = handle(&mut con, "notifychanneldeleted cid=3 \
+            invokerid=5 invokername=Test invokeruid=abc=");
+        assert!(!con.server.channels.contains_key(&ChannelId(3)));
+        assert!(events.iter().any(|e| match *e {
+            Event::ChannelDeleted(ChannelId(3)) => true,
+            _ => false,
+        }), "{:?}", events);
+    }
+
+    #[test]
+    fn text_message_event() {
+        let mut con = connection();
+        let events = handle(&mut con, "notifytextmessage targetmode=2 \
+            msg=Hello\\sworld invokerid=5 invokername=Test invokeruid=abc=");
+        assert_eq!(events.len(), 1, "{:?}", events);
+        if let Event::TextMessage {
+            target: TextMessageTargetMode::Channel,
+            from: ClientId(5),
+            ref message,
+        } = events[0] {
+            assert_eq!(message, "Hello world");
+        } else {
+            panic!("Unexpected event {:?}", events[0]);
+        }
+    }
+
+    /// Wait for the answer to a command, which must be available already.
+    fn answer(receiver: oneshot::Receiver<Result<Vec<Box<messages::Message>>>>)
+        -> Result<Vec<Box<messages::Message>>> {
+        receiver.wait().expect("The command was not answered")
+    }
+
+    #[test]
+    fn command_success() {
+        let mut pending = PendingCommands::default();
+        let (code, receiver) = pending.add();
+        assert_eq!(code, "0");
+        pending.handle_response(&message("notifyfilelist cid=1 path=\\/ \
+            name=file size=5 datetime=1500000000 type=1 return_code=0"));
+        // Messages without our return code are not collected
+        pending.handle_response(&message("notifyfilelist cid=1 path=\\/ \
+            name=other size=5 datetime=1500000000 type=1"));
+        pending.handle_response(&message("error id=0 msg=ok return_code=0"));
+
+        let responses = answer(receiver).unwrap();
+        assert_eq!(responses.len(), 1);
+        if let messages::Message::FileList(ref f) = *responses[0] {
+            assert_eq!(f.name, "file");
+        } else {
+            panic!("Unexpected response {:?}", responses[0]);
+        }
+        assert!(pending.commands.is_empty());
+    }
+
+    #[test]
+    fn command_error() {
+        let mut pending = PendingCommands::default();
+        let (code, receiver) = pending.add();
+        pending.handle_response(&message(&format!(
+            "error id=512 msg=invalid\\sclientID return_code={}", code)));
+        match answer(receiver) {
+            Err(Error::Command(id)) => assert_ne!(id, errors::Error::Ok),
+            res => panic!("Expected a command error, got {:?}", res),
+        }
+        assert!(pending.commands.is_empty());
+    }
+
+    #[test]
+    fn commands_answered_out_of_order() {
+        let mut pending = PendingCommands::default();
+        let (code0, receiver0) = pending.add();
+        let (code1, receiver1) = pending.add();
+        assert_ne!(code0, code1);
+
+        pending.handle_response(&message(&format!(
+            "error id=512 msg=invalid\\sclientID return_code={}", code1)));
+        // Only the second command is finished
+        assert!(pending.commands.contains_key(&code0));
+        assert!(!pending.commands.contains_key(&code1));
+        assert!(answer(receiver1).is_err());
+
+        pending.handle_response(&message(&format!(
+            "error id=0 msg=ok return_code={}", code0)));
+        assert!(answer(receiver0).unwrap().is_empty());
+        assert!(pending.commands.is_empty());
+    }
+
+    #[test]
+    fn unknown_return_code_ignored() {
+        let mut pending = PendingCommands::default();
+        let (code, _receiver) = pending.add();
+        pending.handle_response(&message("error id=0 msg=ok return_code=42"));
+        pending.handle_response(&message("error id=0 msg=ok"));
+        assert!(pending.commands.contains_key(&code));
+    }
+
+    fn text_message(sender: u16, text: &str) -> Message {
+        message(&format!("notifytextmessage targetmode={} msg={} \
+            invokerid={} invokername=Test invokeruid=abc=",
+            TextMessageTargetMode::Channel.to_u32().unwrap(), text, sender))
+    }
+
+    fn chat_wrapper(core: &Core, chat_history: usize) -> NetworkWrapper {
+        let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+        network_wrapper(&core.handle(), ConnectionId(0),
+            ConnectOptions::from_address(addr).chat_history(chat_history))
+    }
+
+    #[test]
+    fn chat_history_recorded() {
+        let core = Core::new().unwrap();
+        let mut con = chat_wrapper(&core, 10);
+        let before = Utc::now();
+        con.handle_chat(&text_message(5, "Hello"));
+        // Other messages are ignored
+        con.handle_chat(&message(CLIENT_ENTER_VIEW));
+
+        assert_eq!(con.chat_history.len(), 1);
+        let entry = &con.chat_history[0];
+        assert_eq!(entry.sender, ClientId(5));
+        assert_eq!(entry.text, "Hello");
+        assert_eq!(entry.mode, TextMessageTargetMode::Channel);
+        assert!(entry.date >= before && entry.date <= Utc::now());
+    }
+
+    #[test]
+    fn chat_history_limit() {
+        let core = Core::new().unwrap();
+        let mut con = chat_wrapper(&core, 2);
+        for text in &["first", "second", "third"] {
+            con.handle_chat(&text_message(5, text));
+        }
+        // The oldest message was removed
+        let texts: Vec<_> = con.chat_history.iter().map(|e| e.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["second", "third"]);
+
+        let mut con = chat_wrapper(&core, 0);
+        con.handle_chat(&text_message(5, "Hello"));
+        assert!(con.chat_history.is_empty());
+    }
+
+    /// Our own client (id 2) was removed from the server.
+    fn left(reason: Reason, extra: &str) -> DisconnectReason {
+        let msg = format!("notifyclientleftview cfid=1 ctid=0 reasonid={} \
+            clid=2{}", reason.to_u32().unwrap(), extra);
+        if let messages::Message::ClientLeftView(cmd) = *parse_message(&msg) {
+            disconnect_reason(&cmd)
+        } else {
+            panic!("Expected a notifyclientleftview");
+        }
+    }
+
+    #[test]
+    fn disconnect_kicked() {
+        match left(Reason::KickServer, " invokerid=5 invokername=Test \
+            reasonmsg=Bye") {
+            DisconnectReason::Kicked { invoker, invoker_name, message } => {
+                assert_eq!(invoker, Some(ClientId(5)));
+                assert_eq!(invoker_name.as_ref().map(|s| s.as_str()),
+                    Some("Test"));
+                assert_eq!(message.as_ref().map(|s| s.as_str()), Some("Bye"));
+            }
+            r => panic!("Unexpected reason {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_banned() {
+        match left(Reason::KickServerBan, " invokerid=5 invokername=Test \
+            bantime=600") {
+            DisconnectReason::Banned { invoker, duration, .. } => {
+                assert_eq!(invoker, Some(ClientId(5)));
+                assert_eq!(duration, Some(Duration::seconds(600)));
+            }
+            r => panic!("Unexpected reason {:?}", r),
+        }
+        // A ban time of 0 is a permanent ban
+        match left(Reason::KickServerBan, " invokerid=5 invokername=Test \
+            bantime=0") {
+            DisconnectReason::Banned { duration: None, .. } => {}
+            r => panic!("Unexpected reason {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_shutdown() {
+        match left(Reason::Serverstop, " reasonmsg=Maintenance") {
+            DisconnectReason::ServerShutdown { message } => assert_eq!(
+                message.as_ref().map(|s| s.as_str()), Some("Maintenance")),
+            r => panic!("Unexpected reason {:?}", r),
+        }
+        match left(Reason::ClientdisconnectServerShutdown, "") {
+            DisconnectReason::ServerShutdown { .. } => {}
+            r => panic!("Unexpected reason {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_timeout() {
+        match left(Reason::LostConnection, "") {
+            DisconnectReason::ConnectionLost => {}
+            r => panic!("Unexpected reason {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_other() {
+        match left(Reason::Channeledit, "") {
+            DisconnectReason::Other { reason: Reason::Channeledit, .. } => {}
+            r => panic!("Unexpected reason {:?}", r),
         }
-        Ok(res)
     }
 }
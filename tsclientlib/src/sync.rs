@@ -0,0 +1,373 @@
+//! A handle to a [`ConnectionManager`] which can be used from other threads.
+//!
+//! The `ConnectionManager` and all connections live on the thread of their
+//! reactor. A [`SyncConnectionManager`] is `Send + Sync`, it sends requests
+//! through a channel to the reactor and returns futures which resolve to the
+//! answers. The futures do not need a reactor, so they can be waited on from
+//! any thread.
+//!
+//! A handle is created for an existing `ConnectionManager` with
+//! [`ConnectionManager::get_sync_handle`]. The requests are handled while the
+//! future of [`ConnectionManager::run`] is polled.
+//!
+//! [`SyncConnectionManager::spawn`] starts a new thread with a reactor and a
+//! `ConnectionManager`, which runs until all handles are dropped:
+//!
+//! ```rust,no_run
+//! # extern crate futures;
+//! # extern crate tsclientlib;
+//! #
+//! # use futures::Future;
+//! # use tsclientlib::ConnectOptions;
+//! # use tsclientlib::sync::SyncConnectionManager;
+//! # fn main() {
+//! #
+//! let cm = SyncConnectionManager::spawn().unwrap();
+//! let con_id = cm.connect(|| ConnectOptions::from_address("localhost"))
+//!     .wait().unwrap();
+//! let snapshot = cm.get_snapshot(con_id).wait().unwrap();
+//! println!("Connection state: {:?}", snapshot.state);
+//! cm.disconnect(con_id, None).wait().unwrap();
+//! # }
+//! ```
+//!
+//! [`ConnectionManager`]: ../struct.ConnectionManager.html
+//! [`SyncConnectionManager`]: struct.SyncConnectionManager.html
+//! [`SyncConnectionManager::spawn`]: struct.SyncConnectionManager.html#method.spawn
+//! [`ConnectionManager::get_sync_handle`]: ../struct.ConnectionManager.html#method.get_sync_handle
+//! [`ConnectionManager::run`]: ../struct.ConnectionManager.html#method.run
+
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+
+use futures::{self, future, Future, Stream};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::{Core, Handle};
+use tsproto::commands;
+use tsproto_commands::ConnectionId;
+use tsproto_commands::messages;
+
+use {BoxFuture, ConnectOptions, ConnectionManager, ConnectionState,
+    DisconnectOptions, DisconnectReason, Error, Result, TextMessageTarget};
+use codec::AudioPacket;
+use data;
+
+/// A future which can be sent to other threads.
+pub type SyncFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// A request which is sent to the reactor thread.
+pub(crate) enum Request {
+    /// Options are created on the reactor thread because they can contain a
+    /// resolver, which cannot be sent to another thread.
+    Connect(Box<FnMut() -> ConnectOptions + Send>,
+        oneshot::Sender<Result<ConnectionId>>),
+    Disconnect(ConnectionId, Option<DisconnectOptions>,
+        oneshot::Sender<Result<()>>),
+    SendAudio(ConnectionId, AudioPacket, oneshot::Sender<Result<()>>),
+    SendTextMessage(ConnectionId, TextMessageTarget, String,
+        oneshot::Sender<Result<()>>),
+    SendCommand(ConnectionId, commands::Command,
+        oneshot::Sender<Result<Vec<Box<messages::Message>>>>),
+    GetConnections(oneshot::Sender<Result<Vec<ConnectionId>>>),
+    GetSnapshot(ConnectionId, oneshot::Sender<Result<Snapshot>>),
+}
+
+/// A copy of the state of a connection.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub state: ConnectionState,
+    /// The reason why the connection was closed, `None` while it is
+    /// established.
+    pub disconnect_reason: Option<DisconnectReason>,
+    /// The bookkeeping of the connection, which contains the server, its
+    /// channels and clients.
+    pub book: data::Connection,
+    /// The received text messages, the oldest message comes first.
+    pub chat_history: Vec<data::ChatEntry>,
+}
+
+/// A handle to a `ConnectionManager` which can be cloned and sent to other
+/// threads.
+///
+/// All functions send a request to the reactor thread and return a future
+/// which resolves to the answer. The futures fail if the `ConnectionManager`
+/// does not exist anymore.
+#[derive(Clone)]
+pub struct SyncConnectionManager {
+    requests: mpsc::UnboundedSender<Request>,
+    /// Stops the thread of a spawned `ConnectionManager` when the last handle
+    /// is dropped.
+    stop: Option<Arc<oneshot::Sender<()>>>,
+}
+
+impl fmt::Debug for SyncConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SyncConnectionManager(...)")
+    }
+}
+
+impl SyncConnectionManager {
+    /// Create a handle which sends its requests to `requests`.
+    pub(crate) fn new(requests: mpsc::UnboundedSender<Request>) -> Self {
+        Self { requests, stop: None }
+    }
+
+    /// Start a new thread with a reactor and a `ConnectionManager`.
+    ///
+    /// The thread runs until all handles are dropped. Connections which are
+    /// still open at that time are closed without sending a disconnect
+    /// message, so they should be removed with [`disconnect`] first.
+    ///
+    /// [`disconnect`]: #method.disconnect
+    pub fn spawn() -> Result<Self> {
+        let (send, recv) = ::std::sync::mpsc::channel();
+        thread::Builder::new().name(String::from("tsclientlib"))
+            .spawn(move || {
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(error) => {
+                        let _ = send.send(Err(error.into()));
+                        return;
+                    }
+                };
+                let mut cm = ConnectionManager::new(core.handle());
+                let (stop_send, stop_recv) = oneshot::channel();
+                let mut handle = cm.get_sync_handle();
+                handle.stop = Some(Arc::new(stop_send));
+                if send.send(Ok(handle)).is_err() {
+                    return;
+                }
+
+                // Run until all handles are dropped
+                let _ = core.run(cm.run().select2(stop_recv));
+            })?;
+
+        recv.recv().unwrap_or_else(|_| Err(format_err!(
+            "The connection manager thread exited").into()))
+    }
+
+    /// Send a request and wait for the answer.
+    fn request<T, F>(&self, f: F) -> SyncFuture<T>
+        where T: Send + 'static,
+              F: FnOnce(oneshot::Sender<Result<T>>) -> Request {
+        let (send, recv) = oneshot::channel();
+        if self.requests.unbounded_send(f(send)).is_err() {
+            return Box::new(future::err(format_err!(
+                "The connection manager does not exist anymore").into()));
+        }
+        Box::new(recv.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err(format_err!(
+                "The connection manager does not exist anymore").into()),
+        }))
+    }
+
+    /// Connect to a server.
+    ///
+    /// The options are created by `options` on the reactor thread. See
+    /// [`ConnectionManager::add_connection`] for more information.
+    ///
+    /// [`ConnectionManager::add_connection`]: ../struct.ConnectionManager.html#method.add_connection
+    pub fn connect<F>(&self, options: F) -> SyncFuture<ConnectionId>
+        where F: FnOnce() -> ConnectOptions + Send + 'static {
+        // Box<FnOnce> cannot be called, so use an FnMut
+        let mut options = Some(options);
+        let options = Box::new(move || (options.take()
+            .expect("Connect options were already created"))());
+        self.request(|send| Request::Connect(options, send))
+    }
+
+    /// Disconnect from a server.
+    ///
+    /// See [`ConnectionManager::remove_connection`] for more information.
+    ///
+    /// [`ConnectionManager::remove_connection`]: ../struct.ConnectionManager.html#method.remove_connection
+    pub fn disconnect<O: Into<Option<DisconnectOptions>>>(&self,
+        id: ConnectionId, options: O) -> SyncFuture<()> {
+        let options = options.into();
+        self.request(|send| Request::Disconnect(id, options, send))
+    }
+
+    /// Send a voice packet to the server.
+    pub fn send_audio(&self, id: ConnectionId, packet: AudioPacket)
+        -> SyncFuture<()> {
+        self.request(|send| Request::SendAudio(id, packet, send))
+    }
+
+    /// Send a text message to a client, our channel or the server.
+    ///
+    /// The returned future resolves when the server accepted the message.
+    pub fn send_text_message<S: Into<String>>(&self, id: ConnectionId,
+        target: TextMessageTarget, message: S) -> SyncFuture<()> {
+        let message = message.into();
+        self.request(|send| Request::SendTextMessage(id, target, message,
+            send))
+    }
+
+    /// Send a command to the server and wait for its answer.
+    ///
+    /// See [`ConnectionMut::send_command`] for more information.
+    ///
+    /// [`ConnectionMut::send_command`]: ../struct.ConnectionMut.html#method.send_command
+    pub fn send_command(&self, id: ConnectionId, command: commands::Command)
+        -> SyncFuture<Vec<Box<messages::Message>>> {
+        self.request(|send| Request::SendCommand(id, command, send))
+    }
+
+    /// The ids of all connections.
+    pub fn get_connections(&self) -> SyncFuture<Vec<ConnectionId>> {
+        self.request(Request::GetConnections)
+    }
+
+    /// Get a copy of the current state and bookkeeping of a connection.
+    pub fn get_snapshot(&self, id: ConnectionId) -> SyncFuture<Snapshot> {
+        self.request(|send| Request::GetSnapshot(id, send))
+    }
+}
+
+/// Fails to compile if a `SyncConnectionManager` cannot be shared between
+/// threads.
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<SyncConnectionManager>();
+}
+
+/// Send the result of `fut` to `send` when it is finished.
+fn spawn_answer<T: 'static>(handle: &Handle, fut: BoxFuture<T>,
+    send: oneshot::Sender<Result<T>>) {
+    handle.spawn(fut.then(move |res| {
+        // Ignore it if nobody waits for the answer
+        let _ = send.send(res);
+        Ok(())
+    }));
+}
+
+fn no_connection(id: ConnectionId) -> Error {
+    format_err!("Connection {:?} does not exist", id).into()
+}
+
+impl ConnectionManager {
+    /// Handle the requests of all `SyncConnectionManager`s.
+    ///
+    /// The current task is notified when new requests arrive.
+    pub(crate) fn poll_sync_requests(&mut self) {
+        let mut requests = Vec::new();
+        {
+            let mut inner = self.inner.borrow_mut();
+            let mut i = 0;
+            while i < inner.sync_requests.len() {
+                match inner.sync_requests[i].poll() {
+                    Ok(futures::Async::Ready(Some(request))) =>
+                        requests.push(request),
+                    Ok(futures::Async::NotReady) => i += 1,
+                    // All handles of this channel were dropped
+                    Ok(futures::Async::Ready(None)) | Err(()) => {
+                        inner.sync_requests.remove(i);
+                    }
+                }
+            }
+        }
+
+        for request in requests {
+            self.handle_sync_request(request);
+        }
+    }
+
+    fn handle_sync_request(&mut self, request: Request) {
+        let handle = self.inner.borrow().handle.clone();
+        match request {
+            Request::Connect(mut options, send) => {
+                match self.connect_future(options()) {
+                    Ok(fut) => spawn_answer(&handle, fut, send),
+                    Err(error) => {
+                        let _ = send.send(Err(error));
+                    }
+                }
+            }
+            Request::Disconnect(id, options, send) => {
+                match self.disconnect_future(id, options) {
+                    Some(fut) => spawn_answer(&handle, fut, send),
+                    None => {
+                        let _ = send.send(Ok(()));
+                    }
+                }
+            }
+            Request::SendAudio(id, packet, send) => {
+                let fut = match self.get_mut_connection(id) {
+                    Some(mut con) => con.send_audio(packet),
+                    None => Box::new(future::err(no_connection(id))),
+                };
+                spawn_answer(&handle, fut, send);
+            }
+            Request::SendTextMessage(id, target, message, send) => {
+                let fut = match self.get_mut_connection(id) {
+                    Some(mut con) => con.send_text_message(target, &message),
+                    None => Box::new(future::err(no_connection(id))),
+                };
+                spawn_answer(&handle, fut, send);
+            }
+            Request::SendCommand(id, command, send) => {
+                let fut = match self.get_mut_connection(id) {
+                    Some(mut con) => con.send_command(command),
+                    None => Box::new(future::err(no_connection(id))),
+                };
+                spawn_answer(&handle, fut, send);
+            }
+            Request::GetConnections(send) => {
+                let ids = self.inner.borrow().connections.keys().cloned()
+                    .collect();
+                let _ = send.send(Ok(ids));
+            }
+            Request::GetSnapshot(id, send) => {
+                let inner = self.inner.borrow();
+                let res = match inner.connections.get(&id) {
+                    Some(con) => Ok(Snapshot {
+                        state: con.state,
+                        disconnect_reason: con.disconnect_reason.clone(),
+                        book: (**con).clone(),
+                        chat_history: con.chat_history.clone(),
+                    }),
+                    None => Err(no_connection(id)),
+                };
+                let _ = send.send(res);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+
+    use chrono::Duration;
+    use tsproto::commands::Command;
+
+    use super::*;
+
+    #[test]
+    fn requests_from_other_thread() {
+        // A server which never answers
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = socket.local_addr().unwrap();
+
+        let cm = SyncConnectionManager::spawn().unwrap();
+        let cm2 = cm.clone();
+        thread::spawn(move || {
+            assert!(cm2.get_connections().wait().unwrap().is_empty());
+
+            let res = cm2.connect(move || ConnectOptions::from_address(addr)
+                .connect_timeout(Duration::milliseconds(100))).wait();
+            assert!(res.is_err(), "Connected to a silent server");
+            assert!(cm2.get_connections().wait().unwrap().is_empty());
+
+            let id = ConnectionId(0);
+            assert!(cm2.send_command(id, Command::new("clientupdate")).wait()
+                .is_err());
+            assert!(cm2.get_snapshot(id).wait().is_err());
+            assert!(cm2.disconnect(id, None).wait().is_ok());
+        }).join().unwrap();
+        drop(socket);
+    }
+}
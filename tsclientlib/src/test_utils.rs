@@ -0,0 +1,125 @@
+//! Messages of a TeamSpeak server and helpers, which are used in tests.
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::Duration as StdDuration;
+
+use futures::Future;
+use futures::future::Either;
+use slog::{self, Logger};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tsproto::{client, crypto};
+use tsproto::commands::Command;
+use tsproto::connectionmanager::SocketConnectionManager;
+use tsproto_commands::ConnectionId;
+use tsproto_commands::messages::{self, InitServer};
+
+use {ConnectOptions, Error};
+use codec::Message;
+use structs::NetworkWrapper;
+
+/// The `initserver` of a server, which assigned the client id 2 to us.
+pub const INIT_SERVER: &str = "initserver \
+    virtualserver_welcomemessage=Welcome virtualserver_platform=Linux \
+    virtualserver_version=3.0.13.8\\s[Build:\\s1500452811] \
+    virtualserver_maxclients=32 virtualserver_created=1500000000 \
+    virtualserver_codec_encryption_mode=0 virtualserver_hostmessage \
+    virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 \
+    virtualserver_default_channel_group=8 virtualserver_hostbanner_url \
+    virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 \
+    virtualserver_priority_speaker_dimm_modificator=-18.0000 \
+    virtualserver_id=1 virtualserver_hostbutton_tooltip \
+    virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url \
+    virtualserver_name_phonetic virtualserver_icon_id=0 \
+    virtualserver_ip=0.0.0.0,\\s:: virtualserver_ask_for_privilegekey=0 \
+    virtualserver_hostbanner_mode=0 \
+    virtualserver_channel_temp_delete_delay_default=0 \
+    virtualserver_name=Server acn=tsclientlib aclid=2 pv=6 lt=0 \
+    client_talk_power=75 client_needed_serverquery_view_power=75";
+
+/// Client 5 enters the server into channel 1.
+pub const CLIENT_ENTER_VIEW: &str = "notifycliententerview cfid=0 ctid=1 \
+    reasonid=0 clid=5 client_unique_identifier=abc= client_nickname=Test \
+    client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 \
+    client_input_hardware=1 client_output_hardware=1 client_meta_data \
+    client_is_recording=0 client_database_id=3 client_channel_group_id=8 \
+    client_servergroups=8 client_away=0 client_away_message client_type=0 \
+    client_flag_avatar client_talk_power=75 client_talk_request=0 \
+    client_talk_request_msg client_description client_is_talker=0 \
+    client_is_priority_speaker=0 client_unread_messages=0 \
+    client_nickname_phonetic client_needed_serverquery_view_power=75 \
+    client_icon_id=0 client_is_channel_commander=0 client_country \
+    client_channel_group_inherited_channel_id=1 client_badges";
+
+/// Parse a command, which was sent by the server.
+pub fn parse_message(s: &str) -> Box<messages::Message> {
+    let cmd = Command::read((), &mut s.as_bytes()).unwrap();
+    let msg = messages::Message::parse(cmd.get_commands().remove(0))
+        .unwrap();
+    Box::new(msg)
+}
+
+/// Parse a command, which was sent by the server, as `Message`.
+pub fn message(s: &str) -> Message {
+    Message::Message(parse_message(s))
+}
+
+pub fn init_server() -> InitServer {
+    if let messages::Message::InitServer(packet) = *parse_message(INIT_SERVER)
+    {
+        packet
+    } else {
+        panic!("Expected an initserver");
+    }
+}
+
+/// A client on a local port without connections.
+pub fn client_data(handle: &Handle) -> Rc<RefCell<client::ClientData>> {
+    let client = client::ClientData::new("127.0.0.1:0".parse().unwrap(),
+        crypto::EccKeyPrivP256::create().unwrap(), handle.clone(), true,
+        SocketConnectionManager::new(), logger()).unwrap();
+    {
+        let client2 = client.clone();
+        let mut client = client.borrow_mut();
+        client.connection_manager.set_data_ref(Rc::downgrade(&client2));
+    }
+    client::default_setup(&client, false);
+    client
+}
+
+/// A connection which never connected to a server.
+///
+/// It can be used to test the handling of messages.
+pub fn network_wrapper(handle: &Handle, id: ConnectionId,
+    options: ConnectOptions) -> NetworkWrapper {
+    NetworkWrapper::new(id, client_data(handle), Weak::new(), &init_server(),
+        options)
+}
+
+pub fn logger() -> Logger {
+    Logger::root(slog::Discard, o!())
+}
+
+/// Run a future on the core and fail if it does not finish within 10 seconds.
+pub fn run<F: Future<Error = Error>>(core: &mut Core, f: F) -> F::Item {
+    let timeout = Timeout::new(StdDuration::from_secs(10), &core.handle())
+        .unwrap();
+    match core.run(f.select2(timeout)) {
+        Ok(Either::A((res, _))) => res,
+        Ok(Either::B(_)) => panic!("Timed out"),
+        Err(Either::A((error, _))) => panic!("Failed: {:?}", error),
+        Err(Either::B((error, _))) => panic!("Timer failed: {:?}", error),
+    }
+}
+
+/// Run the core until `f` returns `true` and fail after 10 seconds.
+pub fn run_until<F: FnMut() -> bool>(core: &mut Core, mut f: F) {
+    for _ in 0..1000 {
+        if f() {
+            return;
+        }
+        let timeout = Timeout::new(StdDuration::from_millis(10),
+            &core.handle()).unwrap();
+        core.run(timeout).unwrap();
+    }
+    panic!("Timed out");
+}
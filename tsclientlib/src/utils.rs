@@ -0,0 +1,34 @@
+//! Helpers for non-blocking io on tcp streams, which are shared by the file
+//! transfers and the TSDNS lookup.
+use std::io::{self, Write};
+
+use futures::{Async, Poll};
+use tokio_core::net::TcpStream;
+
+use Error;
+
+/// Convert the result of a non-blocking io operation into a `Poll`.
+pub(crate) fn poll_io<T>(res: io::Result<T>) -> Poll<T, Error> {
+    match res {
+        Ok(t) => Ok(Async::Ready(t)),
+        Err(ref error) if error.kind() == io::ErrorKind::WouldBlock =>
+            Ok(Async::NotReady),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Write the data to the stream, starting at `pos`.
+///
+/// Returns `Ready` when all data is written.
+pub(crate) fn poll_write_all(stream: &mut TcpStream, data: &[u8],
+    pos: &mut usize) -> Poll<(), Error> {
+    while *pos < data.len() {
+        let written = try_ready!(poll_io(stream.write(&data[*pos..])));
+        if written == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero,
+                "Cannot write to the tcp connection").into());
+        }
+        *pos += written;
+    }
+    Ok(Async::Ready(()))
+}
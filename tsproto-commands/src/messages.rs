@@ -51,3 +51,51 @@ pub enum ParseError {
 }
 
 include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_ENTER_VIEW: &str = "notifycliententerview cfid=0 ctid=1 \
+        reasonid=0 clid=5 client_unique_identifier=abc= client_nickname=Test \
+        client_input_muted=1 client_output_muted=0 client_outputonly_muted=0 \
+        client_input_hardware=1 client_output_hardware=0 client_meta_data \
+        client_is_recording=0 client_database_id=3 client_channel_group_id=8 \
+        client_servergroups=8 client_away=1 client_away_message=Away \
+        client_type=0 client_flag_avatar client_talk_power=75 \
+        client_talk_request=0 client_talk_request_msg client_description \
+        client_is_talker=0 client_is_priority_speaker=1 \
+        client_unread_messages=0 client_nickname_phonetic \
+        client_needed_serverquery_view_power=75 client_icon_id=0 \
+        client_is_channel_commander=0 client_country \
+        client_channel_group_inherited_channel_id=1 client_badges";
+
+    fn parse(s: &str) -> Message {
+        let cmd = Command::read((), &mut s.as_bytes()).unwrap();
+        Message::parse(cmd.get_commands().remove(0)).unwrap()
+    }
+
+    #[test]
+    fn bool_round_trip() {
+        let msg = parse(CLIENT_ENTER_VIEW);
+        let cmd: Command = (&msg).into();
+        for &(key, value) in &[
+            ("client_input_muted", "1"),
+            ("client_output_muted", "0"),
+            ("client_input_hardware", "1"),
+            ("client_output_hardware", "0"),
+            ("client_away", "1"),
+            ("client_is_priority_speaker", "1"),
+            ("client_is_talker", "0"),
+        ] {
+            assert_eq!(cmd.get_static_arg(key), Some(value), "{}", key);
+        }
+
+        // Parse the serialized message again
+        let mut written = Vec::new();
+        cmd.write(&mut written).unwrap();
+        let cmd2: Command = parse(::std::str::from_utf8(&written).unwrap())
+            .into();
+        assert_eq!(cmd, cmd2);
+    }
+}
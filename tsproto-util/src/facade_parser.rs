@@ -1,10 +1,12 @@
 use ::*;
 use book_parser::*;
+use message_parser::{generate_serializer, Field};
 
 #[derive(Template)]
 #[TemplatePath = "src/FacadeDeclarations.tt"]
 #[derive(Debug)]
-pub struct FacadeDeclarations(pub BookDeclarations);
+pub struct FacadeDeclarations<'a>(pub &'a BookDeclarations,
+    pub &'a MessageDeclarations);
 
 fn get_return_type(s: &str) -> String {
     if s.starts_with("Option<") {
@@ -35,3 +37,69 @@ fn get_id_args(ids: &[Id], structs: &[Struct], struc: &Struct) -> String {
     }
     res
 }
+
+/// If the `ConnectionManager` returns an `Option` for this struct.
+///
+/// These structs are not part of the normal bookkeeping and can be missing,
+/// e.g. if no message was received from a client yet or if a directory was
+/// not listed.
+fn is_optional_struct(struc: &Struct) -> bool {
+    match struc.name.as_str() {
+        "ChatEntry" | "File" => true,
+        _ => false,
+    }
+}
+
+/// How a struct is changed on the server.
+struct EditCommand {
+    command: &'static str,
+    /// The argument which identifies the struct.
+    id_arg: Option<&'static str>,
+    /// The prefix of the property names.
+    prefix: &'static str,
+    /// The command which is used instead if the struct is our own client.
+    ///
+    /// It takes no id argument.
+    own_command: Option<&'static str>,
+}
+
+/// The command which changes a struct on the server.
+///
+/// Returns an error if the struct cannot be changed by a command.
+fn get_edit_command(struc: &Struct) -> Result<EditCommand, String> {
+    match struc.name.as_str() {
+        "Server" => Ok(EditCommand {
+            command: "serveredit",
+            id_arg: None,
+            prefix: "virtualserver_",
+            own_command: None,
+        }),
+        "Channel" => Ok(EditCommand {
+            command: "channeledit",
+            id_arg: Some("cid"),
+            prefix: "channel_",
+            own_command: None,
+        }),
+        // clientedit is the moderator command to change other clients
+        "Client" => Ok(EditCommand {
+            command: "clientedit",
+            id_arg: Some("clid"),
+            prefix: "client_",
+            own_command: Some("clientupdate"),
+        }),
+        _ => Err(format!("No edit command is known for the struct {}",
+            struc.name)),
+    }
+}
+
+/// Find the field which is sent to the server to change a property.
+fn get_edit_field<'a>(messages: &'a MessageDeclarations, struc: &Struct,
+    p: &Property) -> Option<&'a Field> {
+    let prefix = if let Ok(edit) = get_edit_command(struc) {
+        edit.prefix
+    } else {
+        return None;
+    };
+    messages.fields.iter().find(|f| f.pretty == p.name
+        && f.ts.starts_with(prefix))
+}
@@ -141,3 +141,68 @@ pub fn convert_type(t: &str) -> String {
         t.into()
     }
 }
+
+/// Returns code which converts `val` into the string representation of this
+/// field.
+///
+/// If `can_move` is `false`, `val` is a reference.
+pub(crate) fn generate_serializer(field: &Field, can_move: bool) -> String {
+    let rust_type = field.get_rust_type("");
+    if rust_type.starts_with("Vec<") {
+        vector_value_serializer(field, can_move)
+    } else {
+        single_value_serializer(field, &rust_type, can_move)
+    }
+}
+
+fn single_value_serializer(field: &Field, rust_type: &str, can_move: bool) -> String {
+    match rust_type {
+         "i8" |  "u8" |
+        "i16" | "u16" |
+        "i32" | "u32" |
+        "i64" | "u64" |
+        "f32" | "f64" => "val.to_string()",
+        "bool" => if can_move { "String::from(if  val { \"1\" } else { \"0\" })" }
+                         else { "String::from(if *val { \"1\" } else { \"0\" })" },
+        "String" => if can_move { "val" } else { "val.to_string()" },
+        "Uid" => if can_move { "val.0" } else { "val.0.to_string()" },
+        "ClientId" |
+        "ClientDbId" |
+        "ChannelId" |
+        "ServerGroupId" |
+        "ChannelGroupId" |
+        "IconHash" => "val.0.to_string()",
+        "TextMessageTargetMode" |
+        "HostMessageMode" |
+        "HostBannerMode" |
+        "LicenseType" |
+        "Codec" |
+        "CodecEncryptionMode" |
+        "Reason" |
+        "ClientType" |
+        "GroupNamingMode" |
+        "GroupType" |
+        "Permission" |
+        "Error" => "val.to_u32().unwrap().to_string()",
+        "Duration" =>
+            if field.type_s == "DurationSeconds" {
+                "val.num_seconds().to_string()"
+            } else if field.type_s == "DurationMilliseconds" {
+                "val.num_milliseconds().to_string()"
+            } else {
+                panic!("Unknown original time type {} found.", field.type_s);
+            },
+        "DateTime<Utc>" => "val.timestamp().to_string()",
+        _ => panic!("Unknown type '{}'", rust_type),
+    }.to_string()
+}
+
+fn vector_value_serializer(field: &Field, can_move: bool) -> String {
+    let rust_type = field.get_rust_type("");
+    let inner_type = &rust_type[4..rust_type.len()-1];
+    // optimizations for special types
+    match inner_type {
+        "String" => String::from("let mut strb = String::new(); for val in val { if !strb.is_empty() { strb += \",\" } strb += &val; } strb"),
+        _ => format!("let mut strb = String::new(); for val in val {{ if !strb.is_empty() {{ strb += \",\" }} let add = {}; strb += &add; }} strb", single_value_serializer(field, inner_type, can_move)),
+    }
+}
@@ -210,6 +210,42 @@ fn mod_to_enu(modi: &str, i: usize) -> RuleOp {
     }
 }
 
+impl<'a> MessagesToBookDeclarations<'a> {
+    /// All properties which can be changed by a message.
+    ///
+    /// Returns the name, the types of the ids and the type of each property.
+    fn get_changed_properties(&self) -> Vec<(String, String, String)> {
+        let mut res: Vec<(String, String, String)> = Vec::new();
+        for event in self.decls.iter().filter(|e| e.op == RuleOp::Update) {
+            for p in event.get_updated_properties() {
+                let name = get_property_name(event, p);
+                if res.iter().any(|&(ref n, _, _)| *n == name) {
+                    continue;
+                }
+                let ids = join(event.book_struct.id.iter().map(|i|
+                    PropId::from(i).get_rust_type(&self.book.structs)), ", ");
+                res.push((name, ids, p.get_rust_type()));
+            }
+        }
+        res
+    }
+}
+
+impl<'a> Event<'a> {
+    /// All properties which are set by an update rule of this event.
+    fn get_updated_properties(&self) -> Vec<&'a Property> {
+        let mut res = Vec::new();
+        for rule in &self.rules {
+            match *rule {
+                RuleKind::Map { to, op: RuleOp::Update, .. } => res.push(to),
+                RuleKind::Function { ref to, .. } => res.extend(to.iter().cloned()),
+                _ => {}
+            }
+        }
+        res
+    }
+}
+
 impl<'a> RuleKind<'a> {
     fn is_function(&self) -> bool {
         if let RuleKind::Function { .. } = *self {
@@ -262,3 +298,36 @@ fn try_result(s: &str) -> &'static str {
         _ => "",
     }
 }
+
+/// The name of the tsclientlib `Event` which is raised for a message, if there
+/// is one.
+fn get_event_name(event: &Event) -> Option<&'static str> {
+    match (event.book_struct.name.as_str(), event.op) {
+        ("Client", RuleOp::Add) => Some("ClientEntered"),
+        ("Client", RuleOp::Remove) => Some("ClientLeft"),
+        ("Channel", RuleOp::Add) => Some("ChannelCreated"),
+        ("Channel", RuleOp::Update) => Some("ChannelEdited"),
+        ("Channel", RuleOp::Remove) => Some("ChannelDeleted"),
+        _ => None,
+    }
+}
+
+/// The name of a property in the `PropertyId` and `PropertyValue` enums.
+fn get_property_name(event: &Event, p: &Property) -> String {
+    format!("{}{}", event.book_struct.name, p.name)
+}
+
+/// If the connection id is needed to create the events for a message.
+fn needs_connection_id(event: &Event) -> bool {
+    let has_id = event.id.iter().any(|i| if let IdKind::Id = *i { true }
+        else { false });
+    has_id && (event.op == RuleOp::Update || get_event_name(event).is_some())
+}
+
+/// The ids of the changed object as values.
+fn get_id_values(event: &Event) -> String {
+    join(event.id.iter().map(|id| match *id {
+        IdKind::Fld(f) => format!("cmd.{}", get_notification_field(f)),
+        IdKind::Id => String::from("connection_id"),
+    }), ", ")
+}
@@ -3,11 +3,12 @@ use std::u64;
 
 use byteorder::{NetworkEndian, WriteBytesExt};
 use curve25519_dalek::edwards::EdwardsPoint;
+use futures_cpupool::{CpuFuture, CpuPool};
 use num::bigint::BigUint;
 use quicklz::CompressionLevel;
 use ring::digest;
 
-use {crypto, Result};
+use {crypto, Error, Result};
 use connection::{CachedKey, SharedIv};
 use crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubP256};
 use packets::*;
@@ -89,6 +90,8 @@ pub fn compress_and_split(is_client: bool, packet: &Packet)
     let default_header = {
         let mut h = Header::default();
         h.set_type(packet.header.get_type());
+        // Keep the newprotocol flag if it was set
+        h.set_newprotocol(packet.header.get_newprotocol());
         h
     };
     let mut packets = Vec::with_capacity(datas.len());
@@ -261,14 +264,33 @@ pub fn compute_iv_mac31(
     Ok((shared_iv, shared_mac))
 }
 
-
+/// Search the first offset which reaches the given security level.
+///
+/// This can take a long time for higher levels, [`hash_cash_on_pool`] does
+/// the same without blocking the current thread.
+///
+/// [`hash_cash_on_pool`]: fn.hash_cash_on_pool.html
 pub fn hash_cash(key: &EccKeyPubP256, level: u8) -> Result<u64> {
     let omega = key.to_ts()?;
+    Ok(find_hash_cash_offset(&omega, level))
+}
+
+/// Search the first offset which reaches the given security level on a
+/// thread pool.
+pub fn hash_cash_on_pool(pool: &CpuPool, key: &EccKeyPubP256, level: u8)
+    -> CpuFuture<u64, Error> {
+    let omega = key.to_ts();
+    pool.spawn_fn(move || -> Result<_> {
+        Ok(find_hash_cash_offset(&omega?, level))
+    })
+}
+
+fn find_hash_cash_offset(omega: &str, level: u8) -> u64 {
     let mut offset = 0;
-    while offset < u64::MAX && get_hash_cash_level(&omega, offset) < level {
+    while offset < u64::MAX && get_hash_cash_level(omega, offset) < level {
         offset += 1;
     }
-    Ok(offset)
+    offset
 }
 
 pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
@@ -288,6 +310,17 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
     res
 }
 
+/// Hash a server or channel password, like it is sent to the server.
+///
+/// An empty password stays empty.
+pub fn hash_password(password: &str) -> String {
+    if password.is_empty() {
+        return String::new();
+    }
+    ::base64::encode(digest::digest(&digest::SHA1, password.as_bytes())
+        .as_ref())
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
     let mut v = i.to_bytes_le();
 
@@ -420,4 +453,15 @@ mod tests {
 
         assert!(keynonce.as_ref() == &expected_keynonce as &[u8]);
     }
+
+    #[test]
+    fn hash_cash_pool() {
+        use futures::Future;
+
+        ::init().unwrap();
+        let key = EccKeyPrivP256::create().unwrap().to_pub();
+        let pool = CpuPool::new(1);
+        let offset = hash_cash_on_pool(&pool, &key, 8).wait().unwrap();
+        assert_eq!(offset, hash_cash(&key, 8).unwrap());
+    }
 }
@@ -6,8 +6,10 @@ use std::rc::{Rc, Weak};
 
 use base64;
 use chrono::Utc;
+use curve25519_dalek::edwards::EdwardsPoint;
 use futures::{self, future, Future, Sink, Stream};
 use futures::unsync::oneshot;
+use futures_cpupool::CpuPool;
 #[cfg(feature = "rust-gmp")]
 use gmp::mpz::Mpz;
 #[cfg(not(feature = "rust-gmp"))]
@@ -40,6 +42,10 @@ pub type ClientConnection = Connection<SocketConnectionManager<ServerConnectionD
 pub struct ServerConnectionData {
     pub state_change_listener: Vec<Box<FnMut() -> BoxFuture<(), Error>>>,
     pub state: ServerConnectionState,
+    /// The root key of the license chain which is sent by the server.
+    ///
+    /// If this is `None`, the TeamSpeak root key is used.
+    pub license_root: Option<EdwardsPoint>,
 }
 
 #[derive(Debug)]
@@ -162,6 +168,22 @@ pub fn wait_until_connected(
 pub fn connect(
     data: &Rc<RefCell<ClientData>>,
     server_addr: SocketAddr,
+) -> BoxFuture<(), Error> {
+    connect_with_license_root(data, server_addr, None)
+}
+
+/// Connect to a server, which signs its licenses with a custom root key.
+///
+/// This works like [`connect`], but it is only needed for servers which do
+/// not use a license from TeamSpeak, e.g. the test servers from the
+/// [`server`] module.
+///
+/// [`connect`]: fn.connect.html
+/// [`server`]: ../server/index.html
+pub fn connect_with_license_root<R: Into<Option<EdwardsPoint>>>(
+    data: &Rc<RefCell<ClientData>>,
+    server_addr: SocketAddr,
+    license_root: R,
 ) -> BoxFuture<(), Error> {
     // Send the first init packet
     // Get the current timestamp
@@ -191,6 +213,7 @@ pub fn connect(
             version: timestamp,
             random0,
         };
+        con_data.license_root = license_root.into();
     }
 
     let packets = Data::get_packets(Rc::downgrade(&data2));
@@ -246,7 +269,7 @@ impl DefaultPacketHandlerStream {
                     let handle_res = match Self::handle_packet(state, &packet,
                         &mut ignore_packet, &mut is_end, &data.private_key,
                         &mut con, key.clone(), &logger, &data.handle,
-                        sink.clone()) {
+                        &data.cpu_pool, sink.clone()) {
                         Ok(res) => res,
                         Err(error) => {
                             error!(logger, "Error when handling packet";
@@ -310,8 +333,9 @@ impl DefaultPacketHandlerStream {
         ignore_packet: &mut bool, is_end: &mut bool,
         private_key: &EccKeyPrivP256, con: &mut Connection<CM>,
         con_key: CM::ConnectionsKey, logger: &Logger, handle: &Handle,
-        sink: MultiSink<InnerSink>)
+        cpu_pool: &Option<CpuPool>, sink: MultiSink<InnerSink>)
         -> Result<Option<(ServerConnectionState, Option<Packet>)>> {
+        let license_root = state.license_root;
         let res = match state.state {
             ServerConnectionState::Uninitialized =>
                 return Err(format_err!("ServerConnectionState is uninitialized").into()),
@@ -366,9 +390,8 @@ impl DefaultPacketHandlerStream {
                         let random2 = *random2;
                         let x = *x;
                         let n = *n;
-                        // Spawn this as another future
                         let logger2 = logger.clone();
-                        let fut = future::lazy(move || {
+                        let solve = move || -> Result<_> {
                             let mut time_reporter = ::slog_perf::TimeReporter::new_with_level(
                                 "Solve RSA puzzle", logger.clone(),
                                 ::slog::Level::Info);
@@ -404,7 +427,18 @@ impl DefaultPacketHandlerStream {
                                       "y" => %yi);
                                 algs::biguint_to_array(&yi)
                             };
+                            Ok(y)
+                        };
 
+                        // Solve the puzzle on the thread pool if there is
+                        // one, so other connections are not blocked.
+                        let y_fut: BoxFuture<_, Error> =
+                            if let Some(ref pool) = *cpu_pool {
+                                Box::new(pool.spawn_fn(solve))
+                            } else {
+                                Box::new(future::lazy(solve))
+                            };
+                        let fut = y_fut.and_then(move |y| {
                             // Create the command string
                             // omega is an ASN.1-DER encoded public key from
                             // the ECDH parameters.
@@ -514,7 +548,11 @@ impl DefaultPacketHandlerStream {
                             // Parse license argument
                             let licenses = Licenses::parse(&l)?;
                             // Ephemeral key of server
-                            let server_ek = licenses.derive_public_key()?;
+                            let server_ek = if let Some(root) = license_root {
+                                licenses.derive_public_key_with_root(root)?
+                            } else {
+                                licenses.derive_public_key()?
+                            };
 
                             // Create own ephemeral key
                             let ek = EccKeyPrivEd25519::create()?;
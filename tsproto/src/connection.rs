@@ -10,6 +10,7 @@ use num::ToPrimitive;
 use connectionmanager::ConnectionManager;
 use crypto::EccKeyPubP256;
 use packets::*;
+use ping::PingState;
 
 /// A cache for the key and nonce for a generation id.
 /// This has to be stored for each packet type.
@@ -140,6 +141,8 @@ pub struct Connection<CM: ConnectionManager + 'static> {
     pub address: SocketAddr,
 
     pub resender: CM::Resend,
+    /// The pings which were sent and the last measured round trip time.
+    pub ping: PingState,
 }
 
 impl<CM: ConnectionManager + 'static> Connection<CM> {
@@ -151,6 +154,7 @@ impl<CM: ConnectionManager + 'static> Connection<CM> {
             params: None,
             address,
             resender,
+            ping: PingState::new(),
         }))
     }
 }
@@ -3,6 +3,7 @@ use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 
+use chrono::Duration;
 use futures::{future, Future, Sink};
 use slog::Logger;
 use tokio_core::reactor::Handle;
@@ -11,6 +12,7 @@ use {Error, Map};
 use connection::Connection;
 use handler_data::Data;
 use packets::{PacketType, UdpPacket};
+use ping::{PingConfig, PingFuture};
 use resend::{DefaultResender, ResendConfig, ResendFuture};
 
 /// Implementers of this trait store all connections for a specific socket.
@@ -118,6 +120,12 @@ pub trait Resender: Sink<SinkItem = (PacketType, u16, UdpPacket),
 
     /// Called for received udp packets.
     fn udp_packet_received(&mut self, packet: &UdpPacket);
+
+    /// Called when the round trip time of the connection was measured, e.g.
+    /// by a ping.
+    ///
+    /// The default implementation ignores the measurement.
+    fn update_srtt(&mut self, _rtt: Duration) {}
 }
 
 /// An implementation of a connectionmanager, that identifies a connection its
@@ -132,6 +140,7 @@ pub struct SocketConnectionManager<T: Default + 'static> {
     /// is created.
     data: Option<Weak<RefCell<Data<SocketConnectionManager<T>>>>>,
     resend_config: ResendConfig,
+    ping_config: PingConfig,
     connections: Map<SocketAddr,
         (T, Rc<RefCell<Connection<SocketConnectionManager<T>>>>)>
 }
@@ -141,6 +150,7 @@ impl<T: Default + 'static> Default for SocketConnectionManager<T> {
         Self {
             data: None,
             resend_config: Default::default(),
+            ping_config: Default::default(),
             connections: Default::default(),
         }
     }
@@ -169,6 +179,11 @@ impl<T: Default + 'static> SocketConnectionManager<T> {
         }
     }
 
+    /// Change how often pings are sent on new connections.
+    pub fn set_ping_config(&mut self, ping_config: PingConfig) {
+        self.ping_config = ping_config;
+    }
+
     /// Sets the data reference in this connection manager.
     pub fn set_data_ref(&mut self, data: Weak<RefCell<Data<Self>>>) {
         self.data = Some(data);
@@ -213,6 +228,18 @@ impl<T: Default + 'static> ConnectionManager for SocketConnectionManager<T> {
         self.connections.insert(key, (Default::default(), con));
 
         let data = self.data.as_ref().unwrap().clone();
+        let ping_config = self.ping_config.clone();
+        let ping_data = data.clone();
+        handle.spawn(future::lazy(move || {
+            let data = ping_data.upgrade().unwrap();
+            let ping = PingFuture::new(&data, key, ping_config);
+            let logger = data.borrow().logger.clone();
+
+            future::result(ping).and_then(|ping| ping).map_err(move |e| {
+                error!(logger, "Sending pings exited with error";
+                    "error" => ?e);
+            })
+        }));
         handle.spawn(future::lazy(move || {
             let data_tmp = data.upgrade().unwrap();
             let resend = ResendFuture::new(&data_tmp, key);
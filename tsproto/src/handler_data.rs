@@ -6,6 +6,7 @@ use std::rc::{Rc, Weak};
 use {slog, slog_async, slog_term};
 use futures::{self, Sink, Stream};
 use futures::unsync::mpsc;
+use futures_cpupool::CpuPool;
 use slog::Drain;
 use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
@@ -54,6 +55,12 @@ pub struct Data<CM: ConnectionManager> {
     pub private_key: EccKeyPrivP256,
     pub handle: Handle,
     pub logger: slog::Logger,
+    /// A thread pool for cpu intensive computations, like solving the RSA
+    /// puzzle of the handshake.
+    ///
+    /// If this is `None`, they run on the thread of the reactor and block all
+    /// other connections in the meantime.
+    pub cpu_pool: Option<CpuPool>,
 
     /// The stream of `UdpPacket`s.
     pub udp_packet_stream:
@@ -99,6 +106,45 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
         connection_manager: CM,
         logger: L,
     ) -> Result<Rc<RefCell<Self>>> {
+        // Create the socket
+        let socket = UdpSocket::bind(&local_addr, &handle)?;
+        let local_addr = socket.local_addr().unwrap_or(local_addr);
+        let (sink, stream) = socket.framed(TsCodec::default()).split();
+        let sink = sink.sink_map_err(|e| e.into());
+        let stream = stream.map_err(|e| e.into());
+
+        Ok(Self::with_transport(local_addr, private_key, handle, is_client,
+            connection_manager, logger, stream, sink))
+    }
+
+    /// Use an arbitrary stream and sink of `UdpPacket`s instead of binding a
+    /// udp socket.
+    ///
+    /// This can be used e.g. with the in-memory transport from the
+    /// [`transport`] module to connect a client and a server without using
+    /// the network.
+    ///
+    /// An optional logger can be provided. If none is provided, a new one will
+    /// be created.
+    ///
+    /// [`transport`]: ../transport/index.html
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    pub fn with_transport<L, St, Si>(
+        local_addr: SocketAddr,
+        private_key: EccKeyPrivP256,
+        handle: Handle,
+        is_client: bool,
+        connection_manager: CM,
+        logger: L,
+        stream: St,
+        sink: Si,
+    ) -> Rc<RefCell<Self>>
+    where
+        L: Into<Option<slog::Logger>>,
+        St: Stream<Item = (SocketAddr, UdpPacket), Error = Error> + 'static,
+        Si: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>
+            + 'static,
+    {
         let logger = logger.into().unwrap_or_else(|| {
             let decorator = slog_term::TermDecorator::new().build();
             let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -107,21 +153,15 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
             slog::Logger::root(drain, o!())
         });
 
-        // Create the socket
-        let socket = UdpSocket::bind(&local_addr, &handle)?;
-        let local_addr = socket.local_addr().unwrap_or(local_addr);
-        let (sink, stream) = socket.framed(TsCodec::default()).split();
-        let sink = Box::new(sink.sink_map_err(|e| e.into()));
-        let stream = Box::new(stream.map_err(|e| e.into()));
-
         let data = Rc::new(RefCell::new(Self {
             is_client,
             local_addr,
             private_key,
             handle,
             logger,
-            udp_packet_stream: Some(stream),
-            udp_packet_sink: Some(sink),
+            cpu_pool: None,
+            udp_packet_stream: Some(Box::new(stream)),
+            udp_packet_sink: Some(Box::new(sink)),
             unknown_udp_packet_sink: None,
             packet_stream: None,
             packet_sink: None,
@@ -133,7 +173,7 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
         ::packet_codec::PacketCodecSink::apply(&data);
         ::packet_codec::PacketCodecStream::apply(&data, true);
 
-        Ok(data)
+        data
     }
 
     pub fn create_connection(data: &Rc<RefCell<Self>>, addr: SocketAddr)
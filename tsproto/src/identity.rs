@@ -0,0 +1,340 @@
+//! The identity of a client, which consists of a private key and the offset
+//! that determines its security level.
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ring::digest;
+
+use Result;
+use algorithms as algs;
+use crypto::EccKeyPrivP256;
+
+/// How many offsets a thread checks before it reports its progress.
+const BATCH_SIZE: u64 = 10_000;
+
+/// A private key together with the hash cash offset for its public key.
+///
+/// The security level of an identity is the number of leading zero bits of
+/// `SHA1(public key || offset)`. It can be raised with [`improve`], which
+/// searches for a better offset.
+///
+/// [`improve`]: #method.improve
+#[derive(Debug, Clone)]
+pub struct Identity {
+    key: EccKeyPrivP256,
+    /// The public key in the TeamSpeak format, which is hashed to compute the
+    /// level.
+    omega: String,
+    offset: u64,
+    level: u8,
+    /// All offsets below this one were already checked.
+    ///
+    /// A search for a better level continues at this offset.
+    last_checked_offset: u64,
+}
+
+impl Identity {
+    /// Create a new identity with a random key and a security level of 0.
+    pub fn create() -> Result<Self> {
+        Self::new(EccKeyPrivP256::create()?, 0)
+    }
+
+    /// Create an identity from an existing key and offset.
+    pub fn new(key: EccKeyPrivP256, offset: u64) -> Result<Self> {
+        Self::new_with_progress(key, offset, 0)
+    }
+
+    /// Create an identity and continue a previous search for a better level.
+    ///
+    /// `last_checked_offset` is the value of [`get_last_checked_offset`] from
+    /// the previous search.
+    ///
+    /// [`get_last_checked_offset`]: #method.get_last_checked_offset
+    pub fn new_with_progress(key: EccKeyPrivP256, offset: u64,
+        last_checked_offset: u64) -> Result<Self> {
+        let omega = key.to_pub().to_ts()?;
+        let level = algs::get_hash_cash_level(&omega, offset);
+        Ok(Self {
+            key,
+            omega,
+            offset,
+            level,
+            last_checked_offset,
+        })
+    }
+
+    pub fn get_key(&self) -> &EccKeyPrivP256 {
+        &self.key
+    }
+
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The security level which is reached with the current offset.
+    pub fn get_level(&self) -> u8 {
+        self.level
+    }
+
+    /// The offset where the next search for a better level starts.
+    pub fn get_last_checked_offset(&self) -> u64 {
+        self.last_checked_offset
+    }
+
+    /// A hardware id, which is derived from the key.
+    ///
+    /// It stays the same for this identity and has the format of the hardware
+    /// ids which are sent by the TeamSpeak client.
+    pub fn get_hardware_id(&self) -> String {
+        let hash = digest::digest(&digest::SHA256, self.omega.as_bytes());
+        let hex: String = hash.as_ref().iter().map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{},{}", &hex[..32], &hex[32..])
+    }
+
+    /// Parse an identity as it is stored by the TeamSpeak client.
+    ///
+    /// Format: Offset || 'V' || obfuscated key
+    pub fn from_ts_obfuscated(data: &str) -> Result<Self> {
+        let pos = data.find('V').ok_or_else(|| format_err!(
+            "Identity contains no offset"))?;
+        let offset = data[..pos].parse()?;
+        Self::new(EccKeyPrivP256::from_ts_obfuscated(&data[pos + 1..])?,
+            offset)
+    }
+
+    /// Store the identity in the format of the TeamSpeak client.
+    pub fn to_ts_obfuscated(&self) -> Result<String> {
+        Ok(format!("{}V{}", self.offset, self.key.to_ts_obfuscated()?))
+    }
+
+    /// Search for an offset which reaches at least the given security level.
+    ///
+    /// The search runs on `threads` threads and blocks until the level is
+    /// reached or `cancel` is set to `true`. It continues where the last
+    /// search stopped, so a cancelled search can be resumed by calling this
+    /// function again.
+    ///
+    /// `progress` is called regularly with the last checked offset and the
+    /// currently reached level.
+    ///
+    /// Returns `true` if the level was reached and `false` if the search was
+    /// cancelled. The best offset which was found is kept in both cases.
+    pub fn improve<F: FnMut(u64, u8)>(&mut self, level: u8, threads: usize,
+        cancel: &AtomicBool, mut progress: F) -> Result<bool> {
+        if self.level >= level {
+            return Ok(true);
+        }
+
+        let threads = cmp::max(threads, 1);
+        let start = self.last_checked_offset;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (send, recv) = mpsc::channel();
+        let mut handles = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let omega = self.omega.clone();
+            let stop = stop.clone();
+            let send = send.clone();
+            handles.push(thread::spawn(move || {
+                // Thread i checks the batches i, i + threads, …
+                let mut batch = i as u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let first = match batch.checked_mul(BATCH_SIZE)
+                        .and_then(|o| o.checked_add(start))
+                        .and_then(|o| o.checked_add(BATCH_SIZE)) {
+                        Some(end) => end - BATCH_SIZE,
+                        None => break,
+                    };
+                    let mut best: Option<(u64, u8)> = None;
+                    for offset in first..(first + BATCH_SIZE) {
+                        let level = algs::get_hash_cash_level(&omega, offset);
+                        if best.map(|(_, l)| level > l).unwrap_or(true) {
+                            best = Some((offset, level));
+                        }
+                    }
+                    batch += threads as u64;
+                    if send.send((i, batch, best)).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(send);
+
+        // The next batch of each thread
+        let mut next_batches: Vec<u64> = (0..threads as u64).collect();
+        let mut reached = false;
+        while !cancel.load(Ordering::Relaxed) {
+            match recv.recv_timeout(Duration::from_millis(100)) {
+                Ok(res) => {
+                    self.handle_batch(start, &mut next_batches, res);
+                    progress(self.last_checked_offset, self.level);
+                    if self.level >= level {
+                        reached = true;
+                        break;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for h in handles {
+            if h.join().is_err() {
+                return Err(format_err!("A hash cash thread panicked").into());
+            }
+        }
+        // Use the results of the last batches
+        for res in recv.try_iter() {
+            self.handle_batch(start, &mut next_batches, res);
+        }
+        Ok(reached || self.level >= level)
+    }
+
+    /// Save the result of a checked batch.
+    fn handle_batch(&mut self, start: u64, next_batches: &mut [u64],
+        (thread, next_batch, best): (usize, u64, Option<(u64, u8)>)) {
+        next_batches[thread] = next_batch;
+        if let Some((offset, level)) = best {
+            if level > self.level {
+                self.offset = offset;
+                self.level = level;
+            }
+        }
+        // All batches before the smallest next batch are checked
+        let checked = *next_batches.iter().min().unwrap();
+        self.last_checked_offset = checked.saturating_mul(BATCH_SIZE)
+            .saturating_add(start);
+    }
+}
+
+/// An identity file as it is exported by the TeamSpeak client.
+///
+/// The file looks like this:
+///
+/// ```text
+/// [Identity]
+/// id=Name of the identity
+/// identity="<offset>V<obfuscated key>"
+/// nickname=Nickname
+/// phonetic_nickname=
+/// ```
+#[derive(Debug, Clone)]
+pub struct IdentityFile {
+    /// The name of this identity in the identity manager of the client.
+    pub name: String,
+    pub identity: Identity,
+    pub nickname: String,
+    pub phonetic_nickname: String,
+}
+
+impl IdentityFile {
+    /// Parse an exported identity file.
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut name = String::new();
+        let mut identity = None;
+        let mut nickname = String::new();
+        let mut phonetic_nickname = String::new();
+
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('[')
+                || line.starts_with(';') {
+                continue;
+            }
+            let pos = if let Some(pos) = line.find('=') {
+                pos
+            } else {
+                continue;
+            };
+            let value = line[pos + 1..].trim();
+            // Values can be quoted
+            let value = if value.len() >= 2 && value.starts_with('"')
+                && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            match line[..pos].trim() {
+                "id" => name = value.to_string(),
+                "identity" =>
+                    identity = Some(Identity::from_ts_obfuscated(value)?),
+                "nickname" => nickname = value.to_string(),
+                "phonetic_nickname" => phonetic_nickname = value.to_string(),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            name,
+            identity: identity.ok_or_else(|| format_err!(
+                "The identity file contains no identity"))?,
+            nickname,
+            phonetic_nickname,
+        })
+    }
+
+    /// Write the identity in the format of an exported identity file.
+    pub fn to_file(&self) -> Result<String> {
+        Ok(format!("[Identity]\nid={}\nidentity=\"{}\"\nnickname={}\n\
+            phonetic_nickname={}\n", self.name,
+            self.identity.to_ts_obfuscated()?, self.nickname,
+            self.phonetic_nickname))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn improve_level() {
+        ::init().unwrap();
+        let mut identity = Identity::create().unwrap();
+        let cancel = AtomicBool::new(false);
+        let mut reports = 0;
+        assert!(identity.improve(8, 2, &cancel, |_, _| reports += 1).unwrap());
+
+        assert!(identity.get_level() >= 8);
+        assert!(reports > 0);
+        let omega = identity.get_key().to_pub().to_ts().unwrap();
+        assert_eq!(algs::get_hash_cash_level(&omega, identity.get_offset()),
+            identity.get_level());
+        assert!(identity.get_last_checked_offset() > 0);
+    }
+
+    #[test]
+    fn identity_file_round_trip() {
+        ::init().unwrap();
+        let identity = Identity::new(EccKeyPrivP256::create().unwrap(), 1234)
+            .unwrap();
+        let file = IdentityFile {
+            name: String::from("Bot"),
+            identity,
+            nickname: String::from("Bot nickname"),
+            phonetic_nickname: String::new(),
+        };
+        let parsed = IdentityFile::parse(&file.to_file().unwrap()).unwrap();
+
+        assert_eq!(parsed.name, "Bot");
+        assert_eq!(parsed.nickname, "Bot nickname");
+        assert_eq!(parsed.phonetic_nickname, "");
+        assert_eq!(parsed.identity.get_offset(), 1234);
+        assert_eq!(parsed.identity.get_key().to_ts().unwrap(),
+            file.identity.get_key().to_ts().unwrap());
+    }
+
+    #[test]
+    fn cancel_improve() {
+        ::init().unwrap();
+        let mut identity = Identity::create().unwrap();
+        let cancel = AtomicBool::new(true);
+        assert!(!identity.improve(60, 1, &cancel, |_, _| {}).unwrap());
+        assert!(identity.get_level() < 60);
+    }
+}
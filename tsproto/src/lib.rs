@@ -9,6 +9,7 @@ extern crate curve25519_dalek;
 #[macro_use]
 extern crate failure;
 extern crate futures;
+extern crate futures_cpupool;
 #[cfg(feature = "rust-gmp")]
 extern crate gmp;
 #[macro_use]
@@ -44,13 +45,21 @@ pub mod connection;
 pub mod connectionmanager;
 pub mod crypto;
 pub mod handler_data;
+pub mod identity;
 pub mod license;
 pub mod log;
 pub mod packets;
 pub mod packet_codec;
+pub mod ping;
+pub mod record;
 pub mod resend;
+pub mod server;
+pub mod transport;
 pub mod utils;
 
+#[cfg(test)]
+mod test_utils;
+
 type BoxFuture<T, E> = Box<Future<Item = T, Error = E>>;
 type Map<K, V> = std::collections::HashMap<K, V>;
 type Result<T> = std::result::Result<T, Error>;
@@ -167,8 +167,18 @@ impl Licenses {
     }
 
     pub fn derive_public_key(&self) -> Result<EdwardsPoint> {
-        let mut last_round = CompressedEdwardsY(::ROOT_KEY).decompress()
-            .unwrap();
+        let root = CompressedEdwardsY(::ROOT_KEY).decompress().unwrap();
+        self.derive_public_key_with_root(root)
+    }
+
+    /// Derive the public key of this license, starting with a custom root key
+    /// instead of the TeamSpeak root key.
+    ///
+    /// This is used for licenses which were created by ourself, e.g. by a test
+    /// server.
+    pub fn derive_public_key_with_root(&self, root: EdwardsPoint)
+        -> Result<EdwardsPoint> {
+        let mut last_round = root;
         for l in &self.blocks {
             //let derived_key = last_round.compress().0;
             //println!("Got key: {:?}", ::utils::HexSlice((&derived_key) as &[u8]));
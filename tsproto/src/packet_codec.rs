@@ -195,6 +195,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = (SocketAddr, UdpPacket),
                 } else {
                     info!(data.logger, "No unknown packet handler");
                 }
+                // The stream polls the next packet
                 return Ok(futures::Async::NotReady);
             }
         };
@@ -333,6 +334,13 @@ impl<CM: ConnectionManager, Inner: Stream<Item = (SocketAddr, UdpPacket),
                                             };
                                             con.resender.ack_packet(p_type, p_id);
                                         }
+                                        packets::Data::Pong(p_id) => {
+                                            // Measure the round trip time
+                                            if let Some(rtt) = con.ping
+                                                .pong_received(p_id) {
+                                                con.resender.update_srtt(rtt);
+                                            }
+                                        }
                                         _ => {}
                                     }
                                     Ok(vec![(con_key.clone(),
@@ -451,11 +459,18 @@ impl<
             ))));
         }
         // Don't return an error, that will terminate the stream (log them only)
-        let res: Result<_> = match self.inner.poll()? {
-            futures::Async::Ready(Some((addr, udp_packet))) =>
-                self.on_packet_received(addr, udp_packet),
-            futures::Async::Ready(None) => Ok(futures::Async::Ready(None)),
-            futures::Async::NotReady => Ok(futures::Async::NotReady),
+        let res: Result<_> = loop {
+            match self.inner.poll()? {
+                futures::Async::Ready(Some((addr, udp_packet))) =>
+                    match self.on_packet_received(addr, udp_packet) {
+                        // The packet yielded nothing, try the next one
+                        Ok(futures::Async::NotReady) => {}
+                        res => break res,
+                    },
+                futures::Async::Ready(None) =>
+                    break Ok(futures::Async::Ready(None)),
+                futures::Async::NotReady => break Ok(futures::Async::NotReady),
+            }
         };
 
         if let Err(error) = res {
@@ -0,0 +1,268 @@
+//! Send ping packets to keep a connection alive and measure its round trip
+//! time.
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use futures::{self, Future, Sink, Stream};
+use num::ToPrimitive;
+use tokio_core::reactor::Interval;
+
+use {packets, Error, Result};
+use connection::Connection;
+use connectionmanager::ConnectionManager;
+use handler_data::Data;
+use packets::*;
+
+/// Configure how often pings are sent.
+#[derive(Clone, Debug)]
+pub struct PingConfig {
+    /// Send a ping packet in this interval.
+    ///
+    /// The default interval is used if this is not positive.
+    pub interval: Duration,
+    /// Forget a ping if no pong was received for it after this duration.
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig {
+            interval: Duration::seconds(1),
+            timeout: Duration::seconds(10),
+        }
+    }
+}
+
+impl PingConfig {
+    /// The interval in which pings are sent.
+    ///
+    /// Falls back to the default interval if the configured interval is not
+    /// positive.
+    pub fn get_interval(&self) -> StdDuration {
+        match self.interval.to_std() {
+            Ok(interval) if interval != StdDuration::from_secs(0) => interval,
+            _ => StdDuration::from_secs(1),
+        }
+    }
+}
+
+/// The pings of a connection which were sent but not yet answered.
+#[derive(Clone, Debug, Default)]
+pub struct PingState {
+    /// The packet id of each sent ping and the time when it was sent.
+    sent: Vec<(u16, DateTime<Utc>)>,
+    /// The round trip time which was measured by the last answered ping.
+    pub rtt: Option<Duration>,
+}
+
+impl PingState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember that a ping with the given packet id was sent.
+    pub fn ping_sent(&mut self, p_id: u16) {
+        self.sent.push((p_id, Utc::now()));
+    }
+
+    /// Handle a received pong packet.
+    ///
+    /// Returns the measured round trip time if the pong answers a ping that we
+    /// sent and `None` otherwise.
+    pub fn pong_received(&mut self, p_id: u16) -> Option<Duration> {
+        let pos = self.sent.iter().position(|&(id, _)| id == p_id)?;
+        let (_, sent) = self.sent.remove(pos);
+        // All older pings were lost
+        self.sent.drain(..pos);
+
+        let rtt = Utc::now().naive_utc().signed_duration_since(
+            sent.naive_utc());
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Forget about pings which are older than `timeout`.
+    pub fn remove_old(&mut self, timeout: Duration) {
+        let threshold = Utc::now() - timeout;
+        self.sent.retain(|&(_, sent)| sent > threshold);
+    }
+}
+
+/// This future is running in parallel to the rest and sends a ping packet in
+/// a regular interval.
+///
+/// Pings are only sent when the connection is established.
+pub struct PingFuture<CM: ConnectionManager + 'static> {
+    data: Weak<RefCell<Data<CM>>>,
+    connection_key: CM::ConnectionsKey,
+    connection: Weak<RefCell<Connection<CM>>>,
+    sink: ::handler_data::DataPackets<CM>,
+    config: PingConfig,
+    /// The timer to wake us up when the next ping should be sent.
+    interval: Interval,
+    /// If we are sending and should poll the sink.
+    is_sending: bool,
+}
+
+impl<CM: ConnectionManager + 'static> PingFuture<CM> {
+    pub fn new(
+        data: &Rc<RefCell<Data<CM>>>,
+        connection_key: CM::ConnectionsKey,
+        config: PingConfig,
+    ) -> Result<Self> {
+        let (handle, connection) = {
+            let data = data.borrow();
+            (data.handle.clone(),
+                data.connection_manager.get_connection(connection_key.clone())
+                .ok_or_else(|| format_err!("The connection does not exist"))?)
+        };
+        let interval = Interval::new(config.get_interval(), &handle)?;
+        Ok(Self {
+            data: Rc::downgrade(data),
+            connection_key,
+            connection: Rc::downgrade(&connection),
+            sink: Data::get_packets(Rc::downgrade(data)),
+            config,
+            interval,
+            is_sending: false,
+        })
+    }
+}
+
+impl<CM: ConnectionManager + 'static> Future for PingFuture<CM> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        loop {
+            // Quit if the connection does not exist anymore
+            let con = if let (Some(_), Some(con)) =
+                (self.data.upgrade(), self.connection.upgrade()) {
+                con
+            } else {
+                return Ok(futures::Async::Ready(()));
+            };
+
+            if self.is_sending {
+                if let futures::Async::Ready(()) = self.sink.poll_complete()? {
+                    self.is_sending = false;
+                } else {
+                    return Ok(futures::Async::NotReady);
+                }
+            }
+
+            match self.interval.poll()? {
+                futures::Async::Ready(Some(())) => {}
+                futures::Async::Ready(None) =>
+                    return Ok(futures::Async::Ready(())),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+
+            // Remember the id of the ping, it gets assigned when the packet
+            // is put into the sink.
+            {
+                let mut con = con.borrow_mut();
+                let con = &mut *con;
+                con.ping.remove_old(self.config.timeout);
+                if let Some(ref params) = con.params {
+                    let type_i = PacketType::Ping.to_usize().unwrap();
+                    con.ping.ping_sent(params.outgoing_p_ids[type_i].1);
+                } else {
+                    // Not yet connected
+                    continue;
+                }
+            }
+
+            let packet = Packet::new(Header::new(PacketType::Ping),
+                packets::Data::Ping {});
+            if let futures::AsyncSink::NotReady(_) = self.sink.start_send(
+                (self.connection_key.clone(), packet))? {
+                // Skip this ping, the id was not used
+                let mut con = con.borrow_mut();
+                con.ping.sent.pop();
+                warn!(con.logger, "Cannot send ping, the sink is busy");
+            } else {
+                self.is_sending = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils;
+
+    #[test]
+    fn pong_removes_older_pings() {
+        let mut state = PingState::new();
+        state.ping_sent(1);
+        state.ping_sent(2);
+        state.ping_sent(3);
+
+        assert!(state.pong_received(2).is_some());
+        assert!(state.rtt.is_some());
+        assert_eq!(state.sent.len(), 1);
+        assert!(state.pong_received(1).is_none());
+        assert!(state.pong_received(3).is_some());
+        assert!(state.sent.is_empty());
+    }
+
+    #[test]
+    fn unknown_pong() {
+        let mut state = PingState::new();
+        state.ping_sent(5);
+        assert!(state.pong_received(4).is_none());
+        assert!(state.rtt.is_none());
+        assert_eq!(state.sent.len(), 1);
+    }
+
+    #[test]
+    fn invalid_interval_uses_default() {
+        let config = PingConfig {
+            interval: Duration::seconds(-5),
+            .. PingConfig::default()
+        };
+        assert_eq!(config.get_interval(), StdDuration::from_secs(1));
+        let config = PingConfig {
+            interval: Duration::zero(),
+            .. PingConfig::default()
+        };
+        assert_eq!(config.get_interval(), StdDuration::from_secs(1));
+        let config = PingConfig {
+            interval: Duration::milliseconds(200),
+            .. PingConfig::default()
+        };
+        assert_eq!(config.get_interval(), StdDuration::from_millis(200));
+    }
+
+    #[test]
+    fn pong_updates_srtt() {
+        let mut core = Core::new().unwrap();
+        let (client, server, root) = test_utils::create_pair(&core.handle(),
+            |p| vec![p]);
+        client.borrow_mut().connection_manager.set_ping_config(PingConfig {
+            interval: Duration::milliseconds(20),
+            .. PingConfig::default()
+        });
+        test_utils::run_server(&server, |_, _| {});
+        test_utils::connect(&mut core, &client, &root);
+
+        let con = client.borrow().connection_manager
+            .get_connection(test_utils::server_addr()).unwrap();
+        // Ignore pings which were answered while connecting
+        let srtt = {
+            let mut con = con.borrow_mut();
+            con.ping.rtt = None;
+            con.resender.get_srtt()
+        };
+        test_utils::run_until(&mut core, || con.borrow().ping.rtt.is_some());
+        let con = con.borrow();
+        assert!(con.ping.rtt.unwrap() >= Duration::zero());
+        assert!(con.resender.get_srtt() != srtt);
+    }
+}
@@ -0,0 +1,850 @@
+//! Record the traffic of a connection to a pcap-ng file and replay it.
+//!
+//! A [`Recorder`] writes the udp packets, as they are sent over the network,
+//! and the decrypted packets to a pcap-ng file. Every packet gets a comment
+//! with its direction and content, so the file can be inspected with
+//! Wireshark.
+//!
+//! The recorder is applied to a [`Data`] object with [`apply`]:
+//!
+//! ```ignore
+//! let recorder = Recorder::new(Box::new(File::create("session.pcapng")?),
+//!     is_client, local_addr)?;
+//! record::apply(&data, recorder);
+//! ```
+//!
+//! A recorded file can be read with [`read_recording`] and fed back into a
+//! `Data` object with [`replay_udp_packets`] or [`replay_packets`].
+//!
+//! The udp packets are stored with a generated ip and udp header on the first
+//! interface of the file. The decrypted packets are stored on the second
+//! interface with the link type `USER0` in the following format:
+//!
+//! ```text
+//! flags (u8) || length of the id (u16) || id || header || data
+//! ```
+//!
+//! [`Recorder`]: struct.Recorder.html
+//! [`Data`]: ../handler_data/struct.Data.html
+//! [`apply`]: fn.apply.html
+//! [`read_recording`]: fn.read_recording.html
+//! [`replay_udp_packets`]: fn.replay_udp_packets.html
+//! [`replay_packets`]: fn.replay_packets.html
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use futures::{self, future, stream, AsyncSink, Sink, StartSend, Stream};
+use slog::Logger;
+
+use {Error, Result, SinkWrapper, StreamWrapper};
+use connectionmanager::ConnectionManager;
+use handler_data::Data;
+use packets::{Data as PacketData, Header, Packet, UdpPacket};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPTION_END: u16 = 0;
+const OPTION_COMMENT: u16 = 1;
+const OPTION_EPB_FLAGS: u16 = 2;
+/// The name of an interface, the code is only unique for interface blocks.
+const OPTION_IF_NAME: u16 = 2;
+/// The length of an option is stored as `u16`.
+const MAX_OPTION_LENGTH: usize = 0xffff;
+/// The direction bits of the `epb_flags` option.
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+
+/// Raw ip packets.
+const LINKTYPE_RAW: u16 = 101;
+/// Reserved for private use, we store decrypted packets with it.
+const LINKTYPE_USER0: u16 = 147;
+/// The interface which stores the udp packets.
+const INTERFACE_UDP: u32 = 0;
+/// The interface which stores the decrypted packets.
+const INTERFACE_DECRYPTED: u32 = 1;
+
+/// Flags of decrypted packets.
+const DECRYPTED_INCOMING: u8 = 1;
+/// Set if the packet was sent by a client, so the header contains a client id.
+const DECRYPTED_FROM_CLIENT: u8 = 2;
+
+/// A packet which was read from a recording.
+#[derive(Debug)]
+pub enum RecordedPacket {
+    /// A udp packet as it was sent over the network.
+    Udp {
+        /// The time in microseconds since the unix epoch.
+        time: u64,
+        incoming: bool,
+        /// The address of the other side.
+        addr: SocketAddr,
+        packet: UdpPacket,
+    },
+    /// A decrypted packet.
+    Decrypted {
+        /// The time in microseconds since the unix epoch.
+        time: u64,
+        incoming: bool,
+        /// The id of the connection, as it is displayed.
+        id: String,
+        packet: Packet,
+    },
+}
+
+struct RecorderInner {
+    writer: Box<Write>,
+    is_client: bool,
+    local_addr: SocketAddr,
+}
+
+/// Writes packets to a pcap-ng file.
+///
+/// The recorder can be cloned, all clones write to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Rc<RefCell<RecorderInner>>,
+}
+
+fn now() -> u64 {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    time.as_secs() * 1_000_000 + u64::from(time.subsec_nanos() / 1000)
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Write a block with the given body.
+fn write_block(w: &mut Write, block_type: u32, body: &[u8]) -> Result<()> {
+    let len = 12 + body.len() + padding(body.len());
+    w.write_u32::<LittleEndian>(block_type)?;
+    w.write_u32::<LittleEndian>(len as u32)?;
+    w.write_all(body)?;
+    w.write_all(&[0; 3][..padding(body.len())])?;
+    w.write_u32::<LittleEndian>(len as u32)?;
+    Ok(())
+}
+
+/// Write an option, longer values are truncated.
+fn write_option(w: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let value = &value[..value.len().min(MAX_OPTION_LENGTH)];
+    w.write_u16::<LittleEndian>(code).unwrap();
+    w.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+    w.extend_from_slice(value);
+    w.extend_from_slice(&[0; 3][..padding(value.len())]);
+}
+
+/// Shorten a comment so it fits into an option, without splitting a
+/// character.
+fn truncate_comment(comment: &str) -> &str {
+    if comment.len() <= MAX_OPTION_LENGTH {
+        return comment;
+    }
+    let mut end = MAX_OPTION_LENGTH;
+    while !comment.is_char_boundary(end) {
+        end -= 1;
+    }
+    &comment[..end]
+}
+
+/// Compute the checksum of an ipv4 header.
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum = header.chunks(2)
+        .map(|c| (u32::from(c[0]) << 8) | u32::from(c[1]))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Add an ip and udp header to the data.
+fn create_ip_packet(from: SocketAddr, to: SocketAddr, data: &[u8])
+    -> Vec<u8> {
+    let udp_len = 8 + data.len();
+    let mut res = Vec::with_capacity(40 + udp_len);
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(from_ip), IpAddr::V4(to_ip)) => {
+            res.extend_from_slice(&[0x45, 0]);
+            res.write_u16::<NetworkEndian>((20 + udp_len) as u16).unwrap();
+            // Id, flags (don't fragment), ttl, protocol (udp), checksum
+            res.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            res.extend_from_slice(&from_ip.octets());
+            res.extend_from_slice(&to_ip.octets());
+            let checksum = ip_checksum(&res);
+            res[10] = (checksum >> 8) as u8;
+            res[11] = checksum as u8;
+        }
+        (from_ip, to_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => Ipv4Addr::to_ipv6_mapped(&ip),
+                IpAddr::V6(ip) => ip,
+            };
+            res.extend_from_slice(&[0x60, 0, 0, 0]);
+            res.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+            // Next header (udp), hop limit
+            res.extend_from_slice(&[17, 64]);
+            res.extend_from_slice(&to_v6(from_ip).octets());
+            res.extend_from_slice(&to_v6(to_ip).octets());
+        }
+    }
+    res.write_u16::<NetworkEndian>(from.port()).unwrap();
+    res.write_u16::<NetworkEndian>(to.port()).unwrap();
+    res.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+    // No checksum
+    res.write_u16::<NetworkEndian>(0).unwrap();
+    res.extend_from_slice(data);
+    res
+}
+
+/// Parse the ip and udp header which were created by `create_ip_packet`.
+///
+/// Returns the source and destination address and the payload.
+fn parse_ip_packet(data: &[u8]) -> Result<(SocketAddr, SocketAddr, &[u8])> {
+    let version = data.first().map(|b| b >> 4);
+    let (from, to, header_len) = match version {
+        Some(4) if data.len() >= 20 => {
+            let header_len = usize::from(data[0] & 0xf) * 4;
+            let mut from = [0; 4];
+            let mut to = [0; 4];
+            from.copy_from_slice(&data[12..16]);
+            to.copy_from_slice(&data[16..20]);
+            (IpAddr::from(from), IpAddr::from(to), header_len)
+        }
+        Some(6) if data.len() >= 40 => {
+            let mut from = [0; 16];
+            let mut to = [0; 16];
+            from.copy_from_slice(&data[8..24]);
+            to.copy_from_slice(&data[24..40]);
+            (IpAddr::from(from), IpAddr::from(to), 40)
+        }
+        _ => return Err(format_err!("Recorded packet is no ip packet")
+            .into()),
+    };
+    if data.len() < header_len + 8 {
+        return Err(format_err!("Recorded packet is too short").into());
+    }
+    let mut r = Cursor::new(&data[header_len..]);
+    let from_port = r.read_u16::<NetworkEndian>()?;
+    let to_port = r.read_u16::<NetworkEndian>()?;
+    Ok((SocketAddr::new(from, from_port), SocketAddr::new(to, to_port),
+        &data[header_len + 8..]))
+}
+
+impl Recorder {
+    /// Create a new recorder and write the header of the file.
+    ///
+    /// # Arguments
+    /// - `is_client`: If the recorded `Data` is owned by a client.
+    /// - `local_addr`: The address of the socket, it is used as the address
+    ///   of our side in the ip headers.
+    pub fn new(mut writer: Box<Write>, is_client: bool, local_addr: SocketAddr)
+        -> Result<Self> {
+        // Section header
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        // Version 1.0
+        body.write_u16::<LittleEndian>(1)?;
+        body.write_u16::<LittleEndian>(0)?;
+        // Unknown section length
+        body.write_i64::<LittleEndian>(-1)?;
+        write_block(&mut *writer, BLOCK_SECTION_HEADER, &body)?;
+
+        // Interfaces for udp and decrypted packets
+        for &(linktype, name) in &[(LINKTYPE_RAW, "udp"),
+            (LINKTYPE_USER0, "decrypted")] {
+            let mut body = Vec::new();
+            body.write_u16::<LittleEndian>(linktype)?;
+            body.write_u16::<LittleEndian>(0)?;
+            // No snapshot length
+            body.write_u32::<LittleEndian>(0)?;
+            write_option(&mut body, OPTION_IF_NAME, name.as_bytes());
+            write_option(&mut body, OPTION_END, &[]);
+            write_block(&mut *writer, BLOCK_INTERFACE_DESCRIPTION, &body)?;
+        }
+        writer.flush()?;
+
+        Ok(Self {
+            inner: Rc::new(RefCell::new(RecorderInner {
+                writer,
+                is_client,
+                local_addr,
+            })),
+        })
+    }
+
+    fn write_packet(&self, interface: u32, incoming: bool, data: &[u8],
+        comment: &str) -> Result<()> {
+        let time = now();
+        let mut body = Vec::with_capacity(data.len() + comment.len() + 40);
+        body.write_u32::<LittleEndian>(interface)?;
+        body.write_u32::<LittleEndian>((time >> 32) as u32)?;
+        body.write_u32::<LittleEndian>(time as u32)?;
+        body.write_u32::<LittleEndian>(data.len() as u32)?;
+        body.write_u32::<LittleEndian>(data.len() as u32)?;
+        body.extend_from_slice(data);
+        body.extend_from_slice(&[0; 3][..padding(data.len())]);
+        write_option(&mut body, OPTION_COMMENT,
+            truncate_comment(comment).as_bytes());
+        let mut flags = Vec::with_capacity(4);
+        flags.write_u32::<LittleEndian>(if incoming {
+            FLAG_INBOUND
+        } else {
+            FLAG_OUTBOUND
+        })?;
+        write_option(&mut body, OPTION_EPB_FLAGS, &flags);
+        write_option(&mut body, OPTION_END, &[]);
+
+        let mut inner = self.inner.borrow_mut();
+        write_block(&mut *inner.writer, BLOCK_ENHANCED_PACKET, &body)?;
+        inner.writer.flush()?;
+        Ok(())
+    }
+
+    /// Record a udp packet which was received from or sent to `addr`.
+    pub fn record_udp_packet(&self, addr: SocketAddr, incoming: bool,
+        packet: &UdpPacket) -> Result<()> {
+        let local_addr = self.inner.borrow().local_addr;
+        let data = if incoming {
+            create_ip_packet(addr, local_addr, &packet.0)
+        } else {
+            create_ip_packet(local_addr, addr, &packet.0)
+        };
+        let comment = format!("{} {}: {:?}", if incoming { "IN from" }
+            else { "OUT to" }, addr, packet);
+        self.write_packet(INTERFACE_UDP, incoming, &data, &comment)
+    }
+
+    /// Record a decrypted packet which was received from or sent to the
+    /// connection `id`.
+    pub fn record_packet<Id: Display>(&self, id: &Id, incoming: bool,
+        packet: &Packet) -> Result<()> {
+        let id = id.to_string();
+        let mut flags = 0;
+        if incoming {
+            flags |= DECRYPTED_INCOMING;
+        }
+        // Incoming packets of a client were sent by the server
+        if self.inner.borrow().is_client != incoming {
+            flags |= DECRYPTED_FROM_CLIENT;
+        }
+        let mut data = vec![flags];
+        data.write_u16::<NetworkEndian>(id.len() as u16)?;
+        data.extend_from_slice(id.as_bytes());
+        packet.header.write(&mut data)?;
+        packet.data.write(&mut data)?;
+
+        let comment = format!("{} {}: {:?}", if incoming { "IN from" }
+            else { "OUT to" }, id, packet);
+        self.write_packet(INTERFACE_DECRYPTED, incoming, &data, &comment)
+    }
+}
+
+/// Parse an enhanced packet block.
+fn parse_packet(linktypes: &[u16], body: &[u8])
+    -> Result<Option<RecordedPacket>> {
+    let mut r = Cursor::new(body);
+    let interface = r.read_u32::<LittleEndian>()? as usize;
+    let time_high = r.read_u32::<LittleEndian>()?;
+    let time_low = r.read_u32::<LittleEndian>()?;
+    let time = (u64::from(time_high) << 32) | u64::from(time_low);
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    // Original length
+    r.read_u32::<LittleEndian>()?;
+    let start = r.position() as usize;
+    if body.len() < start + len {
+        return Err(format_err!("Invalid packet block").into());
+    }
+    let data = &body[start..start + len];
+
+    // Read options
+    let mut incoming = None;
+    let mut r = Cursor::new(&body[start + len + padding(len)..]);
+    while let Ok(code) = r.read_u16::<LittleEndian>() {
+        let len = r.read_u16::<LittleEndian>()? as usize;
+        let pos = r.position() as usize;
+        let value = &r.get_ref()[pos..];
+        if code == OPTION_END || value.len() < len {
+            break;
+        }
+        if code == OPTION_EPB_FLAGS && len == 4 {
+            let flags = (&value[..4]).read_u32::<LittleEndian>()?;
+            match flags & 3 {
+                FLAG_INBOUND => incoming = Some(true),
+                FLAG_OUTBOUND => incoming = Some(false),
+                _ => {}
+            }
+        }
+        r.set_position((pos + len + padding(len)) as u64);
+    }
+    let incoming = if let Some(i) = incoming {
+        i
+    } else {
+        // Packets without direction cannot be replayed
+        return Ok(None);
+    };
+
+    match linktypes.get(interface) {
+        Some(&LINKTYPE_RAW) => {
+            let (from, to, payload) = parse_ip_packet(data)?;
+            Ok(Some(RecordedPacket::Udp {
+                time,
+                incoming,
+                addr: if incoming { from } else { to },
+                packet: UdpPacket(payload.to_vec()),
+            }))
+        }
+        Some(&LINKTYPE_USER0) => {
+            let mut r = Cursor::new(data);
+            let flags = r.read_u8()?;
+            let id_len = r.read_u16::<NetworkEndian>()? as usize;
+            let pos = r.position() as usize;
+            if data.len() < pos + id_len {
+                return Err(format_err!("Invalid decrypted packet").into());
+            }
+            let id = String::from_utf8(data[pos..pos + id_len].to_vec())
+                .map_err(|e| e.utf8_error())?;
+            r.set_position((pos + id_len) as u64);
+            let header = Header::read(&(flags & DECRYPTED_FROM_CLIENT != 0),
+                &mut r)?;
+            let p_data = PacketData::read(&header, &mut r)?;
+            Ok(Some(RecordedPacket::Decrypted {
+                time,
+                incoming: flags & DECRYPTED_INCOMING != 0,
+                id,
+                packet: Packet::new(header, p_data),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Read all packets of a recording.
+///
+/// Only files which were written by a [`Recorder`] are supported, blocks and
+/// interfaces which are not known are skipped.
+///
+/// [`Recorder`]: struct.Recorder.html
+pub fn read_recording(r: &mut Read) -> Result<Vec<RecordedPacket>> {
+    let mut linktypes = Vec::new();
+    let mut packets = Vec::new();
+    loop {
+        let block_type = match r.read_u32::<LittleEndian>() {
+            Ok(t) => t,
+            // End of file
+            Err(_) => break,
+        };
+        let len = r.read_u32::<LittleEndian>()? as usize;
+        if len < 12 || len % 4 != 0 {
+            return Err(format_err!("Invalid block length").into());
+        }
+        let mut body = vec![0; len - 12];
+        r.read_exact(&mut body)?;
+        // Trailing length
+        r.read_u32::<LittleEndian>()?;
+
+        match block_type {
+            BLOCK_SECTION_HEADER => {
+                let magic = (&body[..]).read_u32::<LittleEndian>()?;
+                if magic != BYTE_ORDER_MAGIC {
+                    return Err(format_err!(
+                        "Only little endian files are supported").into());
+                }
+                linktypes.clear();
+            }
+            BLOCK_INTERFACE_DESCRIPTION => {
+                linktypes.push((&body[..]).read_u16::<LittleEndian>()?);
+            }
+            BLOCK_ENHANCED_PACKET => {
+                if let Some(p) = parse_packet(&linktypes, &body)? {
+                    packets.push(p);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(packets)
+}
+
+/// A sink which drops all packets.
+///
+/// It replaces the udp socket when packets are replayed.
+pub struct DiscardSink<T> {
+    phantom: ::std::marker::PhantomData<T>,
+}
+
+impl<T> Default for DiscardSink<T> {
+    fn default() -> Self {
+        Self { phantom: ::std::marker::PhantomData }
+    }
+}
+
+impl<T> Sink for DiscardSink<T> {
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, _: Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
+        Ok(futures::Async::Ready(()))
+    }
+}
+
+/// Replace the udp socket of `data` with the incoming udp packets of a
+/// recording.
+///
+/// The packets go through the normal packet handling. Only the packets of the
+/// handshake can be decrypted by a new `Data` object, the other packets need
+/// the keys of the recorded connection. Use [`replay_packets`] to reproduce
+/// a whole session.
+///
+/// Sent packets are dropped.
+///
+/// [`replay_packets`]: fn.replay_packets.html
+pub fn replay_udp_packets<CM: ConnectionManager + 'static>(
+    data: &Rc<RefCell<Data<CM>>>, packets: Vec<RecordedPacket>) {
+    let packets = packets.into_iter().filter_map(|p| match p {
+        RecordedPacket::Udp { incoming: true, addr, packet, .. } =>
+            Some((addr, packet)),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let mut data = data.borrow_mut();
+    data.udp_packet_stream = Some(Box::new(stream::iter_ok(packets)));
+    data.udp_packet_sink = Some(Box::new(DiscardSink::default()));
+}
+
+/// Replace the decrypted packets of `data` with the incoming decrypted
+/// packets of a recording.
+///
+/// This skips the encryption, so the handling of a recorded session can be
+/// reproduced without the keys of the connection.
+///
+/// Only wrappers which are applied afterwards, like the
+/// [`DefaultPacketHandler`] of a client, see the replayed packets.
+///
+/// [`DefaultPacketHandler`]: ../client/struct.DefaultPacketHandler.html
+pub fn replay_packets<CM: ConnectionManager + 'static>(
+    data: &Rc<RefCell<Data<CM>>>, packets: Vec<RecordedPacket>) -> Result<()>
+    where CM::ConnectionsKey: FromStr {
+    let mut res = Vec::new();
+    for p in packets {
+        if let RecordedPacket::Decrypted { incoming: true, id, packet, .. } = p {
+            let key = id.parse().map_err(|_| format_err!(
+                "Cannot parse connection id {}", id))?;
+            res.push((key, packet));
+        }
+    }
+    data.borrow_mut().packet_stream = Some(Box::new(stream::iter_ok(res)));
+    Ok(())
+}
+
+/// Record all udp packets and decrypted packets of `data`.
+///
+/// Packets which cannot be written to the recording are logged and handled
+/// as usual.
+pub fn apply<CM: ConnectionManager + 'static>(data: &Rc<RefCell<Data<CM>>>,
+    recorder: Recorder) where CM::ConnectionsKey: Display {
+    let logger = data.borrow().logger.clone();
+    Data::apply_udp_packet_stream_wrapper::<UdpPacketStreamRecorder>(data,
+        (recorder.clone(), logger.clone()));
+    Data::apply_udp_packet_sink_wrapper::<UdpPacketSinkRecorder>(data,
+        (recorder.clone(), logger.clone()));
+    Data::apply_packet_stream_wrapper::<
+        PacketStreamRecorder<CM::ConnectionsKey>>(data,
+        (recorder.clone(), logger.clone()));
+    Data::apply_packet_sink_wrapper::<
+        PacketSinkRecorder<CM::ConnectionsKey>>(data, (recorder, logger));
+}
+
+/// Log an error of the recorder, the packet is not lost because of it.
+fn log_error(logger: &Logger, res: Result<()>) {
+    if let Err(error) = res {
+        warn!(logger, "Failed to record packet"; "error" => ?error);
+    }
+}
+
+pub struct UdpPacketStreamRecorder;
+
+impl<Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error> + 'static>
+    StreamWrapper<(SocketAddr, UdpPacket), Error, Inner> for
+    UdpPacketStreamRecorder {
+    /// (recorder, logger)
+    type A = (Recorder, Logger);
+    type Result = Box<Stream<Item = (SocketAddr, UdpPacket), Error = Error>>;
+
+    fn wrap(inner: Inner, (recorder, logger): Self::A) -> Self::Result {
+        Box::new(inner.inspect(move |&(addr, ref packet)| log_error(&logger,
+            recorder.record_udp_packet(addr, true, packet))))
+    }
+}
+
+pub struct UdpPacketSinkRecorder;
+
+impl<Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error> + 'static>
+    SinkWrapper<(SocketAddr, UdpPacket), Error, Inner> for
+    UdpPacketSinkRecorder {
+    /// (recorder, logger)
+    type A = (Recorder, Logger);
+    type Result = Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>;
+
+    fn wrap(inner: Inner, (recorder, logger): Self::A) -> Self::Result {
+        Box::new(inner.with(move |(addr, packet)| {
+            log_error(&logger, recorder.record_udp_packet(addr, false,
+                &packet));
+            future::ok((addr, packet))
+        }))
+    }
+}
+
+pub struct PacketStreamRecorder<Id> {
+    phantom: ::std::marker::PhantomData<Id>,
+}
+
+impl<Inner: Stream<Item = (Id, Packet), Error = Error> + 'static,
+    Id: Display + 'static>
+    StreamWrapper<(Id, Packet), Error, Inner> for PacketStreamRecorder<Id> {
+    /// (recorder, logger)
+    type A = (Recorder, Logger);
+    type Result = Box<Stream<Item = (Id, Packet), Error = Error>>;
+
+    fn wrap(inner: Inner, (recorder, logger): Self::A) -> Self::Result {
+        Box::new(inner.inspect(move |&(ref id, ref packet)| log_error(&logger,
+            recorder.record_packet(id, true, packet))))
+    }
+}
+
+pub struct PacketSinkRecorder<Id> {
+    phantom: ::std::marker::PhantomData<Id>,
+}
+
+impl<Inner: Sink<SinkItem = (Id, Packet), SinkError = Error> + 'static,
+    Id: Display + 'static>
+    SinkWrapper<(Id, Packet), Error, Inner> for PacketSinkRecorder<Id> {
+    /// (recorder, logger)
+    type A = (Recorder, Logger);
+    type Result = Box<Sink<SinkItem = (Id, Packet), SinkError = Error>>;
+
+    fn wrap(inner: Inner, (recorder, logger): Self::A) -> Self::Result {
+        Box::new(inner.with(move |(id, packet)| {
+            log_error(&logger, recorder.record_packet(&id, false, &packet));
+            future::ok((id, packet))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures::Future;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use client::{self, ClientData, ServerConnectionState};
+    use commands::Command;
+    use connectionmanager::{AttachedDataConnectionManager,
+        SocketConnectionManager};
+    use crypto::EccKeyPrivP256;
+    use packets::{C2SInit, PacketType};
+    use test_utils::*;
+    use transport;
+
+    /// A writer which can be read after it was given to the recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_read() {
+        let local: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let server: SocketAddr = "[::1]:9987".parse().unwrap();
+        let buffer = SharedBuffer::default();
+        let recorder = Recorder::new(Box::new(buffer.clone()), true, local)
+            .unwrap();
+
+        recorder.record_udp_packet(server, false, &UdpPacket(vec![1, 2, 3]))
+            .unwrap();
+        recorder.record_udp_packet(server, true, &UdpPacket(vec![4, 5]))
+            .unwrap();
+        let mut header = Header::new(PacketType::Command);
+        header.p_id = 5;
+        let packet = Packet::new(header, PacketData::Command(
+            Command::new("clientinit")));
+        recorder.record_packet(&server, true, &packet).unwrap();
+
+        let data = buffer.0.borrow().clone();
+        let packets = read_recording(&mut Cursor::new(data)).unwrap();
+        assert_eq!(packets.len(), 3);
+        match packets[0] {
+            RecordedPacket::Udp { incoming: false, addr, ref packet, .. } => {
+                assert_eq!(addr.port(), 9987);
+                assert_eq!(packet.0, vec![1, 2, 3]);
+            }
+            ref p => panic!("Unexpected packet {:?}", p),
+        }
+        match packets[1] {
+            RecordedPacket::Udp { incoming: true, ref packet, .. } =>
+                assert_eq!(packet.0, vec![4, 5]),
+            ref p => panic!("Unexpected packet {:?}", p),
+        }
+        match packets[2] {
+            RecordedPacket::Decrypted { incoming: true, ref id, ref packet,
+                .. } => {
+                assert_eq!(id, "[::1]:9987");
+                assert_eq!(packet.header.p_id, 5);
+                if let PacketData::Command(ref cmd) = packet.data {
+                    assert_eq!(cmd.command, "clientinit");
+                } else {
+                    panic!("Expected a command");
+                }
+            }
+            ref p => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    fn long_comment() {
+        let local: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let buffer = SharedBuffer::default();
+        let recorder = Recorder::new(Box::new(buffer.clone()), true, local)
+            .unwrap();
+        let mut command = Command::new("clientinit");
+        command.push("client_nickname", "ä".repeat(40_000));
+        let packet = Packet::new(Header::new(PacketType::Command),
+            PacketData::Command(command));
+        recorder.record_packet(&server_addr(), true, &packet).unwrap();
+
+        let data = buffer.0.borrow().clone();
+        let packets = read_recording(&mut Cursor::new(data)).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(truncate_comment(&"ä".repeat(40_000)).len()
+            <= MAX_OPTION_LENGTH);
+    }
+
+    /// Record the packets of a client, which connects to a server.
+    fn record_session() -> Vec<RecordedPacket> {
+        let mut core = Core::new().unwrap();
+        let (client, server, root) = create_pair(&core.handle(), |p| vec![p]);
+        let buffer = SharedBuffer::default();
+        let recorder = Recorder::new(Box::new(buffer.clone()), true,
+            client_addr()).unwrap();
+        apply(&client, recorder);
+        run_server(&server, |_, _| {});
+        connect(&mut core, &client, &root);
+
+        let data = buffer.0.borrow().clone();
+        read_recording(&mut Cursor::new(data)).unwrap()
+    }
+
+    /// A client without packet handlers, which has a connection to the
+    /// server.
+    fn new_client(core: &Core) -> Rc<RefCell<ClientData>> {
+        let (transport, _) = transport::pair(client_addr(), server_addr());
+        let (sink, stream) = transport.split();
+        let client = ClientData::with_transport(client_addr(),
+            EccKeyPrivP256::create().unwrap(), core.handle(), true,
+            SocketConnectionManager::new(), logger(), stream, sink);
+        {
+            let client2 = client.clone();
+            let mut client = client.borrow_mut();
+            client.connection_manager.set_data_ref(Rc::downgrade(&client2));
+        }
+        Data::add_connection(&client, Data::create_connection(&client,
+            server_addr()));
+        client
+    }
+
+    fn set_state(client: &Rc<RefCell<ClientData>>,
+        state: ServerConnectionState) {
+        client.borrow_mut().connection_manager.get_mut_data(server_addr())
+            .unwrap().state = state;
+    }
+
+    /// Handle the packets of the client and return the packets which were
+    /// passed on by the handler.
+    fn handle_packets(core: &mut Core, client: &Rc<RefCell<ClientData>>)
+        -> Vec<Packet> {
+        client::DefaultPacketHandler::apply(client);
+        core.run(Data::get_packets(Rc::downgrade(client)).collect()).unwrap()
+            .into_iter().map(|(_, p)| p).collect()
+    }
+
+    #[test]
+    fn replay_udp() {
+        let packets = record_session();
+        // Continue the handshake of the recorded client
+        let (version, random0) = packets.iter().filter_map(|p| match *p {
+            RecordedPacket::Decrypted { incoming: false, packet: Packet {
+                data: PacketData::C2SInit(C2SInit::Init0 { version, random0,
+                    .. }), .. }, .. } => Some((version, random0)),
+            _ => None,
+        }).next().expect("Found no Init0 packet");
+
+        let mut core = Core::new().unwrap();
+        let client = new_client(&core);
+        set_state(&client, ServerConnectionState::Init0 { version, random0 });
+        replay_udp_packets(&client, packets);
+        handle_packets(&mut core, &client);
+
+        // The client goes through the init packets of the handshake, but the
+        // other packets need the keys of the recorded connection.
+        let mut client = client.borrow_mut();
+        match client.connection_manager.get_mut_data(server_addr()).unwrap()
+            .state {
+            ServerConnectionState::ClientInitIv { .. }
+            | ServerConnectionState::Connecting => {}
+            ref state => panic!("Unexpected state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn replay_decrypted() {
+        let packets = record_session();
+        let mut core = Core::new().unwrap();
+        let client = new_client(&core);
+        // The handshake is done, the server answers our clientinit
+        set_state(&client, ServerConnectionState::Connecting);
+        replay_packets(&client, packets).unwrap();
+        let handled = handle_packets(&mut core, &client);
+
+        {
+            let mut client = client.borrow_mut();
+            match client.connection_manager.get_mut_data(server_addr())
+                .unwrap().state {
+                ServerConnectionState::Connected => {}
+                ref state => panic!("Unexpected state {:?}", state),
+            }
+        }
+        // The handshake packets are consumed by the handler, the answer to
+        // our clientinit is passed on.
+        match handled.first().map(|p| &p.data) {
+            Some(&PacketData::Command(ref cmd)) => {
+                assert_eq!(cmd.command, "initserver");
+                assert_eq!(cmd.get_static_arg("aclid"), Some("2"));
+            }
+            p => panic!("Unexpected packet {:?}", p),
+        }
+    }
+}
@@ -115,15 +115,14 @@ impl DefaultResender {
         }
     }
 
-    /// Add another duration to the stored smoothed rtt.
-    pub fn update_srtt(&mut self, rtt: Duration) {
-        let diff = if rtt > self.srtt {
-            rtt - self.srtt
-        } else {
-            self.srtt - rtt
-        };
-        self.srtt_dev = self.srtt_dev * 3 / 4 + diff / 4;
-        self.srtt = self.srtt * 7 / 8 + rtt / 8;
+    /// The current smoothed round trip time.
+    pub fn get_srtt(&self) -> Duration {
+        self.srtt
+    }
+
+    /// The deviation of the current smoothed round trip time.
+    pub fn get_srtt_dev(&self) -> Duration {
+        self.srtt_dev
     }
 
     /// Replaces the current state by a new state and return the old state.
@@ -344,6 +343,17 @@ impl Resender for DefaultResender {
             }
         }
     }
+
+    /// Add another duration to the stored smoothed rtt.
+    fn update_srtt(&mut self, rtt: Duration) {
+        let diff = if rtt > self.srtt {
+            rtt - self.srtt
+        } else {
+            self.srtt - rtt
+        };
+        self.srtt_dev = self.srtt_dev * 3 / 4 + diff / 4;
+        self.srtt = self.srtt * 7 / 8 + rtt / 8;
+    }
 }
 
 impl Sink for DefaultResender {
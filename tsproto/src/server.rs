@@ -0,0 +1,535 @@
+//! The server side of the handshake.
+//!
+//! This is not meant to run production servers. It can be used to build local
+//! stand-in servers, e.g. to test clients without a real TeamSpeak server.
+//!
+//! The handshake is finished when the `clientek` of a client was verified. All
+//! following packets, starting with the `clientinit`, are passed to the
+//! application, which has to answer them (e.g. with an `initserver`).
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+
+use base64;
+use chrono::{Duration, Utc};
+use futures::{future, Future, Sink, Stream};
+use futures::unsync::mpsc;
+use num::{One, ToPrimitive};
+use num::bigint::BigUint;
+use rand::{self, Rng};
+use slog::Logger;
+
+use {packets, BoxFuture, Error, Result};
+use algorithms as algs;
+use commands::Command;
+use connection::*;
+use connectionmanager::{AttachedDataConnectionManager, ConnectionManager,
+    Resender, ResenderEvent, SocketConnectionManager};
+use crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubEd25519,
+    EccKeyPubP256};
+use handler_data::Data;
+use license::{InnerLicense, License, LicenseKey, LicenseType, Licenses};
+use packets::*;
+
+/// The data of our server.
+pub type ServerData = Data<SocketConnectionManager<ClientConnectionData>>;
+/// Connections from a server to a client.
+pub type ServerConnection = Connection<SocketConnectionManager<ClientConnectionData>>;
+
+#[derive(Default)]
+pub struct ClientConnectionData {
+    pub state: ClientConnectionState,
+}
+
+pub enum ClientConnectionState {
+    /// Default state.
+    Uninitialized,
+    /// After `Init1` was sent.
+    Init1 { random1: [u8; 16] },
+    /// After `Init3` was sent.
+    Init3 {
+        x: [u8; 64],
+        n: [u8; 64],
+        level: u32,
+        random2: [u8; 100],
+        /// The solution of the RSA puzzle.
+        y: [u8; 64],
+    },
+    /// After `initivexpand2` was sent, the next packet has to be `clientek`.
+    InitIvExpand {
+        alpha: [u8; 10],
+        beta: [u8; 54],
+        /// The public key of the client, which was sent in `clientinitiv`.
+        client_key: EccKeyPubP256,
+        /// The private key which belongs to the sent license chain.
+        ek: EccKeyPrivEd25519,
+    },
+    /// The handshake is done, all packets are passed to the application.
+    Connected,
+}
+
+impl Default for ClientConnectionState {
+    fn default() -> Self {
+        ClientConnectionState::Uninitialized
+    }
+}
+
+/// Configures the handshake of the server.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// The root key of the license chain, which is sent to clients.
+    ///
+    /// Clients have to connect with the matching public key, using
+    /// [`client::connect_with_license_root`].
+    ///
+    /// [`client::connect_with_license_root`]:
+    /// ../client/fn.connect_with_license_root.html
+    pub license_root: EccKeyPrivEd25519,
+    /// The level of the RSA puzzle, which has to be solved by clients.
+    pub puzzle_level: u32,
+}
+
+impl ServerOptions {
+    pub fn new(license_root: EccKeyPrivEd25519) -> Self {
+        Self {
+            license_root,
+            puzzle_level: 10_000,
+        }
+    }
+
+    pub fn puzzle_level(mut self, puzzle_level: u32) -> Self {
+        self.puzzle_level = puzzle_level;
+        self
+    }
+}
+
+fn create_init_header() -> Header {
+    let mut mac = [0; 8];
+    mac.copy_from_slice(b"TS3INIT1");
+    let mut header = Header {
+        mac,
+        p_id: 0x65,
+        c_id: None,
+        p_type: 0,
+    };
+    header.set_type(PacketType::Init);
+    header.set_unencrypted(true);
+    header
+}
+
+/// Create a license chain, which consists of a server license and an
+/// ephemeral license.
+///
+/// The keys in the licenses are private keys, so the private key of the chain
+/// can be derived with [`Licenses::derive_private_key`].
+///
+/// [`Licenses::derive_private_key`]:
+/// ../license/struct.Licenses.html#method.derive_private_key
+pub fn create_license_chain() -> Result<Licenses> {
+    // Create licenses which are valid for one day
+    let not_valid_before = Utc::now() - Duration::minutes(1);
+    let not_valid_after = not_valid_before + Duration::days(1);
+
+    let mut server = License {
+        key: LicenseKey::Private(EccKeyPrivEd25519::create()?),
+        not_valid_before,
+        not_valid_after,
+        hash: [0; 32],
+        inner: InnerLicense::Server {
+            issuer: String::from("tsproto"),
+            license_type: LicenseType::Default,
+            data: 0,
+        },
+    };
+    server.fill_hash();
+
+    let mut ephemeral = License {
+        key: LicenseKey::Private(EccKeyPrivEd25519::create()?),
+        not_valid_before,
+        not_valid_after,
+        hash: [0; 32],
+        inner: InnerLicense::Ephemeral,
+    };
+    ephemeral.fill_hash();
+
+    Ok(Licenses { blocks: vec![server, ephemeral] })
+}
+
+/// Configures the default setup chain, including logging and the handshake.
+pub fn default_setup(data: &Rc<RefCell<ServerData>>, log: bool,
+    options: ServerOptions) {
+    if log {
+        // Logging
+        let a = {
+            let data = data.borrow();
+            (data.logger.clone(), data.is_client)
+        };
+        Data::apply_udp_packet_stream_wrapper::<
+            ::log::UdpPacketStreamLogger>(data, a.clone());
+        Data::apply_udp_packet_sink_wrapper::<
+            ::log::UdpPacketSinkLogger>(data, a.clone());
+
+        Data::apply_packet_stream_wrapper::<
+            ::log::PacketStreamLogger<SocketAddr>>(data, a.clone());
+        Data::apply_packet_sink_wrapper::<
+            ::log::PacketSinkLogger<SocketAddr>>(data, a);
+    }
+
+    DefaultPacketHandler::apply(data, options);
+}
+
+/// Accepts new connections and handles the handshake of existing connections.
+pub struct DefaultPacketHandler;
+
+impl DefaultPacketHandler {
+    pub fn apply(data: &Rc<RefCell<ServerData>>, options: ServerOptions) {
+        // New connections start with an `Init0` from an unknown address
+        let (send, recv) = mpsc::channel(20);
+        let (handle, logger) = {
+            let mut data = data.borrow_mut();
+            data.unknown_udp_packet_sink = Some(send);
+            (data.handle.clone(), data.logger.clone())
+        };
+        let weak_data = Rc::downgrade(data);
+        let logger2 = logger.clone();
+        handle.spawn(recv.for_each(move |(addr, udp_packet)| {
+            let data = if let Some(data) = weak_data.upgrade() {
+                data
+            } else {
+                return Box::new(future::ok(())) as BoxFuture<(), ()>;
+            };
+            match Self::handle_init0(&data, addr, udp_packet) {
+                Ok(fut) => {
+                    let logger = logger2.clone();
+                    Box::new(fut.map_err(move |error|
+                        error!(logger, "Cannot send packet"; "error" => ?error)))
+                }
+                Err(error) => {
+                    warn!(logger2, "Unknown packet dropped";
+                        "addr" => %addr, "error" => ?error);
+                    Box::new(future::ok(()))
+                }
+            }
+        }).map_err(move |()| error!(logger, "Receiving unknown packets failed")));
+
+        let stream = data.borrow_mut().packet_stream.take().unwrap();
+        let stream = Self::new_stream(Rc::downgrade(data), stream, options);
+        data.borrow_mut().packet_stream = Some(stream);
+    }
+
+    /// Create a new connection for an `Init0` packet.
+    ///
+    /// Returns a future which sends the `Init1` answer.
+    fn handle_init0(data: &Rc<RefCell<ServerData>>, addr: SocketAddr,
+        udp_packet: UdpPacket) -> Result<BoxFuture<(), Error>> {
+        let (header, p_data) = ::utils::parse_packet(udp_packet.0, false)?;
+        let random0 = match packets::Data::read(&header,
+            &mut Cursor::new(p_data.as_slice()))? {
+            packets::Data::C2SInit(C2SInit::Init0 { random0, .. }) => random0,
+            _ => return Err(format_err!("Expected an Init0 packet").into()),
+        };
+
+        let mut random1 = [0; 16];
+        rand::thread_rng().fill_bytes(&mut random1);
+        let mut random0_r = random0;
+        random0_r.reverse();
+
+        Data::add_connection(data, Data::create_connection(data, addr));
+        data.borrow_mut().connection_manager.get_mut_data(addr)
+            .ok_or_else(|| format_err!("The new connection was removed"))?
+            .state = ClientConnectionState::Init1 { random1 };
+
+        let packet = Packet::new(create_init_header(), packets::Data::S2CInit(
+            S2CInit::Init1 { random1, random0_r }));
+        Ok(Box::new(Data::get_packets(Rc::downgrade(data))
+            .send((addr, packet)).map(|_| ())))
+    }
+
+    fn new_stream<
+        InnerStream: Stream<Item = (SocketAddr, Packet), Error = Error> + 'static,
+    >(data: Weak<RefCell<ServerData>>, inner_stream: InnerStream,
+        options: ServerOptions)
+        -> Box<Stream<Item = (SocketAddr, Packet), Error = Error>> {
+        Box::new(inner_stream.and_then(move |(key, packet)|
+            -> BoxFuture<_, _> {
+            let data = if let Some(data) = data.upgrade() {
+                data
+            } else {
+                return Box::new(future::ok(None));
+            };
+            // true, if the packet should be passed to the application.
+            let mut pass_packet = false;
+            let answer = {
+                let data = &mut *data.borrow_mut();
+                let con = if let Some(con) = data.connection_manager
+                    .get_connection(key) {
+                    con
+                } else {
+                    return Box::new(future::ok(None));
+                };
+                let mut con = con.borrow_mut();
+                let logger = con.logger.clone();
+                let state = if let Some(state) = data.connection_manager
+                    .get_mut_data(key) {
+                    state
+                } else {
+                    return Box::new(future::ok(None));
+                };
+                match Self::handle_packet(state, &packet, &mut pass_packet,
+                    &data.private_key, &mut con, &options, &logger) {
+                    Ok(res) => res,
+                    Err(error) => {
+                        error!(logger, "Error when handling packet";
+                            "error" => ?error);
+                        None
+                    }
+                }
+            };
+
+            let res_fut: BoxFuture<(), _> = if let Some(p) = answer {
+                Box::new(Data::get_packets(Rc::downgrade(&data))
+                    .send((key, p)).map(|_| ()))
+            } else {
+                Box::new(future::ok(()))
+            };
+            Box::new(res_fut.map(move |_| if pass_packet {
+                Some((key, packet))
+            } else {
+                None
+            }))
+        })
+        .filter_map(|p| p))
+    }
+
+    /// Handle a packet of an existing connection.
+    ///
+    /// Returns the packet which should be sent as an answer.
+    fn handle_packet(state: &mut ClientConnectionData, packet: &Packet,
+        pass_packet: &mut bool, private_key: &EccKeyPrivP256,
+        con: &mut ServerConnection, options: &ServerOptions, logger: &Logger)
+        -> Result<Option<Packet>> {
+        let (new_state, res) = match state.state {
+            ClientConnectionState::Uninitialized =>
+                return Err(format_err!("ClientConnectionState is uninitialized").into()),
+            ClientConnectionState::Init1 { ref random1 } => {
+                // Handle an Init2
+                if let Packet { data: packets::Data::C2SInit(
+                    C2SInit::Init2 { random1: ref random1_r, .. }), .. } = *packet {
+                    if random1_r != random1 {
+                        return Err(format_err!("Init: Got wrong data in the Init2 packet").into());
+                    }
+
+                    // Create the RSA puzzle: y = x ^ (2 ^ level) % n
+                    let mut rng = rand::thread_rng();
+                    let mut n = [0; 64];
+                    rng.fill_bytes(&mut n);
+                    // Use the full length and an odd modulus
+                    n[0] |= 0x80;
+                    n[63] |= 1;
+                    let mut x = [0; 64];
+                    rng.fill_bytes(&mut x);
+                    let mut random2 = [0; 100];
+                    rng.fill_bytes(&mut random2);
+
+                    let level = options.puzzle_level;
+                    let ni = algs::array_to_biguint(&n);
+                    let xi = algs::array_to_biguint(&x) % &ni;
+                    let mut e = BigUint::one();
+                    e <<= level as usize;
+                    let y = algs::biguint_to_array(&xi.modpow(&e, &ni));
+                    let x = algs::biguint_to_array(&xi);
+
+                    let data = S2CInit::Init3 { x, n, level, random2 };
+                    (Some(ClientConnectionState::Init3 {
+                        x, n, level, random2, y,
+                    }), Some(Packet::new(create_init_header(),
+                        packets::Data::S2CInit(data))))
+                } else {
+                    (None, None)
+                }
+            }
+            ClientConnectionState::Init3 { ref x, ref n, level, ref random2,
+                ref y } => {
+                // Handle an Init4
+                if let Packet { data: packets::Data::C2SInit(C2SInit::Init4 {
+                    x: ref x_r, n: ref n_r, level: level_r,
+                    random2: ref random2_r, y: ref y_r, ref command, .. }), .. }
+                    = *packet {
+                    if x_r[..] != x[..] || n_r[..] != n[..] || level_r != level
+                        || random2_r[..] != random2[..] {
+                        return Err(format_err!("Init: Got wrong data in the Init4 packet").into());
+                    }
+                    if y_r[..] != y[..] {
+                        return Err(format_err!("Init: Wrong solution of the RSA puzzle").into());
+                    }
+
+                    let cmds = command.get_commands();
+                    let cmd = cmds.first().ok_or_else(||
+                        format_err!("Got an empty command"))?;
+                    if cmd.command != "clientinitiv" || !cmd.has_arg("alpha")
+                        || !cmd.has_arg("omega") || !cmd.has_arg("ot")
+                        || cmd.args["ot"] != "1" {
+                        return Err(format_err!("clientinitiv command has wrong arguments").into());
+                    }
+
+                    let alpha_vec = base64::decode(cmd.args["alpha"])?;
+                    if alpha_vec.len() != 10 {
+                        return Err(format_err!("Incorrect alpha length").into());
+                    }
+                    let mut alpha = [0; 10];
+                    alpha.copy_from_slice(&alpha_vec);
+                    let client_key = EccKeyPubP256::from_ts(cmd.args["omega"])?;
+
+                    let mut beta = [0; 54];
+                    rand::thread_rng().fill_bytes(&mut beta);
+
+                    let licenses = create_license_chain()?;
+                    let ek = licenses.derive_private_key(0,
+                        options.license_root.clone())?;
+                    let mut l = Vec::new();
+                    licenses.write(&mut l)?;
+                    // Signature of l
+                    let proof = private_key.clone().sign(&l)?;
+
+                    let mut command = Command::new("initivexpand2");
+                    command.push("l", base64::encode(&l));
+                    command.push("beta", base64::encode(&beta[..]));
+                    command.push("omega", private_key.to_pub().to_ts()?);
+                    command.push("ot", "1");
+                    command.push("proof", base64::encode(&proof));
+                    command.push("tvd", "");
+                    command.push("time", Utc::now().timestamp().to_string());
+
+                    (Some(ClientConnectionState::InitIvExpand {
+                        alpha, beta, client_key, ek,
+                    }), Some(Packet::new(Header::new(PacketType::Command),
+                        packets::Data::Command(command))))
+                } else {
+                    (None, None)
+                }
+            }
+            ClientConnectionState::InitIvExpand { ref alpha, ref beta,
+                ref client_key, ref ek } => {
+                // Handle a clientek
+                if let Packet { data: packets::Data::Command(ref command), .. }
+                    = *packet {
+                    let cmds = command.get_commands();
+                    let cmd = cmds.first().ok_or_else(||
+                        format_err!("Got an empty command"))?;
+                    if cmd.command != "clientek" || !cmd.has_arg("ek")
+                        || !cmd.has_arg("proof") {
+                        return Err(format_err!("clientek command has wrong arguments").into());
+                    }
+
+                    let client_ek = EccKeyPubEd25519::from_base64(
+                        cmd.args["ek"])?;
+                    let proof = base64::decode(cmd.args["proof"])?;
+                    // Proof: ECDSA signature of ek || beta
+                    let mut all = Vec::with_capacity(32 + 54);
+                    all.extend_from_slice(&(client_ek.0).0);
+                    all.extend_from_slice(beta);
+                    client_key.clone().verify(&all, &proof)?;
+
+                    let client_ek = client_ek.0.decompress().ok_or_else(||
+                        format_err!("Cannot uncompress public key"))?;
+                    let (iv, mac) = algs::compute_iv_mac31(alpha, beta, ek,
+                        &client_ek)?;
+                    let mut params = ConnectedParams::new(client_key.clone(),
+                        SharedIv::Protocol31(iv), mac);
+                    // We already sent a command packet (initivexpand2).
+                    params.outgoing_p_ids[PacketType::Command.to_usize().unwrap()]
+                        .1 = 1;
+                    // We received two command packets (clientinitiv and
+                    // clientek).
+                    params.incoming_p_ids[PacketType::Command.to_usize().unwrap()]
+                        .1 = 2;
+                    // We received and sent an ack.
+                    params.incoming_p_ids[PacketType::Ack.to_usize().unwrap()]
+                        .1 = 1;
+                    params.outgoing_p_ids[PacketType::Ack.to_usize().unwrap()]
+                        .1 = 1;
+                    con.params = Some(params);
+
+                    // clientek is the ack for initivexpand2
+                    con.resender.ack_packet(PacketType::Command, 0);
+                    con.resender.handle_event(ResenderEvent::Connected);
+                    info!(logger, "Client finished the handshake");
+                    (Some(ClientConnectionState::Connected), None)
+                } else {
+                    (None, None)
+                }
+            }
+            ClientConnectionState::Connected => {
+                *pass_packet = true;
+                (None, None)
+            }
+        };
+
+        if let Some(s) = new_state {
+            state.state = s;
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Sink;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils;
+    use transport;
+
+    #[test]
+    fn license_chain_keys_match() {
+        let root = EccKeyPrivEd25519::create().unwrap();
+        let licenses = create_license_chain().unwrap();
+        let private = licenses.derive_private_key(0, root.clone()).unwrap();
+
+        // Serialize the licenses, so the client side only sees public keys
+        let mut l = Vec::new();
+        licenses.write(&mut l).unwrap();
+        let parsed = Licenses::parse(&l).unwrap();
+        let root_pub = root.to_pub().0.decompress().unwrap();
+        let public = parsed.derive_public_key_with_root(root_pub).unwrap();
+
+        assert_eq!((private.to_pub().0).0, public.compress().0);
+    }
+
+    #[test]
+    fn client_connects() {
+        let mut core = Core::new().unwrap();
+        let (client, server, root) = test_utils::create_pair(&core.handle(),
+            |p| vec![p]);
+        test_utils::run_server(&server, |_, _| {});
+        test_utils::connect(&mut core, &client, &root);
+
+        let mut server = server.borrow_mut();
+        let con_data = server.connection_manager
+            .get_mut_data(test_utils::client_addr()).unwrap();
+        if let ClientConnectionState::Connected = con_data.state {
+        } else {
+            panic!("The server did not finish the handshake");
+        }
+    }
+
+    #[test]
+    fn invalid_init_packet_dropped() {
+        let mut core = Core::new().unwrap();
+        let (client, server) = transport::pair(test_utils::client_addr(),
+            test_utils::server_addr());
+        let (server, _) = test_utils::create_server(&core.handle(), server);
+        test_utils::run_server(&server, |_, _| {});
+
+        // A packet from an unknown address, which is not an Init0
+        let client = client.send((test_utils::server_addr(),
+            UdpPacket(vec![0; 20])));
+        let _client = test_utils::run(&mut core, client);
+        test_utils::wait(&mut core, 50);
+
+        assert!(server.borrow().connection_manager
+            .get_connection(test_utils::client_addr()).is_none());
+    }
+}
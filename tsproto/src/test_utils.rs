@@ -0,0 +1,209 @@
+//! Helpers to connect a client and a server in tests, without using the
+//! network.
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration as StdDuration;
+
+use chrono::Duration;
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::Either;
+use slog;
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use {packets, BoxFuture, Error};
+use client::{self, ClientData};
+use commands::Command;
+use connectionmanager::SocketConnectionManager;
+use crypto::{EccKeyPrivEd25519, EccKeyPrivP256};
+use handler_data::Data;
+use packets::*;
+use resend::ResendConfig;
+use server::{self, ServerData, ServerOptions};
+use transport::{self, MemoryTransport};
+
+pub fn client_addr() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+pub fn server_addr() -> SocketAddr {
+    "127.0.0.1:9987".parse().unwrap()
+}
+
+pub fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, o!())
+}
+
+/// Resend lost packets quickly, so tests with packet loss finish fast.
+pub fn resend_config() -> ResendConfig {
+    ResendConfig {
+        connecting_interval: Duration::milliseconds(50),
+        srtt: Duration::milliseconds(50),
+        .. ResendConfig::default()
+    }
+}
+
+/// Run a future on the core and fail if it does not finish within 10 seconds.
+pub fn run<F: Future<Error = Error>>(core: &mut Core, f: F) -> F::Item {
+    let timeout = Timeout::new(StdDuration::from_secs(10), &core.handle())
+        .unwrap();
+    match core.run(f.select2(timeout)) {
+        Ok(Either::A((res, _))) => res,
+        Ok(Either::B(_)) => panic!("Timed out"),
+        Err(Either::A((error, _))) => panic!("Failed: {:?}", error),
+        Err(Either::B((error, _))) => panic!("Timer failed: {:?}", error),
+    }
+}
+
+/// Run the core for the given time.
+pub fn wait(core: &mut Core, millis: u64) {
+    let timeout = Timeout::new(StdDuration::from_millis(millis),
+        &core.handle()).unwrap();
+    core.run(timeout).unwrap();
+}
+
+/// Run the core until `f` returns `true` and fail after 10 seconds.
+pub fn run_until<F: FnMut() -> bool>(core: &mut Core, mut f: F) {
+    for _ in 0..1000 {
+        if f() {
+            return;
+        }
+        wait(core, 10);
+    }
+    panic!("Timed out");
+}
+
+/// The header of a udp packet which was sent by a client.
+pub fn client_header(packet: &UdpPacket) -> Header {
+    Header::read(&true, &mut Cursor::new(packet.0.as_slice())).unwrap()
+}
+
+/// Create a client which sends its udp packets through `filter`.
+///
+/// `filter` gets every udp packet of the client and returns the packets which
+/// are actually sent, so tests can drop or reorder packets.
+pub fn create_client<F>(handle: &Handle, transport: MemoryTransport,
+    mut filter: F) -> Rc<RefCell<ClientData>>
+    where F: FnMut((SocketAddr, UdpPacket)) -> Vec<(SocketAddr, UdpPacket)>
+        + 'static {
+    let local_addr = transport.local_addr();
+    let (sink, stream) = transport.split();
+    let sink = sink.with_flat_map(move |p| stream::iter_ok(filter(p)));
+    let data = ClientData::with_transport(local_addr,
+        EccKeyPrivP256::create().unwrap(), handle.clone(), true,
+        SocketConnectionManager::with_resender_config(resend_config()),
+        logger(), stream, sink);
+    {
+        let data2 = data.clone();
+        let mut data = data.borrow_mut();
+        data.connection_manager.set_data_ref(Rc::downgrade(&data2));
+    }
+    client::default_setup(&data, false);
+    data
+}
+
+/// Create a server with a cheap RSA puzzle.
+///
+/// Returns the server and the root key of its licenses.
+pub fn create_server(handle: &Handle, transport: MemoryTransport)
+    -> (Rc<RefCell<ServerData>>, EccKeyPrivEd25519) {
+    let local_addr = transport.local_addr();
+    let (sink, stream) = transport.split();
+    let data = ServerData::with_transport(local_addr,
+        EccKeyPrivP256::create().unwrap(), handle.clone(), false,
+        SocketConnectionManager::new(), logger(), stream, sink);
+    {
+        let data2 = data.clone();
+        let mut data = data.borrow_mut();
+        data.connection_manager.set_data_ref(Rc::downgrade(&data2));
+    }
+    let root = EccKeyPrivEd25519::create().unwrap();
+    server::default_setup(&data, false,
+        ServerOptions::new(root.clone()).puzzle_level(100));
+    (data, root)
+}
+
+/// Create a client and a server, which are connected by an in-memory
+/// transport.
+///
+/// The udp packets of the client go through `filter`, see
+/// [`create_client`](fn.create_client.html).
+pub fn create_pair<F>(handle: &Handle, filter: F)
+    -> (Rc<RefCell<ClientData>>, Rc<RefCell<ServerData>>, EccKeyPrivEd25519)
+    where F: FnMut((SocketAddr, UdpPacket)) -> Vec<(SocketAddr, UdpPacket)>
+        + 'static {
+    let (client_transport, server_transport) =
+        transport::pair(client_addr(), server_addr());
+    let client = create_client(handle, client_transport, filter);
+    let (server, root) = create_server(handle, server_transport);
+    (client, server, root)
+}
+
+/// Handle the packets of the server.
+///
+/// A `clientinit` is answered with an `initserver`, all other packets are
+/// given to `f`.
+pub fn run_server<F>(data: &Rc<RefCell<ServerData>>, mut f: F)
+    where F: FnMut(SocketAddr, Packet) + 'static {
+    let handle = data.borrow().handle.clone();
+    let weak_data = Rc::downgrade(data);
+    let stream = Data::get_packets(weak_data.clone());
+    handle.spawn(stream.for_each(move |(addr, packet)| -> BoxFuture<(), Error> {
+        let is_clientinit =
+            if let packets::Data::Command(ref cmd) = packet.data {
+                cmd.command == "clientinit"
+            } else {
+                false
+            };
+        if is_clientinit {
+            let mut command = Command::new("initserver");
+            command.push("aclid", "2");
+            let answer = Packet::new(Header::new(PacketType::Command),
+                packets::Data::Command(command));
+            Box::new(Data::get_packets(weak_data.clone())
+                .send((addr, answer)).map(|_| ()))
+        } else {
+            f(addr, packet);
+            Box::new(future::ok(()))
+        }
+    }).map_err(|error| panic!("Server failed: {:?}", error)));
+}
+
+/// Connect the client to the server and wait until the client received the
+/// `initserver`.
+pub fn connect(core: &mut Core, client: &Rc<RefCell<ClientData>>,
+    root: &EccKeyPrivEd25519) {
+    // Receive packets, so acks are sent
+    let stream = Data::get_packets(Rc::downgrade(client));
+    core.handle().spawn(stream.for_each(|_| Ok(()))
+        .map_err(|error| panic!("Client failed: {:?}", error)));
+
+    let root = root.to_pub().0.decompress().unwrap();
+    let client2 = client.clone();
+    let fut = client::connect_with_license_root(client, server_addr(), root)
+        .and_then(move |()| {
+            let mut command = Command::new("clientinit");
+            command.push("client_nickname", "tsproto");
+            let packet = Packet::new(Header::new(PacketType::Command),
+                packets::Data::Command(command));
+            Data::get_packets(Rc::downgrade(&client2))
+                .send((server_addr(), packet))
+                .and_then(move |_|
+                    client::wait_until_connected(&client2, server_addr()))
+        });
+    run(core, fut);
+}
+
+/// Send commands from the client to the server.
+///
+/// All commands are put into the sink at once, so they are sent directly
+/// after each other.
+pub fn send_commands(core: &mut Core, client: &Rc<RefCell<ClientData>>,
+    names: &[&str]) {
+    let packets = names.iter().map(|name| (server_addr(), Packet::new(
+        Header::new(PacketType::Command),
+        packets::Data::Command(Command::new(*name))))).collect::<Vec<_>>();
+    run(core, Data::get_packets(Rc::downgrade(client))
+        .send_all(stream::iter_ok(packets)));
+}
@@ -0,0 +1,208 @@
+//! An in-memory replacement for a udp socket.
+//!
+//! Two connected transports can be created with [`pair`] and given to
+//! [`Data::with_transport`], so a client and a server can talk to each other
+//! without touching the network.
+//!
+//! [`pair`]: fn.pair.html
+//! [`Data::with_transport`]: ../handler_data/struct.Data.html#method.with_transport
+use std::net::SocketAddr;
+
+use futures::{self, AsyncSink, Sink, StartSend, Stream};
+use futures::unsync::mpsc;
+
+use Error;
+use packets::UdpPacket;
+
+/// One end of an in-memory transport.
+///
+/// Packets which are put into the sink arrive at the other end, if they are
+/// addressed to it. Like with udp, packets for other addresses and packets to
+/// a closed transport are dropped.
+pub struct MemoryTransport {
+    /// The address of this end.
+    local_addr: SocketAddr,
+    /// The address of the other end.
+    peer_addr: SocketAddr,
+    sender: mpsc::UnboundedSender<(SocketAddr, UdpPacket)>,
+    receiver: mpsc::UnboundedReceiver<(SocketAddr, UdpPacket)>,
+}
+
+/// Create two transports which are connected to each other.
+///
+/// The first transport has the address `addr_a`, the second one `addr_b`.
+pub fn pair(addr_a: SocketAddr, addr_b: SocketAddr)
+    -> (MemoryTransport, MemoryTransport) {
+    let (send_a, recv_a) = mpsc::unbounded();
+    let (send_b, recv_b) = mpsc::unbounded();
+    (
+        MemoryTransport {
+            local_addr: addr_a,
+            peer_addr: addr_b,
+            sender: send_b,
+            receiver: recv_a,
+        },
+        MemoryTransport {
+            local_addr: addr_b,
+            peer_addr: addr_a,
+            sender: send_a,
+            receiver: recv_b,
+        },
+    )
+}
+
+impl MemoryTransport {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl Stream for MemoryTransport {
+    type Item = (SocketAddr, UdpPacket);
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+        // The receiver never returns an error
+        Ok(self.receiver.poll().unwrap())
+    }
+}
+
+impl Sink for MemoryTransport {
+    type SinkItem = (SocketAddr, UdpPacket);
+    type SinkError = Error;
+
+    fn start_send(&mut self, (addr, packet): Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+        if addr == self.peer_addr {
+            // Ignore the error if the other end was dropped
+            let _ = self.sender.unbounded_send((self.local_addr, packet));
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
+        Ok(futures::Async::Ready(()))
+    }
+
+    fn close(&mut self) -> futures::Poll<(), Self::SinkError> {
+        Ok(futures::Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use futures::Future;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use packets::{Data, PacketType};
+    use test_utils;
+
+    /// Connect a client and a server and record the commands which arrive at
+    /// the server.
+    ///
+    /// The udp packets of the client go through `filter`.
+    fn connect<F>(core: &mut Core, filter: F)
+        -> (Rc<RefCell<::client::ClientData>>, Rc<RefCell<Vec<String>>>)
+        where F: FnMut((SocketAddr, UdpPacket)) -> Vec<(SocketAddr, UdpPacket)>
+            + 'static {
+        let (client, server, root) = test_utils::create_pair(&core.handle(),
+            filter);
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        let commands2 = commands.clone();
+        test_utils::run_server(&server, move |_, packet| {
+            if let Data::Command(cmd) = packet.data {
+                commands2.borrow_mut().push(cmd.command);
+            }
+        });
+        test_utils::connect(core, &client, &root);
+        (client, commands)
+    }
+
+    #[test]
+    fn send_between_pair() {
+        let addr_a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let (a, b) = pair(addr_a, addr_b);
+
+        let a = a.send((other, UdpPacket(vec![0]))).wait().unwrap();
+        let a = a.send((addr_b, UdpPacket(vec![1, 2]))).wait().unwrap();
+        drop(a);
+
+        let received = b.collect().wait().unwrap();
+        assert_eq!(received, vec![(addr_a, UdpPacket(vec![1, 2]))]);
+    }
+
+    #[test]
+    fn connect_over_pair() {
+        let mut core = Core::new().unwrap();
+        let (client, commands) = connect(&mut core, |p| vec![p]);
+        test_utils::send_commands(&mut core, &client, &["clientupdate"]);
+        test_utils::run_until(&mut core, || !commands.borrow().is_empty());
+        assert_eq!(*commands.borrow(), vec![String::from("clientupdate")]);
+    }
+
+    #[test]
+    fn resend_lost_packet() {
+        let mut core = Core::new().unwrap();
+        // Drop the first command packet after the handshake
+        let drop_next = Rc::new(Cell::new(false));
+        let dropped = Rc::new(Cell::new(0));
+        let drop_next2 = drop_next.clone();
+        let dropped2 = dropped.clone();
+        let (client, commands) = connect(&mut core, move |p| {
+            if drop_next2.get() && test_utils::client_header(&p.1).get_type()
+                == PacketType::Command {
+                drop_next2.set(false);
+                dropped2.set(dropped2.get() + 1);
+                vec![]
+            } else {
+                vec![p]
+            }
+        });
+
+        drop_next.set(true);
+        test_utils::send_commands(&mut core, &client, &["clientupdate"]);
+        test_utils::run_until(&mut core, || !commands.borrow().is_empty());
+        assert_eq!(dropped.get(), 1);
+        assert_eq!(*commands.borrow(), vec![String::from("clientupdate")]);
+    }
+
+    #[test]
+    fn reordered_packets() {
+        let mut core = Core::new().unwrap();
+        // Hold back the first command packet after the handshake and send it
+        // after the next one.
+        let reorder = Rc::new(Cell::new(false));
+        let reorder2 = reorder.clone();
+        let mut held = None;
+        let (client, commands) = connect(&mut core, move |p| {
+            if !reorder2.get() || test_utils::client_header(&p.1).get_type()
+                != PacketType::Command {
+                vec![p]
+            } else if let Some(first) = held.take() {
+                reorder2.set(false);
+                vec![p, first]
+            } else {
+                held = Some(p);
+                vec![]
+            }
+        });
+
+        reorder.set(true);
+        test_utils::send_commands(&mut core, &client,
+            &["clientupdate", "clientmove"]);
+        test_utils::run_until(&mut core, || commands.borrow().len() == 2);
+        assert!(!reorder.get());
+        assert_eq!(*commands.borrow(), vec![String::from("clientupdate"),
+            String::from("clientmove")]);
+    }
+}